@@ -0,0 +1,10 @@
+-- Index of TigerBeetle transfers by account, so that statements can be queried by time range.
+CREATE TABLE IF NOT EXISTS transfers (
+    id uuid NOT NULL PRIMARY KEY,
+    debit_account_id uuid NOT NULL,
+    credit_account_id uuid NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS transfers_debit_account_id ON transfers (debit_account_id, created);
+CREATE INDEX IF NOT EXISTS transfers_credit_account_id ON transfers (credit_account_id, created);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS fills (
+    id uuid NOT NULL PRIMARY KEY,
+    asset_id INTEGER NOT NULL,
+    buy_order_id uuid NOT NULL,
+    sell_order_id uuid NOT NULL,
+    buyer_id uuid NOT NULL,
+    seller_id uuid NOT NULL,
+    price INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS fills_buyer_id ON fills (buyer_id, created);
+CREATE INDEX IF NOT EXISTS fills_seller_id ON fills (seller_id, created);
@@ -0,0 +1,5 @@
+DROP TABLE leaderboard_snapshots;
+
+DROP TABLE competition_assets;
+
+DROP TABLE competitions;
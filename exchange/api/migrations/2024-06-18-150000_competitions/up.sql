@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS competitions (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    starts TIMESTAMP WITH TIME ZONE NOT NULL,
+    ends TIMESTAMP WITH TIME ZONE NOT NULL,
+    starting_cash BIGINT NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK (starts < ends)
+);
+
+-- Assets whose positions count towards a participant's portfolio value.
+-- Competitions without any count every equity.
+CREATE TABLE IF NOT EXISTS competition_assets (
+    competition_id INTEGER NOT NULL,
+    asset_id INTEGER NOT NULL,
+    PRIMARY KEY (competition_id, asset_id),
+    FOREIGN KEY(competition_id) REFERENCES competitions(id)
+);
+
+CREATE TABLE IF NOT EXISTS leaderboard_snapshots (
+    competition_id INTEGER NOT NULL,
+    account_id uuid NOT NULL,
+    taken TIMESTAMP WITH TIME ZONE NOT NULL,
+    value BIGINT NOT NULL,
+    PRIMARY KEY (competition_id, account_id, taken),
+    FOREIGN KEY(competition_id) REFERENCES competitions(id),
+    FOREIGN KEY(account_id) REFERENCES users(id)
+);
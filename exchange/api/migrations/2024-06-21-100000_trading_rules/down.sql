@@ -0,0 +1,2 @@
+ALTER TABLE equity_options DROP COLUMN IF EXISTS lot_size, DROP COLUMN IF EXISTS tick_size;
+ALTER TABLE equities DROP COLUMN IF EXISTS lot_size, DROP COLUMN IF EXISTS tick_size;
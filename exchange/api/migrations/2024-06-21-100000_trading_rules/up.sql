@@ -0,0 +1,8 @@
+-- Prices must be a multiple of the tick size and order sizes a multiple of the lot size.
+ALTER TABLE equities
+    ADD COLUMN IF NOT EXISTS tick_size INTEGER NOT NULL DEFAULT 1 CHECK (tick_size > 0),
+    ADD COLUMN IF NOT EXISTS lot_size INTEGER NOT NULL DEFAULT 1 CHECK (lot_size > 0);
+
+ALTER TABLE equity_options
+    ADD COLUMN IF NOT EXISTS tick_size INTEGER NOT NULL DEFAULT 1 CHECK (tick_size > 0),
+    ADD COLUMN IF NOT EXISTS lot_size INTEGER NOT NULL DEFAULT 1 CHECK (lot_size > 0);
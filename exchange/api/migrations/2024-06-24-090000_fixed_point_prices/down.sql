@@ -0,0 +1,14 @@
+UPDATE leaderboard_snapshots SET value = value / 1000;
+
+UPDATE competitions SET starting_cash = starting_cash / 1000;
+
+ALTER TABLE fills ALTER COLUMN price TYPE INTEGER;
+
+ALTER TABLE equity_options
+    ALTER COLUMN tick_size SET DEFAULT 1,
+    ALTER COLUMN tick_size TYPE INTEGER USING tick_size / 1000,
+    ALTER COLUMN strike_price TYPE INTEGER USING strike_price / 1000;
+
+ALTER TABLE equities
+    ALTER COLUMN tick_size SET DEFAULT 1,
+    ALTER COLUMN tick_size TYPE INTEGER USING tick_size / 1000;
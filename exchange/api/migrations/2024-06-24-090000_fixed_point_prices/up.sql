@@ -0,0 +1,28 @@
+-- Prices and amounts of money are stored as a BIGINT count of mills, matching
+-- protocol::price::Price and Notional, so everything stored in whole units is rescaled.
+--
+-- Redis books and TigerBeetle balances can't be rescaled from here, so they have to be wiped
+-- (along with the fills and transfers recorded from them) before migrating. The migration
+-- refuses to run while any are left.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM transfers) OR EXISTS (SELECT 1 FROM fills) THEN
+        RAISE EXCEPTION 'prices are now counted in mills: wipe Redis, TigerBeetle, fills and '
+            'transfers before migrating';
+    END IF;
+END $$;
+
+ALTER TABLE equities
+    ALTER COLUMN tick_size TYPE BIGINT USING tick_size::BIGINT * 1000,
+    ALTER COLUMN tick_size SET DEFAULT 1000;
+
+ALTER TABLE equity_options
+    ALTER COLUMN strike_price TYPE BIGINT USING strike_price::BIGINT * 1000,
+    ALTER COLUMN tick_size TYPE BIGINT USING tick_size::BIGINT * 1000,
+    ALTER COLUMN tick_size SET DEFAULT 1000;
+
+ALTER TABLE fills ALTER COLUMN price TYPE BIGINT;
+
+UPDATE competitions SET starting_cash = starting_cash * 1000;
+
+UPDATE leaderboard_snapshots SET value = value * 1000;
@@ -0,0 +1,4 @@
+-- What happens when an account's orders would trade with each other.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS self_trade_prevention TEXT NOT NULL DEFAULT 'cancel_newest'
+        CHECK (self_trade_prevention IN ('cancel_newest', 'cancel_oldest', 'cancel_both', 'decrement'));
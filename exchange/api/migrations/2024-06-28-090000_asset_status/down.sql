@@ -0,0 +1,2 @@
+ALTER TABLE equity_options DROP COLUMN IF EXISTS status;
+ALTER TABLE equities DROP COLUMN IF EXISTS status;
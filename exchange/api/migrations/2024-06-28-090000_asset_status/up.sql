@@ -0,0 +1,8 @@
+-- Halted and delisted assets don't accept orders.
+ALTER TABLE equities
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'halted', 'delisted'));
+
+ALTER TABLE equity_options
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'halted', 'delisted'));
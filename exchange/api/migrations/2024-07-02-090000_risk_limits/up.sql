@@ -0,0 +1,10 @@
+-- Pre-trade limits on an account's orders. Limits left NULL aren't checked.
+CREATE TABLE IF NOT EXISTS risk_limits (
+    account_id uuid NOT NULL PRIMARY KEY,
+    max_order_size INTEGER CHECK (max_order_size > 0),
+    max_notional BIGINT CHECK (max_notional > 0),
+    max_open_orders INTEGER CHECK (max_open_orders > 0),
+    max_position BIGINT CHECK (max_position > 0),
+    max_price_deviation INTEGER CHECK (max_price_deviation > 0),
+    FOREIGN KEY(account_id) REFERENCES users(id)
+);
@@ -0,0 +1,26 @@
+-- Every order accepted for matching, kept up to date from the order event stream so that the
+-- books can be rebuilt if Redis loses them.
+CREATE TABLE IF NOT EXISTS orders (
+    id uuid NOT NULL PRIMARY KEY,
+    account_id uuid NOT NULL,
+    asset_id INTEGER NOT NULL,
+    side TEXT NOT NULL,
+    price BIGINT NOT NULL,
+    size INTEGER NOT NULL,
+    original_size INTEGER NOT NULL,
+    -- time priority on the book; NULL for stop orders waiting to trigger
+    priority BIGINT,
+    -- good 'til date expiry, in milliseconds since the epoch
+    expires BIGINT,
+    display_size INTEGER,
+    trigger BIGINT,
+    order_type TEXT,
+    time_in_force TEXT,
+    -- whether the order is on a book or trigger book
+    open BOOLEAN NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS orders_open ON orders (asset_id) WHERE open;
+CREATE INDEX IF NOT EXISTS orders_account_id ON orders (account_id, created);
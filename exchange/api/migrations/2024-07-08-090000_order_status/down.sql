@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS fills_sell_order_id;
+DROP INDEX IF EXISTS fills_buy_order_id;
+ALTER TABLE orders DROP COLUMN IF EXISTS status;
@@ -0,0 +1,17 @@
+-- What became of each order. Orders closed before this was recorded are taken to have been
+-- cancelled unless their fills add up to their size.
+ALTER TABLE orders
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'open'
+        CHECK (status IN ('open', 'filled', 'cancelled', 'expired'));
+
+UPDATE orders SET status = CASE
+    WHEN open THEN 'open'
+    WHEN original_size = (
+        SELECT COALESCE(SUM(size), 0) FROM fills
+        WHERE fills.buy_order_id = orders.id OR fills.sell_order_id = orders.id
+    ) THEN 'filled'
+    ELSE 'cancelled'
+END;
+
+CREATE INDEX IF NOT EXISTS fills_buy_order_id ON fills (buy_order_id);
+CREATE INDEX IF NOT EXISTS fills_sell_order_id ON fills (sell_order_id);
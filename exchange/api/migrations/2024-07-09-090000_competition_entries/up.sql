@@ -0,0 +1,9 @@
+-- Accounts taking part in a competition, each funded with its starting cash on entry.
+CREATE TABLE IF NOT EXISTS competition_entries (
+    competition_id INTEGER NOT NULL,
+    account_id uuid NOT NULL,
+    entered TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (competition_id, account_id),
+    FOREIGN KEY(competition_id) REFERENCES competitions(id),
+    FOREIGN KEY(account_id) REFERENCES users(id)
+);
@@ -18,9 +18,23 @@ use tracing::error;
 
 use crate::{Accounting, Meta, Orders};
 
-mod accounts;
+pub mod accounts;
 mod assets;
+mod auctions;
 mod auth;
+pub mod bands;
+pub mod competitions;
+pub mod events;
+pub mod expiry;
+pub mod history;
+mod idempotency;
+mod instruments;
+pub mod keys;
+pub mod ledger;
+pub mod reconciliation;
+mod rejection;
+mod risk;
+mod valuation;
 #[rustfmt::skip]
 pub mod schema;
 pub mod types;
@@ -35,17 +49,32 @@ pub fn rocket() -> Rocket<Build> {
         .attach(Accounting::init())
         .attach(Orders::init())
         .attach(AdHoc::try_on_ignite("migrate", migrate))
+        .manage(instruments::Instruments::default())
         .mount(
             "/",
             openapi_get_routes![
                 auth::login,
                 accounts::register,
                 accounts::get_account_by_id,
+                accounts::update_account_settings,
                 accounts::list_accounts,
                 accounts::get_equities_for_account,
+                accounts::get_portfolio_for_account,
                 accounts::submit_orders_for_account,
+                accounts::submit_order_batch_for_account,
+                accounts::amend_order_for_account,
+                accounts::cancel_orders_for_account,
+                accounts::get_order_for_account,
                 accounts::list_orders_for_account,
                 accounts::deposit_or_withdraw,
+                risk::get_risk_limits_for_account,
+                risk::set_risk_limits_for_account,
+                ledger::get_statement_for_account,
+                valuation::get_pnl_for_account,
+                competitions::create_competition,
+                competitions::list_competitions,
+                competitions::enter_competition,
+                competitions::get_leaderboard,
                 assets::create_equities,
                 assets::get_equity_by_id,
                 assets::get_equity_by_ticker,
@@ -54,6 +83,13 @@ pub fn rocket() -> Rocket<Build> {
                 assets::list_equity_options_by_underlying_id,
                 assets::list_equity_options_by_underlying_ticker,
                 assets::get_order_book,
+                assets::set_asset_status,
+                auctions::get_auction,
+                auctions::set_trading_phase,
+                bands::get_price_bands,
+                bands::set_price_bands,
+                reconciliation::reconcile_orders,
+                history::rebuild_order_books,
             ],
         )
         .mount(
@@ -105,6 +141,16 @@ pub struct CursorList<T> {
     pub cursor: Option<usize>,
 }
 
+impl<T> CursorList<T> {
+    /// A page of items, followed by more unless `cursor` is zero.
+    pub fn new(items: Vec<T>, cursor: usize) -> Self {
+        Self {
+            inner: List::from(items),
+            cursor: (cursor > 0).then_some(cursor),
+        }
+    }
+}
+
 impl<T: FromRedisValue> FromRedisValue for CursorList<T> {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         if !v.looks_like_cursor() {
@@ -1,12 +1,17 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    str::FromStr,
+};
 
 use bitflags::bitflags;
+use chrono::{DateTime, Utc};
 use diesel::{
     backend::Backend,
     deserialize::{self, FromSql},
     result::DatabaseErrorKind,
     serialize::{self, Output, ToSql},
-    sql_types::BigInt,
+    sql_types::{BigInt, Text},
     upsert::excluded,
     ExpressionMethods,
 };
@@ -16,12 +21,15 @@ use redis_derive::{FromRedisValue, ToRedisArgs};
 use rocket::{
     fairing, get,
     http::Status,
-    post,
+    patch, post,
     request::{FromParam, FromRequest, Outcome},
     serde::json::Json,
-    Build, Request, Rocket,
+    Build, FromFormField, Request, Rocket, State,
+};
+use rocket_db_pools::diesel::{
+    prelude::{QueryDsl, RunQueryDsl},
+    AsyncPgConnection,
 };
-use rocket_db_pools::diesel::prelude::{QueryDsl, RunQueryDsl};
 use rocket_db_pools::{deadpool_redis::redis, Connection, Database, Pool};
 use rocket_okapi::{
     gen::OpenApiGenerator,
@@ -35,16 +43,32 @@ use schemars::{
 };
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
+use strum_macros::{EnumString, IntoStaticStr};
 use tigerbeetle_unofficial::{
     self as tb,
-    error::{CreateAccountErrorKind, CreateAccountsError},
+    error::{CreateTransferErrorKind, CreateTransfersError},
 };
 use tracing::error;
 
 use super::{
+    auctions::{self, TradingPhase},
     auth::{AdminCheck, AuthnClaim, UserCheck},
+    bands,
+    events::CancelReason,
+    expiry::{self, ExpiredOrder, TradingDay},
+    history::{self, Order, OrderFilter, OrderState},
+    idempotency::IdempotencyKey,
+    instruments::Instruments,
+    keys,
+    ledger::{
+        self, LedgerError, Reservation, TransferCode, CASH_LEDGER, LOOKUP_BATCH_SIZE,
+        MAX_EXPIRY_DAYS,
+    },
+    rejection::{OrderError, Rejection, RejectionBody},
+    risk,
     schema::users::dsl,
-    types::{Email, Password},
+    types::{Email, Notional, Password, Price},
+    valuation::{self, Fill},
     CursorList, ADMIN_ACCOUNT_ID,
 };
 
@@ -68,6 +92,58 @@ pub struct User {
     /// A string representation of the user's roles.
     #[serde(rename = "roles")]
     pub role_flags: Roles,
+    /// What happens when the user's orders would trade with each other.
+    pub self_trade_prevention: SelfTradePrevention,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Deserialize,
+    Serialize,
+    FromSqlRow,
+    AsExpression,
+    EnumString,
+    IntoStaticStr,
+    JsonSchema,
+    ToRedisArgs,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[redis(rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum SelfTradePrevention {
+    /// Cancel the rest of the incoming order.
+    #[default]
+    CancelNewest,
+    /// Cancel the resting order and keep matching the incoming one.
+    CancelOldest,
+    /// Cancel the resting order and the rest of the incoming one.
+    CancelBoth,
+    /// Reduce both orders by the smaller of their sizes without trading.
+    Decrement,
+}
+
+impl<B: Backend> FromSql<Text, B> for SelfTradePrevention
+where
+    String: FromSql<Text, B>,
+{
+    fn from_sql(bytes: <B as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        String::from_sql(bytes).and_then(|v| {
+            Self::from_str(&v).map_err(|e| format!("invalid self-trade prevention: {e}").into())
+        })
+    }
+}
+
+impl<B: Backend> ToSql<Text, B> for SelfTradePrevention
+where
+    str: ToSql<Text, B>,
+{
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, B>) -> serialize::Result {
+        str::to_sql(self.into(), out)
+    }
 }
 
 bitflags! {
@@ -156,6 +232,43 @@ pub async fn list_accounts(
         })
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct AccountSettingsForm {
+    #[serde(default)]
+    self_trade_prevention: Option<SelfTradePrevention>,
+}
+
+/// # Update account settings
+///
+/// Change how the account's orders behave, such as what happens when they would trade with each
+/// other.
+#[openapi(tag = "Accounts")]
+#[patch("/accounts/<account_id>", data = "<form>")]
+pub async fn update_account_settings(
+    _check: UserIdCheck,
+    mut conn: Connection<Meta>,
+    account_id: uuid::Uuid,
+    form: Json<AccountSettingsForm>,
+) -> Result<Json<User>, Status> {
+    let user = match form.self_trade_prevention {
+        Some(self_trade_prevention) => {
+            diesel::update(dsl::users.find(account_id))
+                .set(dsl::self_trade_prevention.eq(self_trade_prevention))
+                .get_result(&mut conn)
+                .await
+        }
+        None => dsl::users.find(account_id).first(&mut conn).await,
+    };
+
+    user.map(Json).map_err(|e| match e {
+        diesel::result::Error::NotFound => Status::NotFound,
+        e => {
+            error!("error updating account settings: {e}");
+            Status::InternalServerError
+        }
+    })
+}
+
 #[derive(Deserialize, JsonSchema)]
 pub struct NewAccountForm {
     email: Email,
@@ -189,6 +302,7 @@ pub async fn register(
             email: form.email,
             password: hash,
             role_flags: Roles::empty(),
+            self_trade_prevention: SelfTradePrevention::default(),
         })
         .get_result(&mut conn)
         .await
@@ -203,29 +317,118 @@ pub async fn register(
             }
         })?;
 
-    match accounting
-        .create_accounts(vec![tb::Account::new(account_id.as_u128(), u32::MAX, 1)
-            .with_user_data_128(account_id.as_u128())
-            .with_flags(tb::account::Flags::CREDITS_MUST_NOT_EXCEED_DEBITS)])
-        .await
-    {
-        Err(CreateAccountsError::Api(errs))
-            if matches!(errs.as_slice()[0].kind(), CreateAccountErrorKind::Exists) => {}
-        Ok(()) => {}
-        Err(e) => {
-            error!("error creating funds account: {e}");
-            return Err(Status::InternalServerError);
+    if let Err(e) = ledger::create_accounts(&accounting, vec![cash_account(account_id)]).await {
+        error!("error creating funds account: {e}");
+        // Don't leave a user behind without a funds account. If this fails too, the account
+        // reconciliation job creates the funds account later.
+        if let Err(e) = diesel::delete(dsl::users.find(account_id))
+            .execute(&mut conn)
+            .await
+        {
+            error!("error rolling back registration of account {account_id}: {e}");
         }
+        return Err(Status::InternalServerError);
     }
-    // todo: rollback if this fails
 
     Ok(account)
 }
 
+/// Create the funds accounts of users missing one in TigerBeetle, returning how many were
+/// created.
+///
+/// Registration is rolled back when the funds account can't be created, so this only catches
+/// users left behind by a failed rollback or a crash in between.
+pub async fn reconcile_accounts(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+) -> Result<usize, Status> {
+    let account_ids: Vec<uuid::Uuid> =
+        dsl::users.select(dsl::id).load(meta).await.map_err(|e| {
+            error!("error listing accounts: {e}");
+            Status::InternalServerError
+        })?;
+
+    let mut missing = Vec::new();
+    for chunk in account_ids.chunks(LOOKUP_BATCH_SIZE) {
+        let found: HashSet<u128> = accounting
+            .lookup_accounts(chunk.iter().map(uuid::Uuid::as_u128).collect_vec())
+            .await
+            .map_err(|e| {
+                error!("error fetching funds accounts from tigerbeetle: {e}");
+                Status::InternalServerError
+            })?
+            .iter()
+            .map(tb::Account::id)
+            .collect();
+        missing.extend(
+            chunk
+                .iter()
+                .filter(|account_id| !found.contains(&account_id.as_u128())),
+        );
+    }
+
+    for chunk in missing.chunks(LOOKUP_BATCH_SIZE) {
+        let accounts = chunk.iter().copied().map(cash_account).collect_vec();
+        ledger::create_accounts(accounting, accounts)
+            .await
+            .map_err(|e| {
+                error!("error creating missing funds accounts: {e}");
+                Status::InternalServerError
+            })?;
+    }
+    if !missing.is_empty() {
+        tracing::warn!("created {} missing funds accounts", missing.len());
+    }
+
+    Ok(missing.len())
+}
+
+/// The TigerBeetle account holding cash on behalf of `account_id`.
+///
+/// Only the admin account may go negative, as it is the source of all cash.
+pub fn cash_account(account_id: uuid::Uuid) -> tb::Account {
+    let account = tb::Account::new(account_id.as_u128(), CASH_LEDGER, 1)
+        .with_user_data_128(account_id.as_u128());
+
+    if account_id == ADMIN_ACCOUNT_ID {
+        account
+    } else {
+        account.with_flags(tb::account::Flags::CREDITS_MUST_NOT_EXCEED_DEBITS)
+    }
+}
+
+/// TigerBeetle ID of the account holding units of `asset_id` on behalf of `account_id`.
+pub fn asset_account_id(account_id: uuid::Uuid, asset_id: i32) -> u128 {
+    uuid::Uuid::new_v5(&account_id, &asset_id.to_be_bytes()).as_u128()
+}
+
+/// The TigerBeetle account holding units of `asset_id` on behalf of `account_id`.
+///
+/// Only the admin account may go negative, as it is the source of every unit of an asset.
+pub fn asset_account(account_id: uuid::Uuid, asset_id: i32) -> tb::Account {
+    let account = tb::Account::new(asset_account_id(account_id, asset_id), asset_id as u32, 1)
+        .with_user_data_128(account_id.as_u128())
+        .with_user_data_32(asset_id as u32);
+
+    if account_id == ADMIN_ACCOUNT_ID {
+        account
+    } else {
+        account.with_flags(tb::account::Flags::CREDITS_MUST_NOT_EXCEED_DEBITS)
+    }
+}
+
+/// Posted balance of a user-owned TigerBeetle account.
+///
+/// User accounts are flagged with `CREDITS_MUST_NOT_EXCEED_DEBITS`, so their balance is
+/// debits minus credits.
+pub fn posted_balance(account: &tb::Account) -> i128 {
+    account.debits_posted() as i128 - account.credits_posted() as i128
+}
+
 #[derive(Debug, Clone, Serialize, JsonSchema)]
 pub struct Holdings {
-    asset_id: i32,
-    amount: i128,
+    pub asset_id: i32,
+    pub amount: i128,
 }
 
 /// List equity holdings for an account.
@@ -237,9 +440,21 @@ pub async fn get_equities_for_account(
     mut meta: Connection<Meta>,
     accounting: Connection<Accounting>,
 ) -> Result<Json<List<Holdings>>, Status> {
+    equity_holdings(&mut meta, &accounting, account_id)
+        .await
+        .map(List::from)
+        .map(Json)
+}
+
+/// Look up an account's holdings of each equity.
+pub async fn equity_holdings(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    account_id: uuid::Uuid,
+) -> Result<Vec<Holdings>, Status> {
     let asset_ids: Vec<i32> = {
         use super::schema::equities::dsl::*;
-        equities.select(id).load(&mut meta)
+        equities.select(id).load(meta)
     }
     .await
     .map_err(|e| {
@@ -249,7 +464,7 @@ pub async fn get_equities_for_account(
 
     let tb_ids = asset_ids
         .into_iter()
-        .map(|asset_id| uuid::Uuid::new_v5(&account_id, &i32::to_be_bytes(asset_id)).as_u128())
+        .map(|asset_id| asset_account_id(account_id, asset_id))
         .collect_vec();
 
     let assets: Vec<tb::Account> = accounting.lookup_accounts(tb_ids).await.map_err(|e| {
@@ -257,23 +472,126 @@ pub async fn get_equities_for_account(
         Status::InternalServerError
     })?;
 
-    Ok(Json(List::from(
-        assets
-            .into_iter()
-            .map(|asset| Holdings {
-                asset_id: asset.ledger() as i32,
-                amount: asset.credits_posted() as i128 - asset.debits_posted() as i128,
-            })
-            .collect_vec(),
-    )))
+    Ok(assets
+        .into_iter()
+        .map(|asset| Holdings {
+            asset_id: asset.ledger() as i32,
+            amount: posted_balance(&asset),
+        })
+        .collect_vec())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, ToRedisArgs, FromRedisValue)]
-pub struct OrderBookEntry {
-    pub account_id: super::types::Uuid,
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CashBalance {
+    /// Cash that can be used for new orders or withdrawn.
+    pub available: Notional,
+    /// Cash reserved by resting buy orders.
+    pub reserved: Notional,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Position {
     pub asset_id: i32,
-    pub price: i32,
-    pub size: u32,
+    /// Units of the asset held by the account, including reserved units.
+    pub posted: i128,
+    /// Units of the asset reserved by resting sell orders.
+    pub pending: u128,
+}
+
+impl From<&tb::Account> for Position {
+    fn from(account: &tb::Account) -> Self {
+        Self {
+            asset_id: account.ledger() as i32,
+            posted: posted_balance(account),
+            pending: account.credits_pending(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Portfolio {
+    pub cash: CashBalance,
+    pub equities: Vec<Position>,
+    pub options: Vec<Position>,
+}
+
+/// # Get Portfolio
+///
+/// Show cash balances and asset positions for an account.
+#[openapi(tag = "Accounts")]
+#[get("/accounts/<account_id>/portfolio")]
+pub async fn get_portfolio_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+) -> Result<Json<Portfolio>, Status> {
+    let equity_ids: Vec<i32> = {
+        use super::schema::equities::dsl::*;
+        equities.select(id).load(&mut meta)
+    }
+    .await
+    .map_err(|e| {
+        error!("error fetching equities: {e}");
+        Status::InternalServerError
+    })?;
+
+    let option_ids: Vec<i32> = {
+        use super::schema::equity_options::dsl::*;
+        equity_options.select(id).load(&mut meta)
+    }
+    .await
+    .map_err(|e| {
+        error!("error fetching equity options: {e}");
+        Status::InternalServerError
+    })?;
+
+    let tb_ids = std::iter::once(account_id.as_u128())
+        .chain(
+            equity_ids
+                .iter()
+                .chain(option_ids.iter())
+                .map(|&asset_id| asset_account_id(account_id, asset_id)),
+        )
+        .collect_vec();
+
+    let mut accounts: Vec<tb::Account> = Vec::with_capacity(tb_ids.len());
+    for chunk in tb_ids.chunks(LOOKUP_BATCH_SIZE) {
+        accounts.extend(
+            accounting
+                .lookup_accounts(chunk.to_vec())
+                .await
+                .map_err(|e| {
+                    error!("error fetching portfolio from tigerbeetle: {e}");
+                    Status::InternalServerError
+                })?,
+        );
+    }
+
+    let cash = accounts
+        .iter()
+        .find(|account| account.ledger() == CASH_LEDGER)
+        .ok_or_else(|| {
+            error!("account {account_id} has no funds account");
+            Status::NotFound
+        })?;
+
+    let positions = |ids: &[i32]| {
+        accounts
+            .iter()
+            .filter(|account| ids.contains(&(account.ledger() as i32)))
+            .map(Position::from)
+            .collect_vec()
+    };
+
+    Ok(Json(Portfolio {
+        cash: CashBalance {
+            available: Notional::from_mills(posted_balance(cash) - cash.credits_pending() as i128),
+            reserved: Notional::from_mills(cash.credits_pending() as i128),
+        },
+        equities: positions(&equity_ids),
+        options: positions(&option_ids),
+    }))
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
@@ -281,17 +599,93 @@ pub struct OrderBookEntry {
 #[serde(tag = "order_type")]
 pub enum OrderType {
     Market,
-    Limit { price: i32 },
+    Limit {
+        price: Price,
+    },
+    /// Submitted as a market order once the last trade reaches the trigger price: at or above it
+    /// for buys, at or below it for sells.
+    Stop {
+        trigger: Price,
+    },
+    /// Submitted as a limit order once the last trade reaches the trigger price.
+    StopLimit {
+        trigger: Price,
+        price: Price,
+    },
+}
+
+impl OrderType {
+    /// Limit price of the order, if it has one.
+    pub fn price(self) -> Option<Price> {
+        match self {
+            Self::Market | Self::Stop { .. } => None,
+            Self::Limit { price } | Self::StopLimit { price, .. } => Some(price),
+        }
+    }
+
+    /// Price the last trade has to reach before a stop order is submitted.
+    pub fn trigger(self) -> Option<Price> {
+        match self {
+            Self::Market | Self::Limit { .. } => None,
+            Self::Stop { trigger } | Self::StopLimit { trigger, .. } => Some(trigger),
+        }
+    }
+
+    /// The order submitted to the book once a stop order is triggered.
+    pub fn triggered(self) -> Self {
+        match self {
+            Self::Stop { .. } => Self::Market,
+            Self::StopLimit { price, .. } => Self::Limit { price },
+            order_type => order_type,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, ToRedisArgs, FromRedisValue)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    FromSqlRow,
+    AsExpression,
+    EnumString,
+    IntoStaticStr,
+    JsonSchema,
+    ToRedisArgs,
+    FromRedisValue,
+    FromFormField,
+)]
 #[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 #[redis(rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
 pub enum Book {
     Bids,
     Offers,
 }
 
+impl<B: Backend> FromSql<Text, B> for Book
+where
+    String: FromSql<Text, B>,
+{
+    fn from_sql(bytes: <B as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        String::from_sql(bytes)
+            .and_then(|v| Self::from_str(&v).map_err(|e| format!("invalid book: {e}").into()))
+    }
+}
+
+impl<B: Backend> ToSql<Text, B> for Book
+where
+    str: ToSql<Text, B>,
+{
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, B>) -> serialize::Result {
+        str::to_sql(self.into(), out)
+    }
+}
+
 impl<'a> FromParam<'a> for Book {
     type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -304,6 +698,15 @@ impl<'a> FromParam<'a> for Book {
     }
 }
 
+impl Book {
+    pub fn opposite(self) -> Self {
+        match self {
+            Self::Bids => Self::Offers,
+            Self::Offers => Self::Bids,
+        }
+    }
+}
+
 impl Display for Book {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -313,11 +716,96 @@ impl Display for Book {
     }
 }
 
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EnumString,
+    JsonSchema,
+    ToRedisArgs,
+    FromRedisValue,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[redis(rename_all = "snake_case")]
+pub enum TimeInForce {
+    /// Good 'til cancelled: rest on the book until filled or cancelled.
+    #[default]
+    Gtc,
+    /// Immediate or cancel: fill as much as possible at once and cancel the rest.
+    Ioc,
+    /// Fill or kill: fill completely at once, or not at all. Killed if it would reach one of the
+    /// account's own orders, unless self-trade prevention cancels the resting order.
+    Fok,
+    /// Good 'til date: rest on the book until filled or the order expires.
+    Gtd,
+    /// Good for the day: rest on the book until filled or the trading day ends.
+    Day,
+}
+
+impl TimeInForce {
+    /// Whether limit orders with this time in force rest on the book.
+    fn rests(self) -> bool {
+        matches!(self, Self::Gtc | Self::Gtd | Self::Day)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CreateOrderForm {
     pub size: u32,
     #[serde(flatten)]
     pub order_type: OrderType,
+    /// How long the order stays on the book. Market orders never rest on the book. For stop
+    /// orders, this also bounds how long they wait to be triggered.
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    /// Date & time a good 'til date order expires, in RFC 3339 format. At most a year ahead.
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+    /// Only add liquidity: reject the order rather than match it against resting orders.
+    #[serde(default)]
+    pub post_only: bool,
+    /// Show only this many units on the book at a time. Each time the visible slice fills, it is
+    /// refreshed from the hidden rest of the order and goes behind the other orders at its price.
+    #[serde(default)]
+    pub display_size: Option<u32>,
+}
+
+impl CreateOrderForm {
+    /// Check that the time in force, expiry, post-only flag and display size make sense together.
+    fn validate(&self, now: DateTime<Utc>) -> Result<(), Rejection> {
+        match (self.time_in_force, self.expires) {
+            (TimeInForce::Gtd | TimeInForce::Day, Some(expires))
+                if expires > now && expires - now <= chrono::Duration::days(MAX_EXPIRY_DAYS) => {}
+            (TimeInForce::Gtd | TimeInForce::Day, _) | (_, Some(_)) => {
+                return Err(Rejection::InvalidExpiry)
+            }
+            _ => {}
+        }
+
+        let rests =
+            matches!(self.order_type, OrderType::Limit { .. }) && self.time_in_force.rests();
+        if self.post_only && !rests {
+            return Err(Rejection::PostOnlyNotResting);
+        }
+
+        if let Some(display_size) = self.display_size {
+            let rests = self.order_type.price().is_some() && self.time_in_force.rests();
+            if !rests || display_size == 0 || display_size > self.size {
+                return Err(Rejection::InvalidDisplaySize {
+                    display_size,
+                    size: self.size,
+                });
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl ToRedisArgs for CreateOrderForm {
@@ -327,6 +815,12 @@ impl ToRedisArgs for CreateOrderForm {
     {
         self.size.write_redis_args(out);
         self.order_type.write_redis_args(out);
+        self.time_in_force.write_redis_args(out);
+        (self.post_only as u8).write_redis_args(out);
+        self.expires
+            .map_or(0, |expires| expires.timestamp_millis())
+            .write_redis_args(out);
+        self.display_size.unwrap_or(0).write_redis_args(out);
     }
 }
 
@@ -335,170 +829,1422 @@ impl ToRedisArgs for OrderType {
     where
         W: ?Sized + redis::RedisWrite,
     {
-        match self {
-            OrderType::Market => "market".write_redis_args(out),
+        // stop orders are stored as the order they become once triggered
+        match self.triggered() {
             OrderType::Limit { price } => {
                 "limit".write_redis_args(out);
                 price.write_redis_args(out);
             }
+            _ => {
+                "market".write_redis_args(out);
+                Price::ZERO.write_redis_args(out);
+            }
         }
     }
 }
 
+/// What happened to a resting order reached while matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRedisValue)]
+#[redis(rename_all = "snake_case")]
+enum MatchOutcome {
+    Trade,
+    /// Both orders were reduced by the size without trading, to prevent a self-trade.
+    Decrement,
+    /// The resting order was cancelled to prevent a self-trade.
+    Cancel,
+}
+
+/// A resting order reached while matching: its ID, account ID, price, size matched, original
+/// size, size filled before the match, what happened to it, and its expiry in milliseconds since
+/// the epoch (0 if none).
+type MatchedOrder = (
+    super::types::Uuid,
+    super::types::Uuid,
+    Price,
+    u32,
+    u32,
+    u32,
+    MatchOutcome,
+    i64,
+);
+
+/// A stop order taken out of its trigger book: its ID, account ID, side, the type of order it
+/// becomes, limit price, size, time in force, expiry in milliseconds since the epoch (0 if
+/// none) and display size (0 if none).
+pub type TriggeredOrder = (
+    super::types::Uuid,
+    super::types::Uuid,
+    Book,
+    String,
+    Price,
+    u32,
+    TimeInForce,
+    i64,
+    u32,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// The stop order is waiting for the last trade to reach its trigger price.
+    Pending,
+    /// The rest of the order is on the book.
+    Resting,
+    Filled,
+    /// The rest of the order was dropped without resting on the book.
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct OrderReceipt {
+    pub order_id: uuid::Uuid,
+    pub status: OrderStatus,
+    /// Units filled on submission.
+    pub filled: u32,
+    /// Total value of the units filled on submission.
+    pub dollar_volume: Notional,
+    /// Orders cancelled or reduced to keep this order from trading with the account's own
+    /// orders, including this one.
+    pub self_trade_cancels: Vec<SelfTradeCancel>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct SelfTradeCancel {
+    pub order_id: uuid::Uuid,
+    /// Units of the order cancelled.
+    pub size: u32,
+}
+
+/// Routes an idempotency key is scoped to.
+const ORDERS_SCOPE: &str = "orders";
+const ORDER_BATCHES_SCOPE: &str = "order_batches";
+const CASH_SCOPE: &str = "cash";
+
 /// Submit an order for an equity asset.
+///
+/// Orders for unknown or expired assets, or that don't respect the asset's tick and lot sizes,
+/// are rejected, as are post-only orders that would trade on arrival.
+///
+/// Stop and stop-limit orders wait in the asset's trigger book until the last trade reaches
+/// their trigger price, with their funds/assets reserved as for the market or limit order they
+/// become. Market buys reserve funds at the best offer, or the last trade if there are no offers,
+/// widened by the asset's price band or 10% if it has none, and stop matching at that price. Stop
+/// buys reserve nothing until they trigger, and are then funded the same way.
+///
+/// Orders over the account's risk limits are rejected before anything is reserved for them.
+///
+/// Limit orders priced outside the asset's price band around the last trade are rejected, and
+/// market orders stop matching at its edge. Orders that would trade too far from the price at the
+/// start of the asset's volatility window interrupt continuous trading with a volatility auction
+/// instead, with any rest of the order staying on the book if it can.
+///
+/// During an auction call, orders collect on the book without matching until it is uncrossed,
+/// and only limit orders that can rest on the book and stop orders are accepted. Closed assets
+/// accept no orders.
+///
+/// Day orders expire at the end of the trading day, so they can't be given an expiry of their
+/// own.
+///
+/// Retries sent with the same `Idempotency-Key` get the receipt or rejection of the first
+/// submission instead of placing the order again. Keys whose submission failed with a server
+/// error can be reused.
 #[openapi(tag = "Accounts")]
 #[post("/accounts/<account_id>/assets/<asset_id>/<book>", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn submit_orders_for_account(
     _check: UserIdCheck,
     account_id: uuid::Uuid,
     asset_id: i32,
     book: Book,
     mut orders: Connection<Orders>,
+    mut meta: Connection<Meta>,
     accounting: Connection<Accounting>,
+    instruments: &State<Instruments>,
+    trading_day: TradingDay,
+    idempotency_key: IdempotencyKey,
     form: Json<CreateOrderForm>,
-) -> Result<String, Status> {
-    let order_id = uuid::Uuid::now_v7();
-
-    let asset_account_id = uuid::Uuid::new_v5(&account_id, &asset_id.to_be_bytes()).as_u128();
+) -> Result<Json<OrderReceipt>, OrderError> {
+    let form = form.into_inner();
+    let request = (asset_id, book, &form);
+    let replay: Option<Result<OrderReceipt, Rejection>> = idempotency_key
+        .claim(orders.as_mut(), account_id, ORDERS_SCOPE, &request)
+        .await?;
+    if let Some(response) = replay {
+        return response.map(Json).map_err(OrderError::from);
+    }
 
-    // reserve funds/assets
-    // todo: how do you handle market buy orders??
-    match book {
-        Book::Bids => {
-            // they want to buy, so reserve funds
-            if let OrderType::Limit { price } = form.order_type {
-                // TODO: we should technically handle negative prices here...
-                accounting
-                    .create_transfers(vec![tb::Transfer::new(order_id.as_u128())
-                        .with_code(1)
-                        .with_amount(form.size as u128 * price as u128)
-                        .with_ledger(u32::MAX)
-                        .with_credit_account_id(account_id.as_u128())
-                        .with_debit_account_id(ADMIN_ACCOUNT_ID.as_u128())
-                        .with_flags(tb::transfer::Flags::PENDING)])
-                    .await
-                    .map_err(|e| {
-                        error!("error reserving funds: {e:?}");
-                        Status::InternalServerError
-                    })?;
-            }
+    let order_id = idempotency_key.id(account_id, ORDERS_SCOPE);
+    let result = submit(
+        &mut meta,
+        orders.as_mut(),
+        &accounting,
+        instruments,
+        trading_day,
+        account_id,
+        asset_id,
+        book,
+        order_id,
+        form.clone(),
+    )
+    .await;
+    match &result {
+        Ok(receipt) => {
+            idempotency_key
+                .store(
+                    orders.as_mut(),
+                    account_id,
+                    ORDERS_SCOPE,
+                    &request,
+                    &Ok::<_, Rejection>(receipt),
+                )
+                .await
         }
-        Book::Offers => {
-            // they want to sell, so reserve units of the asset
-            match accounting
-                .create_accounts(vec![tb::Account::new(asset_account_id, asset_id as u32, 1)
-                    .with_user_data_128(account_id.as_u128())
-                    .with_user_data_32(asset_id as u32)
-                    .with_flags(tb::account::Flags::CREDITS_MUST_NOT_EXCEED_DEBITS)])
+        Err(OrderError::Rejected(rejection)) => {
+            idempotency_key
+                .store(
+                    orders.as_mut(),
+                    account_id,
+                    ORDERS_SCOPE,
+                    &request,
+                    &Err::<OrderReceipt, _>(rejection),
+                )
                 .await
-            {
-                Err(CreateAccountsError::Api(errs))
-                    if matches!(errs.as_slice()[0].kind(), CreateAccountErrorKind::Exists) => {}
-                Ok(()) => {}
-                Err(e) => {
-                    error!("error creating asset account: {e:?}");
-                    return Err(Status::InternalServerError);
-                }
-            }
-            accounting
-                .create_transfers(vec![tb::Transfer::new(order_id.as_u128())
-                    .with_code(1)
-                    .with_amount(form.size as u128)
-                    .with_ledger(asset_id as u32)
-                    .with_credit_account_id(asset_account_id)
-                    .with_debit_account_id(
-                        uuid::Uuid::new_v5(&ADMIN_ACCOUNT_ID, &asset_id.to_be_bytes()).as_u128(),
-                    )
-                    .with_flags(tb::transfer::Flags::PENDING)])
+        }
+        Err(OrderError::Failed(_)) => {
+            idempotency_key
+                .release(orders.as_mut(), account_id, ORDERS_SCOPE)
                 .await
-                .map_err(|e| {
-                    error!("error reserving assets for sell order: {e}");
-                    Status::InternalServerError
-                })?;
         }
     }
+    result.map(Json)
+}
 
-    let (shares_filled, dollar_volume, _orders): (
-        i32,
-        i32,
-        HashMap<super::types::Uuid, OrderBookEntry>,
-    ) = redis::Script::new(include_str!("scripts/order.lua"))
-        .prepare_invoke()
-        .key(asset_id)
-        .key(format!("{asset_id}_bids"))
-        .key(format!("{asset_id}_offers"))
-        .key(super::types::Uuid(account_id))
-        .key(super::types::Uuid(order_id))
-        .arg(book)
-        .arg(form.0)
-        .invoke_async(orders.as_mut())
-        .await
-        .map_err(|e| {
-            error!("error submitting order: {e}");
-            Status::InternalServerError
-        })?;
+/// The most orders accepted in one batch.
+const MAX_BATCH_ORDERS: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchOrderForm {
+    pub asset_id: i32,
+    /// Book the order is for: bids to buy, offers to sell.
+    pub book: Book,
+    #[serde(flatten)]
+    pub order: CreateOrderForm,
+}
 
-    // dbg!(orders);
-
-    // let closing_order_id = uuid::Uuid::now_v7();
-    // match book {
-    //     Book::Bids => {
-    //         match form.order_type {
-    //             OrderType::Limit { price} => {
-    //                 accounting
-    //                 .create_transfers(vec![tb::Transfer::new(closing_order_id.as_u128())
-    //                     .with_code(1)
-    //                     .with_amount(form.size as u128 * price as u128)
-    //                     .with_ledger(u32::MAX)
-    //                     .with_credit_account_id(account_id.as_u128())
-    //                     .with_debit_account_id(ADMIN_ACCOUNT_ID.as_u128())
-    //                     .with_flags(tb::transfer::Flags::PENDING)])
-    //                 .await
-    //                 .map_err(|e| {
-    //                     error!("error reserving funds: {e:?}");
-    //                     Status::InternalServerError
-    //                 })?;
-    //             }
-    //         }
-    //     }
-    // }
-
-    Ok(format!("{shares_filled}, {dollar_volume}"))
+/// What became of one order in a batch.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", tag = "result")]
+pub enum BatchOrderResult {
+    Accepted(OrderReceipt),
+    Rejected(RejectionBody),
+    /// The order couldn't be processed.
+    Failed,
+}
+
+impl From<OrderError> for BatchOrderResult {
+    fn from(error: OrderError) -> Self {
+        match error {
+            OrderError::Rejected(rejection) => Self::Rejected(rejection.into()),
+            OrderError::Failed(_) => Self::Failed,
+        }
+    }
 }
 
+/// # Submit Order Batch
+///
+/// Submit orders for any number of assets at once. Each order is checked and placed as if it
+/// were submitted on its own, in the order given, but the reservations for the whole batch are
+/// made together. Orders that are rejected or fail don't hold up the rest, and the results are
+/// listed in the same order as the orders.
+///
+/// Each order is checked against the account's risk limits separately, without counting the
+/// other orders in the batch. Batches are limited to 1000 orders.
+///
+/// Retries sent with the same `Idempotency-Key` get the results of the first submission instead
+/// of placing the orders again, including for orders that failed, which can be submitted again
+/// under a new key. Keys whose batch failed as a whole with a server error can be reused.
 #[openapi(tag = "Accounts")]
-#[get("/accounts/<account_id>/assets/orders?<cursor>")]
-pub async fn list_orders_for_account(
+#[post("/accounts/<account_id>/orders/batch", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn submit_order_batch_for_account(
     _check: UserIdCheck,
-    mut orders: Connection<Orders>,
     account_id: uuid::Uuid,
-    cursor: Option<usize>,
-) -> Result<Json<CursorList<OrderBookEntry>>, Status> {
-    let script = redis::Script::new(
-        r"
-        local cursor, keys = unpack(redis.call('ZSCAN', KEYS[1], ARGV[1]))
-        local results = {}
-        for i = 1, #keys, 2 do
-            local hash = redis.call('HGETALL', keys[i])
-            table.insert(results, hash)
-        end
-        return {cursor, results}
-    ",
-    );
-
-    script
-        .prepare_invoke()
-        .key(super::types::Uuid(account_id))
-        .arg(cursor.unwrap_or_default())
-        .invoke_async(orders.as_mut())
+    mut orders: Connection<Orders>,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+    instruments: &State<Instruments>,
+    trading_day: TradingDay,
+    idempotency_key: IdempotencyKey,
+    form: Json<List<BatchOrderForm>>,
+) -> Result<Json<List<BatchOrderResult>>, Status> {
+    let items = form.into_inner().items;
+    if items.len() > MAX_BATCH_ORDERS {
+        return Err(Status::UnprocessableEntity);
+    }
+    let replay: Option<List<BatchOrderResult>> = idempotency_key
+        .claim(orders.as_mut(), account_id, ORDER_BATCHES_SCOPE, &items)
+        .await?;
+    if let Some(response) = replay {
+        return Ok(Json(response));
+    }
+
+    let result = submit_batch(
+        &mut meta,
+        orders.as_mut(),
+        &accounting,
+        instruments,
+        trading_day,
+        &idempotency_key,
+        account_id,
+        items.clone(),
+    )
+    .await;
+    match &result {
+        Ok(results) => {
+            idempotency_key
+                .store(
+                    orders.as_mut(),
+                    account_id,
+                    ORDER_BATCHES_SCOPE,
+                    &items,
+                    results,
+                )
+                .await
+        }
+        Err(_) => {
+            idempotency_key
+                .release(orders.as_mut(), account_id, ORDER_BATCHES_SCOPE)
+                .await
+        }
+    }
+    result.map(Json)
+}
+
+/// Check, reserve for and place a batch of orders, with IDs derived from the idempotency key and
+/// their position in the batch. Fails only if nothing was placed.
+#[allow(clippy::too_many_arguments)]
+async fn submit_batch<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    instruments: &Instruments,
+    trading_day: TradingDay,
+    idempotency_key: &IdempotencyKey,
+    account_id: uuid::Uuid,
+    items: Vec<BatchOrderForm>,
+) -> Result<List<BatchOrderResult>, Status> {
+    let now = Utc::now();
+
+    // orders that pass their checks are left without a result until they are placed
+    let mut results = Vec::with_capacity(items.len());
+    let mut checked = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        match check_order(
+            meta,
+            orders,
+            accounting,
+            instruments,
+            trading_day,
+            account_id,
+            item.asset_id,
+            item.book,
+            idempotency_key.item_id(account_id, ORDER_BATCHES_SCOPE, index),
+            item.order,
+            now,
+        )
+        .await
+        {
+            Ok((reservation, form)) => {
+                checked.push((results.len(), reservation, form));
+                results.push(None);
+            }
+            Err(e) => results.push(Some(BatchOrderResult::from(e))),
+        }
+    }
+
+    // sellers need an account holding units of each asset they sell
+    let asset_accounts = checked
+        .iter()
+        .filter(|(_, reservation, _)| reservation.book == Book::Offers)
+        .map(|(_, reservation, _)| reservation.asset_id)
+        .unique()
+        .flat_map(|asset_id| {
+            [
+                asset_account(account_id, asset_id),
+                asset_account(ADMIN_ACCOUNT_ID, asset_id),
+            ]
+        })
+        .collect_vec();
+    if !asset_accounts.is_empty() {
+        ledger::create_accounts(accounting, asset_accounts)
+            .await
+            .map_err(|e| {
+                error!("error creating asset accounts: {e:?}");
+                Status::InternalServerError
+            })?;
+    }
+
+    // reserve funds/assets for the whole batch at once, leaving out orders whose reservation failed
+    // or that the account can't cover
+    let (positions, transfers): (Vec<usize>, Vec<tb::Transfer>) = checked
+        .iter()
+        .enumerate()
+        .filter_map(|(position, (_, reservation, _))| {
+            reservation.reserve(0).map(|transfer| (position, transfer))
+        })
+        .unzip();
+    let mut unreserved = HashSet::new();
+    let mut insufficient = HashSet::new();
+    if !transfers.is_empty() {
+        match ledger::create_transfers(meta, accounting, transfers).await {
+            Ok(()) => {}
+            Err(LedgerError::Create(CreateTransfersError::Api(errs))) => {
+                for err in errs.as_slice() {
+                    // already made by an earlier attempt at the same batch
+                    if matches!(
+                        err.kind(),
+                        CreateTransferErrorKind::Exists
+                            | CreateTransferErrorKind::ExistsWithDifferentTimeout
+                    ) {
+                        continue;
+                    }
+                    let position = positions[err.index() as usize];
+                    if ledger::exceeds_balance(err.kind()) {
+                        insufficient.insert(position);
+                        continue;
+                    }
+                    error!(
+                        "error reserving funds/assets for order {}: {err:?}",
+                        checked[position].1.order_id
+                    );
+                    unreserved.insert(position);
+                }
+            }
+            Err(e) => {
+                error!("error reserving funds/assets for orders: {e}");
+                return Err(Status::InternalServerError);
+            }
+        }
+    }
+
+    for (position, (index, reservation, form)) in checked.into_iter().enumerate() {
+        let result = if insufficient.contains(&position) {
+            BatchOrderResult::Rejected(Rejection::InsufficientFunds.into())
+        } else if unreserved.contains(&position) {
+            BatchOrderResult::Failed
+        } else {
+            match place(meta, orders, accounting, &reservation, &form, now).await {
+                Ok(execution) => match execution.receipt(reservation.order_id) {
+                    Ok(receipt) => BatchOrderResult::Accepted(receipt),
+                    Err(rejection) => BatchOrderResult::Rejected(rejection.into()),
+                },
+                Err(_) => BatchOrderResult::Failed,
+            }
+        };
+        results[index] = Some(result);
+    }
+
+    Ok(List::from(
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(BatchOrderResult::Failed))
+            .collect_vec(),
+    ))
+}
+
+/// Check, reserve for and place an order with ID `order_id`.
+#[allow(clippy::too_many_arguments)]
+async fn submit<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    instruments: &Instruments,
+    trading_day: TradingDay,
+    account_id: uuid::Uuid,
+    asset_id: i32,
+    book: Book,
+    order_id: uuid::Uuid,
+    form: CreateOrderForm,
+) -> Result<OrderReceipt, OrderError> {
+    let now = Utc::now();
+    let (reservation, form) = check_order(
+        meta,
+        orders,
+        accounting,
+        instruments,
+        trading_day,
+        account_id,
+        asset_id,
+        book,
+        order_id,
+        form,
+        now,
+    )
+    .await?;
+
+    // reserve funds/assets
+    if matches!(book, Book::Offers) {
+        // they want to sell, so they need an account holding units of the asset
+        ledger::create_accounts(
+            accounting,
+            vec![
+                asset_account(account_id, asset_id),
+                asset_account(ADMIN_ACCOUNT_ID, asset_id),
+            ],
+        )
+        .await
+        .map_err(|e| {
+            error!("error creating asset account: {e:?}");
+            Status::InternalServerError
+        })?;
+    }
+    if let Some(transfer) = reservation.reserve(0) {
+        // retries under an idempotency key make the same reservation
+        ledger::create_transfers_once(meta, accounting, vec![transfer])
+            .await
+            .map_err(|e| -> OrderError {
+                if e.is_insufficient() {
+                    return Rejection::InsufficientFunds.into();
+                }
+                error!("error reserving funds/assets for order: {e}");
+                Status::InternalServerError.into()
+            })?;
+    }
+
+    let execution = place(meta, orders, accounting, &reservation, &form, now).await?;
+    Ok(execution.receipt(order_id)?)
+}
+
+/// Check an order with ID `order_id` before anything is reserved for it, returning its
+/// reservation and the order as it is to be placed.
+#[allow(clippy::too_many_arguments)]
+async fn check_order<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    instruments: &Instruments,
+    trading_day: TradingDay,
+    account_id: uuid::Uuid,
+    asset_id: i32,
+    book: Book,
+    order_id: uuid::Uuid,
+    mut form: CreateOrderForm,
+    now: DateTime<Utc>,
+) -> Result<(Reservation, CreateOrderForm), OrderError> {
+    if form.time_in_force == TimeInForce::Day
+        && form.expires.replace(trading_day.close_after(now)).is_some()
+    {
+        return Err(Rejection::InvalidExpiry.into());
+    }
+    form.validate(now)?;
+    let price = form.order_type.price();
+
+    let instrument = instruments.get(meta, asset_id).await.map_err(|e| {
+        error!("error fetching trading rules for asset {asset_id}: {e}");
+        Status::InternalServerError
+    })??;
+    instrument.check(form.size, price)?;
+    if let Some(trigger) = form.order_type.trigger() {
+        instrument.check(form.size, Some(trigger))?;
+    }
+    if let Some(display_size) = form.display_size {
+        instrument.check(display_size, None)?;
+    }
+
+    let phase = auctions::phase(orders, asset_id).await.map_err(|e| {
+        error!("error fetching trading phase of asset {asset_id}: {e}");
+        Status::InternalServerError
+    })?;
+    let rests = price.is_some() && form.time_in_force.rests();
+    if phase == TradingPhase::Closed {
+        return Err(Rejection::NotTradable { asset_id }.into());
+    } else if phase.is_call() && form.order_type.trigger().is_none() && !rests {
+        return Err(Rejection::CallPhase { phase }.into());
+    }
+    if let OrderType::Limit { price } = form.order_type {
+        bands::check(orders, asset_id, price).await?;
+    }
+    risk::check(
+        meta, orders, accounting, account_id, asset_id, book, &form, None,
+    )
+    .await?;
+
+    let mut reservation = Reservation {
+        order_id,
+        account_id,
+        asset_id,
+        book,
+        price,
+        size: form.size,
+        expires: form.expires,
+    };
+    if matches!(form.order_type, OrderType::Market) {
+        reservation.price = market_price(orders, &reservation).await?;
+    }
+    Ok((reservation, form))
+}
+
+/// Price to reserve a market order at: the highest price a market buy may trade at, or none for
+/// a market sell, which reserves the assets it sells.
+async fn market_price<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    reservation: &Reservation,
+) -> Result<Option<Price>, Status> {
+    if reservation.book == Book::Offers {
+        return Ok(None);
+    }
+    bands::market_buy_limit(orders, reservation.asset_id)
         .await
-        .map(Json)
         .map_err(|e| {
-            error!("error listing orders: {e}");
+            error!(
+                "error pricing market buy of asset {}: {e}",
+                reservation.asset_id
+            );
             Status::InternalServerError
         })
 }
 
+/// Reserve funds for a stop buy that has triggered and is about to be submitted as a market
+/// order. If they can't be reserved, the order is submitted unfunded and trades nothing.
+async fn fund_triggered_buy<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    reservation: &mut Reservation,
+) {
+    let order_id = reservation.order_id;
+    reservation.price = match market_price(orders, reservation).await {
+        Ok(price) => price,
+        Err(_) => return,
+    };
+    if let Some(transfer) = reservation.reserve(0) {
+        if let Err(e) = ledger::create_transfers_once(meta, accounting, vec![transfer]).await {
+            error!("error reserving funds for triggered stop order {order_id}: {e}");
+            reservation.price = None;
+        }
+    }
+}
+
+/// Send an order whose funds/assets are already reserved to its asset's trigger book if it is a
+/// stop order yet to trigger, or to the book otherwise, then submit any stop orders it triggers.
+async fn place<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    reservation: &Reservation,
+    form: &CreateOrderForm,
+    now: DateTime<Utc>,
+) -> Result<Execution, Status> {
+    let Reservation {
+        order_id,
+        account_id,
+        asset_id,
+        book,
+        ..
+    } = *reservation;
+
+    if let Some(trigger) = form.order_type.trigger() {
+        // stop orders whose trigger has already traded go straight to the book
+        let placement: String = redis::Script::new(include_str!("scripts/stop.lua"))
+            .prepare_invoke()
+            .key(keys::asset(asset_id, format!("stop_{book}")))
+            .key(keys::order(asset_id, order_id))
+            .key(keys::asset(asset_id, "last"))
+            .key(keys::asset(asset_id, "expiries"))
+            .key(keys::asset(asset_id, "events"))
+            .arg(super::types::Uuid(order_id))
+            .arg(super::types::Uuid(account_id))
+            .arg(book)
+            .arg(trigger)
+            .arg(asset_id)
+            .arg(form)
+            .arg(now.timestamp_millis())
+            .invoke_async(orders)
+            .await
+            .map_err(|e| {
+                error!("error placing stop order: {e}");
+                Status::InternalServerError
+            })?;
+
+        if placement == "pending" {
+            return Ok(Execution {
+                status: Some(OrderStatus::Pending),
+                filled: 0,
+                dollar_volume: Notional::ZERO,
+                self_trade_cancels: Vec::new(),
+                triggered: Vec::new(),
+            });
+        }
+    }
+
+    let mut reservation = *reservation;
+    if matches!(form.order_type, OrderType::Stop { .. }) && reservation.book == Book::Bids {
+        // stop buys are only funded once triggered
+        fund_triggered_buy(meta, orders, accounting, &mut reservation).await;
+    }
+    let mut execution = execute(meta, orders, accounting, &reservation, form, now).await?;
+    let triggered = std::mem::take(&mut execution.triggered);
+    trigger_stops(meta, orders, accounting, asset_id, triggered, now).await;
+
+    Ok(execution)
+}
+
+/// Outcome of matching an order against the book.
+struct Execution {
+    /// `None` if a post-only order was rejected because it would have traded.
+    status: Option<OrderStatus>,
+    filled: u32,
+    dollar_volume: Notional,
+    self_trade_cancels: Vec<SelfTradeCancel>,
+    /// Stop orders triggered by the trades, to be submitted in turn.
+    triggered: Vec<TriggeredOrder>,
+}
+
+impl Execution {
+    /// Receipt for the order with ID `order_id`, unless it was rejected for trading.
+    fn receipt(self, order_id: uuid::Uuid) -> Result<OrderReceipt, Rejection> {
+        Ok(OrderReceipt {
+            order_id,
+            status: self.status.ok_or(Rejection::WouldTrade)?,
+            filled: self.filled,
+            dollar_volume: self.dollar_volume,
+            self_trade_cancels: self.self_trade_cancels,
+        })
+    }
+}
+
+/// Match an order whose funds/assets are already reserved against the book, then record and
+/// settle the trades.
+async fn execute<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    reservation: &Reservation,
+    form: &CreateOrderForm,
+    now: DateTime<Utc>,
+) -> Result<Execution, Status> {
+    let Reservation {
+        order_id,
+        account_id,
+        asset_id,
+        book,
+        ..
+    } = *reservation;
+
+    let self_trade_prevention: SelfTradePrevention = dsl::users
+        .find(account_id)
+        .select(dsl::self_trade_prevention)
+        .first(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching self-trade prevention for account {account_id}: {e}");
+            Status::InternalServerError
+        })?;
+
+    let (_, matched, status, expired, triggered, prevented): (
+        u32,
+        Vec<MatchedOrder>,
+        String,
+        Vec<ExpiredOrder>,
+        Vec<TriggeredOrder>,
+        u32,
+    ) = redis::Script::new(include_str!("scripts/order.lua"))
+        .prepare_invoke()
+        .key(keys::asset(asset_id, "bids"))
+        .key(keys::asset(asset_id, "offers"))
+        .key(keys::asset(asset_id, "last"))
+        .key(keys::asset(asset_id, "expiries"))
+        .key(keys::asset(asset_id, "stop_bids"))
+        .key(keys::asset(asset_id, "stop_offers"))
+        .key(keys::asset(asset_id, "sequence"))
+        .key(keys::asset(asset_id, "phase"))
+        .key(keys::asset(asset_id, "bands"))
+        .key(keys::asset(asset_id, "volatility"))
+        .key(keys::asset(asset_id, "reopening"))
+        .key(keys::asset(asset_id, "events"))
+        .arg(asset_id)
+        .arg(keys::order_prefix(asset_id))
+        .arg(super::types::Uuid(account_id))
+        .arg(super::types::Uuid(order_id))
+        .arg(book)
+        .arg(form)
+        .arg(self_trade_prevention)
+        .arg(now.timestamp_millis())
+        .arg(reservation.price.unwrap_or(Price::ZERO))
+        .invoke_async(orders)
+        .await
+        .map_err(|e| {
+            error!("error submitting order: {e}");
+            Status::InternalServerError
+        })?;
+
+    let mut filled = 0;
+    // units filled or decremented, which is what the order's reservation has been reduced by
+    let mut consumed = 0;
+    let mut dollar_volume = Notional::ZERO;
+    let mut fills = Vec::with_capacity(matched.len());
+    let mut buyer_accounts = Vec::with_capacity(matched.len());
+    let mut settlement = Vec::new();
+    let mut self_trade_cancels = Vec::new();
+    for (
+        maker_order_id,
+        maker_account_id,
+        price,
+        size,
+        original_size,
+        maker_filled,
+        outcome,
+        maker_expires,
+    ) in matched
+    {
+        let maker = Reservation {
+            order_id: maker_order_id.0,
+            account_id: maker_account_id.0,
+            asset_id,
+            book: book.opposite(),
+            price: Some(price),
+            size: original_size,
+            expires: DateTime::from_timestamp_millis(maker_expires).filter(|_| maker_expires > 0),
+        };
+
+        match outcome {
+            MatchOutcome::Trade => {}
+            MatchOutcome::Decrement => {
+                settlement.extend(ledger::decrement(reservation, consumed, size));
+                settlement.extend(ledger::decrement(&maker, maker_filled, size));
+                self_trade_cancels.push(SelfTradeCancel {
+                    order_id: maker.order_id,
+                    size,
+                });
+                consumed += size;
+                continue;
+            }
+            MatchOutcome::Cancel => {
+                settlement.extend(maker.release(maker_filled));
+                self_trade_cancels.push(SelfTradeCancel {
+                    order_id: maker.order_id,
+                    size,
+                });
+                continue;
+            }
+        }
+
+        let ((buy, buy_filled), (sell, sell_filled)) = match book {
+            Book::Bids => ((reservation, consumed), (&maker, maker_filled)),
+            Book::Offers => ((&maker, maker_filled), (reservation, consumed)),
+        };
+
+        let fill_id = uuid::Uuid::new_v5(&order_id, maker.order_id.as_bytes());
+        settlement.extend(ledger::settle(
+            fill_id,
+            (buy, buy_filled),
+            (sell, sell_filled),
+            price,
+            size,
+        ));
+        buyer_accounts.push(asset_account(buy.account_id, asset_id));
+        fills.push(Fill {
+            id: fill_id,
+            asset_id,
+            buy_order_id: buy.order_id,
+            sell_order_id: sell.order_id,
+            buyer_id: buy.account_id,
+            seller_id: sell.account_id,
+            price,
+            size: size as i32,
+            created: Utc::now(),
+        });
+
+        filled += size;
+        consumed += size;
+        dollar_volume = dollar_volume.saturating_add(price * size);
+    }
+    if prevented > 0 {
+        self_trade_cancels.push(SelfTradeCancel {
+            order_id,
+            size: prevented,
+        });
+    }
+
+    for expired in expired {
+        settlement.extend(expiry::release(asset_id, book.opposite(), expired));
+    }
+
+    let status = match status.as_str() {
+        "resting" => Some(OrderStatus::Resting),
+        "filled" => Some(OrderStatus::Filled),
+        "cancelled" => Some(OrderStatus::Cancelled),
+        // a post-only order that would have traded
+        _ => None,
+    };
+    if status != Some(OrderStatus::Resting) {
+        // the rest of an order that doesn't rest on the book is dropped
+        settlement.extend(reservation.release(consumed));
+    }
+
+    // The order has already matched, so failures here are logged rather than failing the
+    // request, and failed transfers are left for the worker to retry.
+    if let Err(e) = valuation::record_fills(meta, &fills).await {
+        error!("error recording fills for order {order_id}: {e}");
+    }
+    if !settlement.is_empty() {
+        if let Err(e) = ledger::create_accounts(accounting, buyer_accounts).await {
+            error!("error creating buyer asset accounts for order {order_id}: {e:?}");
+        }
+        if let Err(e) =
+            ledger::create_or_queue_transfers(meta, orders, accounting, settlement).await
+        {
+            error!("error settling order {order_id}: {e}");
+        }
+    }
+
+    Ok(Execution {
+        status,
+        filled,
+        dollar_volume,
+        self_trade_cancels,
+        triggered,
+    })
+}
+
+/// Submit stop orders triggered by a trade, along with any stop orders their own trades trigger.
+///
+/// The order that set them off has already been accepted, so failures are logged for
+/// reconciliation rather than failing its request.
+pub async fn trigger_stops<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    asset_id: i32,
+    triggered: Vec<TriggeredOrder>,
+    now: DateTime<Utc>,
+) {
+    let mut queue = VecDeque::from(triggered);
+    while let Some((
+        order_id,
+        account_id,
+        book,
+        order_type,
+        price,
+        size,
+        time_in_force,
+        expires,
+        display_size,
+    )) = queue.pop_front()
+    {
+        let order_type = match order_type.as_str() {
+            "limit" => OrderType::Limit { price },
+            _ => OrderType::Market,
+        };
+        let expires = DateTime::from_timestamp_millis(expires).filter(|_| expires > 0);
+        let mut reservation = Reservation {
+            order_id: order_id.0,
+            account_id: account_id.0,
+            asset_id,
+            book,
+            price: order_type.price(),
+            size,
+            expires,
+        };
+
+        if expires.is_some_and(|expires| expires <= now) {
+            // expired before the sweep got to it
+            if let Some(transfer) = reservation.release(0) {
+                if let Err(e) = ledger::void_reservations(meta, accounting, vec![transfer]).await {
+                    error!("error releasing reservation of expired stop order {order_id}: {e}");
+                }
+            }
+            continue;
+        }
+
+        let form = CreateOrderForm {
+            size,
+            order_type,
+            time_in_force,
+            expires,
+            post_only: false,
+            display_size: (display_size > 0).then_some(display_size),
+        };
+        if matches!(order_type, OrderType::Market) && book == Book::Bids {
+            fund_triggered_buy(meta, orders, accounting, &mut reservation).await;
+        }
+        match execute(meta, orders, accounting, &reservation, &form, now).await {
+            Ok(execution) => queue.extend(execution.triggered),
+            Err(_) => error!("error submitting triggered stop order {order_id}"),
+        }
+    }
+}
+
+/// An order as stored in Redis, either on the book or waiting in a trigger book.
+#[derive(Debug, Clone, FromRedisValue)]
+pub struct OrderRecord {
+    pub account_id: super::types::Uuid,
+    pub asset_id: i32,
+    pub side: Book,
+    pub price: Price,
+    pub size: u32,
+    pub original_size: u32,
+    pub expires: Option<i64>,
+    pub display_size: Option<u32>,
+    pub trigger: Option<Price>,
+    pub order_type: Option<String>,
+    pub time_in_force: Option<TimeInForce>,
+}
+
+impl OrderRecord {
+    /// The reservation held for the order, which has traded or been decremented by the
+    /// difference between its original and remaining size.
+    pub fn reservation(&self, order_id: uuid::Uuid) -> Reservation {
+        Reservation {
+            order_id,
+            account_id: self.account_id.0,
+            asset_id: self.asset_id,
+            book: self.side,
+            price: self.form().order_type.price(),
+            size: self.original_size,
+            expires: self.form().expires,
+        }
+    }
+
+    /// The order as it would be submitted for its remaining size.
+    fn form(&self) -> CreateOrderForm {
+        // orders on the book are always limit orders
+        let order_type = match (self.trigger, self.order_type.as_deref()) {
+            (None, _) => OrderType::Limit { price: self.price },
+            (Some(trigger), Some("limit")) => OrderType::StopLimit {
+                trigger,
+                price: self.price,
+            },
+            (Some(trigger), _) => OrderType::Stop { trigger },
+        };
+        let expires = self
+            .expires
+            .filter(|&expires| expires > 0)
+            .and_then(DateTime::from_timestamp_millis);
+
+        CreateOrderForm {
+            size: self.size,
+            order_type,
+            time_in_force: self.time_in_force.unwrap_or(match expires {
+                Some(_) => TimeInForce::Gtd,
+                None => TimeInForce::Gtc,
+            }),
+            expires,
+            post_only: false,
+            display_size: self.display_size.filter(|&display_size| display_size > 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct AmendOrderForm {
+    /// New limit price, for limit and stop-limit orders.
+    #[serde(default)]
+    pub price: Option<Price>,
+    /// New number of units left to fill.
+    #[serde(default)]
+    pub size: Option<u32>,
+}
+
+/// # Amend Order
+///
+/// Change the limit price and/or remaining size of an order on the book or waiting for its
+/// trigger price.
+///
+/// Reducing the size of an order on the book keeps its ID and time priority, and releases what
+/// was reserved for the units taken off. Any other change cancels the order and sends an amended
+/// copy through matching as if it were newly submitted, under the new ID in the receipt.
+///
+/// Orders are found through their account's index of open orders, which the worker brings up to
+/// date from the order event streams, so an order can only be amended once it has been indexed,
+/// usually within a moment of being placed.
+#[openapi(tag = "Accounts")]
+#[patch("/accounts/<account_id>/orders/<order_id>", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn amend_order_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    order_id: uuid::Uuid,
+    mut orders: Connection<Orders>,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+    instruments: &State<Instruments>,
+    form: Json<AmendOrderForm>,
+) -> Result<Json<OrderReceipt>, OrderError> {
+    let now = Utc::now();
+    let asset_id: Option<i32> = redis::cmd("ZSCORE")
+        .arg(keys::account_orders(account_id))
+        .arg(super::types::Uuid(order_id))
+        .query_async(orders.as_mut())
+        .await
+        .map_err(|e| {
+            error!("error looking up order {order_id}: {e}");
+            Status::InternalServerError
+        })?;
+    let asset_id = asset_id.ok_or(Status::NotFound)?;
+    let record: Option<OrderRecord> = redis::Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return nil
+        end
+        return redis.call('HGETALL', KEYS[1])
+    ",
+    )
+    .prepare_invoke()
+    .key(keys::order(asset_id, order_id))
+    .invoke_async(orders.as_mut())
+    .await
+    .map_err(|e| {
+        error!("error fetching order {order_id}: {e}");
+        Status::InternalServerError
+    })?;
+
+    let record = record
+        .filter(|record| record.account_id.0 == account_id)
+        .ok_or(Status::NotFound)?;
+    let original = record.form();
+    if original.expires.is_some_and(|expires| expires <= now) {
+        return Err(Status::NotFound.into());
+    }
+
+    let mut amended = original.clone();
+    if let Some(size) = form.size {
+        amended.size = size;
+    }
+    if let Some(price) = form.price {
+        amended.order_type = match original.order_type {
+            OrderType::Limit { .. } => OrderType::Limit { price },
+            OrderType::StopLimit { trigger, .. } => OrderType::StopLimit { trigger, price },
+            _ => return Err(Rejection::NoLimitPrice.into()),
+        };
+    }
+    amended.validate(now)?;
+    instruments
+        .get(&mut meta, record.asset_id)
+        .await
+        .map_err(|e| {
+            error!(
+                "error fetching trading rules for asset {}: {e}",
+                record.asset_id
+            );
+            Status::InternalServerError
+        })??
+        .check(amended.size, amended.order_type.price())?;
+    if let (Some(_), OrderType::Limit { price }) = (form.price, amended.order_type) {
+        bands::check(orders.as_mut(), record.asset_id, price).await?;
+    }
+    risk::check(
+        &mut meta,
+        orders.as_mut(),
+        &accounting,
+        account_id,
+        record.asset_id,
+        record.side,
+        &amended,
+        Some(order_id),
+    )
+    .await?;
+
+    // only the size of an order on the book going down keeps its ID, and with it its priority
+    let keeps_priority = record.trigger.is_none()
+        && form.price.map_or(true, |price| price == record.price)
+        && amended.size <= record.size;
+    let new_order_id = if keeps_priority {
+        order_id
+    } else {
+        uuid::Uuid::now_v7()
+    };
+    let reservation = Reservation {
+        order_id: new_order_id,
+        account_id,
+        asset_id: record.asset_id,
+        book: record.side,
+        price: amended.order_type.price(),
+        size: amended.size,
+        expires: amended.expires,
+    };
+    if let Some(transfer) = reservation.reserve(0).filter(|_| !keeps_priority) {
+        ledger::create_transfers(&mut meta, &accounting, vec![transfer])
+            .await
+            .map_err(|e| -> OrderError {
+                if e.is_insufficient() {
+                    return Rejection::InsufficientFunds.into();
+                }
+                error!("error reserving funds/assets for amended order: {e}");
+                Status::InternalServerError.into()
+            })?;
+    }
+
+    let replaced: Option<(String, Price, u32, u32)> =
+        redis::Script::new(include_str!("scripts/amend.lua"))
+            .prepare_invoke()
+            .key(keys::order(asset_id, order_id))
+            .key(keys::asset(asset_id, record.side))
+            .key(keys::asset(asset_id, format!("stop_{}", record.side)))
+            .key(keys::asset(asset_id, "expiries"))
+            .key(keys::asset(asset_id, "events"))
+            .arg(super::types::Uuid(order_id))
+            .arg(super::types::Uuid(new_order_id))
+            .arg(super::types::Uuid(account_id))
+            .arg(amended.size)
+            .arg(amended.order_type.price().unwrap_or(Price::ZERO))
+            .arg(now.timestamp_millis())
+            .invoke_async(orders.as_mut())
+            .await
+            .map_err(|e| {
+                error!("error amending order {order_id}: {e}");
+                Status::InternalServerError
+            })?;
+
+    let Some((outcome, price, size, original_size)) = replaced else {
+        // the order was filled, cancelled or expired in the meantime
+        if let Some(transfer) = reservation.release(0).filter(|_| !keeps_priority) {
+            if let Err(e) = ledger::void_reservations(&mut meta, &accounting, vec![transfer]).await
+            {
+                error!("error releasing reservation of amended order {new_order_id}: {e}");
+            }
+        }
+        return Err(Status::NotFound.into());
+    };
+
+    let previous = Reservation {
+        order_id,
+        account_id,
+        asset_id: record.asset_id,
+        book: record.side,
+        price: original.order_type.price().map(|_| price),
+        size: original_size,
+        expires: original.expires,
+    };
+    match outcome.as_str() {
+        "amended" => {
+            if amended.size < size {
+                let decrement =
+                    ledger::decrement(&previous, original_size - size, size - amended.size);
+                if let Err(e) = ledger::create_or_queue_transfers(
+                    &mut meta,
+                    orders.as_mut(),
+                    &accounting,
+                    decrement,
+                )
+                .await
+                {
+                    error!("error releasing reservation for units taken off order {order_id}: {e}");
+                }
+            }
+            return Ok(Json(OrderReceipt {
+                order_id,
+                status: OrderStatus::Resting,
+                filled: 0,
+                dollar_volume: Notional::ZERO,
+                self_trade_cancels: Vec::new(),
+            }));
+        }
+        // the order traded below its new size while it was being amended
+        "stale" => return Err(Status::Conflict.into()),
+        _ => {}
+    }
+    if let Some(transfer) = previous.release(original_size - size) {
+        if let Err(e) = ledger::void_reservations(&mut meta, &accounting, vec![transfer]).await {
+            error!("error releasing reservation of order {order_id}: {e}");
+        }
+    }
+
+    let execution = place(
+        &mut meta,
+        orders.as_mut(),
+        &accounting,
+        &reservation,
+        &amended,
+        now,
+    )
+    .await?;
+    Ok(Json(execution.receipt(new_order_id)?))
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CancelOrdersForm {
+    /// Only cancel orders for this asset.
+    #[serde(default)]
+    pub asset_id: Option<i32>,
+    /// Only cancel orders on this side.
+    #[serde(default)]
+    pub book: Option<Book>,
+}
+
+/// # Cancel Orders
+///
+/// Cancel the account's orders on the books and waiting for their trigger price, optionally only
+/// those for one asset and/or on one side, and release their reservations together. Returns the
+/// IDs of the orders cancelled.
+///
+/// Orders are found through the account's index of open orders, so orders placed a moment before
+/// may be missed. Orders that trade while they are being cancelled are left alone.
+#[openapi(tag = "Accounts")]
+#[post("/accounts/<account_id>/orders/cancel", data = "<form>")]
+pub async fn cancel_orders_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    mut orders: Connection<Orders>,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+    form: Json<CancelOrdersForm>,
+) -> Result<Json<List<uuid::Uuid>>, Status> {
+    let (min, max) = match form.asset_id {
+        Some(asset_id) => (asset_id.to_string(), asset_id.to_string()),
+        None => ("-inf".to_owned(), "+inf".to_owned()),
+    };
+    let indexed: Vec<(super::types::Uuid, i32)> = redis::cmd("ZRANGEBYSCORE")
+        .arg(keys::account_orders(account_id))
+        .arg(min)
+        .arg(max)
+        .arg("WITHSCORES")
+        .query_async(orders.as_mut())
+        .await
+        .map_err(|e| {
+            error!("error listing orders of account {account_id}: {e}");
+            Status::InternalServerError
+        })?;
+
+    let mut pipe = redis::pipe();
+    for &(order_id, asset_id) in &indexed {
+        pipe.cmd("HGETALL").arg(keys::order(asset_id, order_id.0));
+    }
+    let hashes: Vec<redis::Value> = if indexed.is_empty() {
+        Vec::new()
+    } else {
+        pipe.query_async(orders.as_mut()).await.map_err(|e| {
+            error!("error fetching orders of account {account_id}: {e}");
+            Status::InternalServerError
+        })?
+    };
+
+    let mut open = Vec::new();
+    for (&(order_id, _), hash) in indexed.iter().zip(&hashes) {
+        // left the book since the index was last brought up to date
+        if matches!(hash, redis::Value::Bulk(fields) if fields.is_empty()) {
+            continue;
+        }
+        let record: OrderRecord = redis::FromRedisValue::from_redis_value(hash).map_err(|e| {
+            error!("error fetching order {}: {e}", order_id.0);
+            Status::InternalServerError
+        })?;
+        if form.book.map_or(true, |book| book == record.side) {
+            open.push((order_id, record));
+        }
+    }
+
+    let mut cancelled = Vec::new();
+    let mut releases = Vec::new();
+    for (asset_id, open) in open
+        .into_iter()
+        .into_group_map_by(|(_, record)| record.asset_id)
+    {
+        let script = redis::Script::new(include_str!("scripts/remove.lua"));
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(keys::asset(asset_id, "bids"))
+            .key(keys::asset(asset_id, "offers"))
+            .key(keys::asset(asset_id, "stop_bids"))
+            .key(keys::asset(asset_id, "stop_offers"))
+            .key(keys::asset(asset_id, "expiries"))
+            .key(keys::asset(asset_id, "events"))
+            .arg(Utc::now().timestamp_millis())
+            .arg(CancelReason::Requested)
+            .arg(asset_id)
+            .arg(keys::order_prefix(asset_id));
+        for (order_id, record) in &open {
+            invocation.arg(order_id).arg(record.size);
+        }
+
+        let removed: HashSet<uuid::Uuid> = invocation
+            .invoke_async::<_, Vec<super::types::Uuid>>(orders.as_mut())
+            .await
+            .map_err(|e| {
+                error!("error cancelling orders of account {account_id} for asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?
+            .into_iter()
+            .map(|order_id| order_id.0)
+            .collect();
+        for (order_id, record) in open {
+            if removed.contains(&order_id.0) {
+                let filled = record.original_size - record.size;
+                releases.extend(record.reservation(order_id.0).release(filled));
+                cancelled.push(order_id.0);
+            }
+        }
+    }
+
+    // release the reservations together, within the ledger's limit per request
+    for chunk in releases.chunks(LOOKUP_BATCH_SIZE) {
+        ledger::void_reservations(&mut meta, &accounting, chunk.to_vec())
+            .await
+            .map_err(|e| {
+                error!("error releasing reservations of cancelled orders: {e}");
+                Status::InternalServerError
+            })?;
+    }
+
+    Ok(Json(List::from(cancelled)))
+}
+
+/// # Get Order
+///
+/// Show one of the account's orders, open or not, with what it has traded so far.
+///
+/// Orders are recorded by the worker from the order event streams, so changes show up a moment
+/// after they happen.
+#[openapi(tag = "Accounts")]
+#[get("/accounts/<account_id>/orders/<order_id>")]
+pub async fn get_order_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    order_id: uuid::Uuid,
+    mut meta: Connection<Meta>,
+) -> Result<Json<Order>, Status> {
+    history::find_order(&mut meta, account_id, order_id)
+        .await
+        .map_err(|e| {
+            error!("error fetching order {order_id}: {e}");
+            Status::InternalServerError
+        })?
+        .map(Json)
+        .ok_or(Status::NotFound)
+}
+
+/// # List Orders
+///
+/// List the account's orders, newest first, optionally only those for one asset, on one side
+/// or in one state. Orders are recorded by the worker from the order event streams, so changes
+/// show up a moment after they happen.
+#[openapi(tag = "Accounts")]
+#[get("/accounts/<account_id>/assets/orders?<cursor>&<asset_id>&<side>&<status>")]
+pub async fn list_orders_for_account(
+    _check: UserIdCheck,
+    mut meta: Connection<Meta>,
+    account_id: uuid::Uuid,
+    cursor: Option<usize>,
+    asset_id: Option<i32>,
+    side: Option<Book>,
+    status: Option<OrderState>,
+) -> Result<Json<CursorList<Order>>, Status> {
+    let filter = OrderFilter {
+        asset_id,
+        side,
+        status,
+    };
+    let (orders, cursor) =
+        history::list_orders(&mut meta, account_id, filter, cursor.unwrap_or_default())
+            .await
+            .map_err(|e| {
+                error!("error listing orders of account {account_id}: {e}");
+                Status::InternalServerError
+            })?;
+
+    Ok(Json(CursorList::new(orders, cursor)))
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 #[schemars(rename_all = "snake_case")]
@@ -509,35 +2255,99 @@ pub enum TxType {
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 pub struct BalanceForm {
-    pub amount: u128,
+    pub amount: Notional,
     pub r#type: TxType,
 }
 
+/// # Deposit or Withdraw
+///
+/// Move cash into or out of an account. Retries sent with the same `Idempotency-Key` move it
+/// only once.
 #[openapi(tag = "Accounts")]
 #[post("/accounts/<account_id>/assets/cash", data = "<form>")]
 pub async fn deposit_or_withdraw(
     _check: AdminCheck,
     account_id: uuid::Uuid,
     form: Json<BalanceForm>,
+    mut meta: Connection<Meta>,
+    mut orders: Connection<Orders>,
     accounting: Connection<Accounting>,
+    idempotency_key: IdempotencyKey,
+) -> Result<(), Status> {
+    if let Some(()) = idempotency_key
+        .claim(orders.as_mut(), account_id, CASH_SCOPE, &*form)
+        .await?
+    {
+        return Ok(());
+    }
+
+    let result = move_cash(&mut meta, &accounting, &idempotency_key, account_id, *form).await;
+    match result {
+        Ok(()) => {
+            idempotency_key
+                .store(orders.as_mut(), account_id, CASH_SCOPE, &*form, &())
+                .await
+        }
+        // nothing was moved, so the request can be retried
+        Err(_) => {
+            idempotency_key
+                .release(orders.as_mut(), account_id, CASH_SCOPE)
+                .await
+        }
+    }
+    result
+}
+
+/// Create the transfer for a deposit or withdrawal, with its ID derived from the idempotency key
+/// if there is one.
+async fn move_cash(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    idempotency_key: &IdempotencyKey,
+    account_id: uuid::Uuid,
+    form: BalanceForm,
 ) -> Result<(), Status> {
-    let (debit, credit) = match form.r#type {
-        TxType::Deposit => (account_id.as_u128(), ADMIN_ACCOUNT_ID.as_u128()),
-        TxType::Withdraw => (ADMIN_ACCOUNT_ID.as_u128(), account_id.as_u128()),
+    let amount = form
+        .amount
+        .to_ledger()
+        .filter(|&amount| amount > 0)
+        .ok_or(Status::BadRequest)?;
+    let (debit, credit, code) = match form.r#type {
+        TxType::Deposit => (
+            account_id.as_u128(),
+            ADMIN_ACCOUNT_ID.as_u128(),
+            TransferCode::Deposit,
+        ),
+        TxType::Withdraw => (
+            ADMIN_ACCOUNT_ID.as_u128(),
+            account_id.as_u128(),
+            TransferCode::Withdrawal,
+        ),
     };
 
-    accounting
-        .create_transfers(vec![tb::Transfer::new(uuid::Uuid::now_v7().as_u128())
-            .with_code(1)
-            .with_amount(form.amount)
-            .with_ledger(u32::MAX)
-            .with_debit_account_id(debit)
-            .with_credit_account_id(credit)])
-        .await
-        .map_err(|e| {
+    ledger::create_transfers_once(
+        meta,
+        accounting,
+        vec![
+            tb::Transfer::new(idempotency_key.id(account_id, CASH_SCOPE).as_u128())
+                .with_code(code.into())
+                .with_amount(amount)
+                .with_ledger(CASH_LEDGER)
+                .with_debit_account_id(debit)
+                .with_credit_account_id(credit),
+        ],
+    )
+    .await
+    .map_err(|e| match e {
+        LedgerError::Create(e) => {
             error!("error depositing/withdrawing: {e:?}");
             Status::BadRequest
-        })?;
+        }
+        e => {
+            error!("error depositing/withdrawing: {e}");
+            Status::InternalServerError
+        }
+    })?;
 
     Ok(())
 }
@@ -627,17 +2437,7 @@ pub async fn create_admin_user(rocket: Rocket<Build>) -> fairing::Result {
         }
     };
 
-    match accounting_conn
-        .create_accounts(vec![tb::Account::new(
-            ADMIN_ACCOUNT_ID.as_u128(),
-            u32::MAX,
-            1,
-        )
-        .with_user_data_128(ADMIN_ACCOUNT_ID.as_u128())])
-        .await
-    {
-        Err(CreateAccountsError::Api(errs))
-            if matches!(errs.as_slice()[0].kind(), CreateAccountErrorKind::Exists) => {}
+    match ledger::create_accounts(&accounting_conn, vec![cash_account(ADMIN_ACCOUNT_ID)]).await {
         Ok(()) => {}
         Err(e) => {
             error!("error setting up admin funds account: {e:?}");
@@ -655,6 +2455,7 @@ pub async fn create_admin_user(rocket: Rocket<Build>) -> fairing::Result {
                 email: form.email,
                 password: hash,
                 role_flags: Roles::ADMIN,
+                self_trade_prevention: SelfTradePrevention::default(),
             })
             .on_conflict(dsl::email)
             .do_update()
@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use chrono::{NaiveDate, NaiveDateTime};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use diesel::{
     backend::Backend,
     deserialize::{FromSql, FromSqlRow},
@@ -9,29 +9,39 @@ use diesel::{
     result::{DatabaseErrorKind, Error::DatabaseError},
     serialize::{Output, ToSql},
     sql_function,
-    sql_types::{Integer, Text},
+    sql_types::Text,
     ExpressionMethods, Queryable, Selectable,
 };
+use itertools::Itertools;
 use redis::FromRedisValue;
-use rocket::{get, http::Status, post, serde::json::Json};
+use rocket::{get, http::Status, post, put, serde::json::Json, State};
 use rocket_db_pools::{
-    diesel::prelude::{QueryDsl, RunQueryDsl},
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        AsyncPgConnection,
+    },
     Connection,
 };
 use rocket_okapi::openapi;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use strum_macros::{EnumString, IntoStaticStr};
+use tigerbeetle_unofficial as tb;
 use tracing::error;
 
 use super::{
     accounts::Book,
     auth::{AdminCheck, UserCheck},
+    events::CancelReason,
+    expiry,
+    instruments::Instruments,
+    keys, ledger,
+    types::{Price, Uuid},
     CursorList,
 };
 
 use super::List;
-use crate::{Meta, Orders};
+use crate::{Accounting, Meta, Orders};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, Selectable, JsonSchema)]
 #[diesel(table_name = super::schema::equities)]
@@ -45,6 +55,12 @@ pub struct Equity {
     pub description: Option<String>,
     /// Date & time of creation in RFC 3339 format.
     pub created: NaiveDateTime,
+    /// Smallest price increment orders can be placed at.
+    pub tick_size: Price,
+    /// Smallest number of units orders can be placed for.
+    pub lot_size: i32,
+    /// Whether the asset is accepting orders.
+    pub status: AssetStatus,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, Selectable, JsonSchema)]
@@ -59,10 +75,16 @@ pub struct EquityOption {
     pub expiration_date: NaiveDate,
     /// The kind of contract (call or put).
     pub contract_type: ContractType,
-    /// The strike price, measured in mills.
-    pub strike_price: Mills,
+    /// The strike price.
+    pub strike_price: Price,
     /// Date & time of creation in RFC 3339 format.
     pub created: NaiveDateTime,
+    /// Smallest price increment orders can be placed at.
+    pub tick_size: Price,
+    /// Smallest number of units orders can be placed for.
+    pub lot_size: i32,
+    /// Whether the asset is accepting orders.
+    pub status: AssetStatus,
 }
 
 /// # Get Equities
@@ -140,6 +162,12 @@ pub async fn get_equity_by_ticker(
 pub struct CreateEquityForm {
     pub ticker: String,
     pub description: Option<String>,
+    /// Smallest price increment orders can be placed at. Defaults to 0.001.
+    #[serde(default)]
+    pub tick_size: Option<Price>,
+    /// Smallest number of units orders can be placed for. Defaults to 1.
+    #[serde(default)]
+    pub lot_size: Option<i32>,
 }
 
 sql_function!(fn last_insert_rowid() -> Integer);
@@ -165,6 +193,7 @@ pub async fn create_equities(
     .map(Json)
     .map_err(|e| match e {
         DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Status::Conflict,
+        DatabaseError(DatabaseErrorKind::CheckViolation, _) => Status::UnprocessableEntity,
         e => {
             error!("error creating equities: {e}");
             Status::InternalServerError
@@ -182,8 +211,14 @@ pub struct CreateEquityOptionItem {
     pub expiration_date: NaiveDate,
     /// The kind of contract (call or put).
     pub contract_type: ContractType,
-    /// The strike price, measured in mills.
-    pub strike_price: Mills,
+    /// The strike price.
+    pub strike_price: Price,
+    /// Smallest price increment orders can be placed at. Defaults to 0.001.
+    #[serde(default)]
+    pub tick_size: Option<Price>,
+    /// Smallest number of units orders can be placed for. Defaults to 1.
+    #[serde(default)]
+    pub lot_size: Option<i32>,
 }
 
 /// # Create Equity Options
@@ -208,7 +243,10 @@ pub async fn create_equity_options(
     .map(Json)
     .map_err(|e| match e {
         DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Status::Conflict,
-        DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => Status::UnprocessableEntity,
+        DatabaseError(
+            DatabaseErrorKind::ForeignKeyViolation | DatabaseErrorKind::CheckViolation,
+            _,
+        ) => Status::UnprocessableEntity,
         e => {
             error!("error creating equity options: {e}");
             Status::InternalServerError
@@ -270,6 +308,9 @@ pub async fn list_equity_options_by_underlying_ticker(
                 contract_type,
                 strike_price,
                 created,
+                tick_size,
+                lot_size,
+                status,
             ))
             .load(&mut conn)
     }
@@ -287,19 +328,20 @@ pub async fn list_equity_options_by_underlying_ticker(
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct AnonymizedOrderBookEntry {
-    pub price: i32,
+    pub price: Price,
     pub size: u32,
 }
 
 impl FromRedisValue for AnonymizedOrderBookEntry {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-        <(i32, u32)>::from_redis_value(v).map(|(price, size)| Self { price, size })
+        <(Price, u32)>::from_redis_value(v).map(|(price, size)| Self { price, size })
     }
 }
 
 /// # Get Order Book
 ///
-/// Show the current state of the order book for a given asset.
+/// Show the current state of the order book for a given asset. Iceberg orders only show their
+/// visible slice.
 #[openapi(tag = "Assets")]
 #[get("/assets/<asset_id>/<book>?<cursor>", rank = 3)]
 pub async fn get_order_book(
@@ -314,8 +356,10 @@ pub async fn get_order_book(
         local cursor, keys = unpack(redis.call('ZSCAN', KEYS[1], ARGV[1]))
         local results = {}
         for i = 1, #keys, 2 do
-            local hash = redis.call('HMGET', keys[i], 'price', 'size')
-            table.insert(results, hash)
+            local order = ARGV[2] .. keys[i]
+            local price, size, visible = unpack(redis.call('HMGET', order, 'price', 'size', 'visible'))
+            -- only the visible slice of an iceberg order is shown
+            table.insert(results, {price, visible or size})
         end
 
         return {cursor, results}
@@ -324,8 +368,9 @@ pub async fn get_order_book(
 
     script
         .prepare_invoke()
-        .key(format!("{asset_id}_{book}"))
+        .key(keys::asset(asset_id, book))
         .arg(cursor.unwrap_or_default())
+        .arg(keys::order_prefix(asset_id))
         .invoke_async(orders.as_mut())
         .await
         .map(Json)
@@ -377,25 +422,180 @@ where
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, FromSqlRow, AsExpression, JsonSchema)]
-#[serde(transparent)]
-#[diesel(sql_type = Integer)]
-pub struct Mills(pub i32);
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Deserialize,
+    Serialize,
+    FromSqlRow,
+    AsExpression,
+    EnumString,
+    IntoStaticStr,
+    JsonSchema,
+    strum_macros::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum AssetStatus {
+    /// Orders are accepted.
+    Active,
+    /// Trading is suspended until the asset is made active again.
+    Halted,
+    /// The asset no longer trades.
+    Delisted,
+}
 
-impl<B: Backend> FromSql<Integer, B> for Mills
+impl<B: Backend> FromSql<Text, B> for AssetStatus
 where
-    i32: FromSql<Integer, B>,
+    String: FromSql<Text, B>,
 {
     fn from_sql(bytes: <B as Backend>::RawValue<'_>) -> diesel::deserialize::Result<Self> {
-        i32::from_sql(bytes).map(Self)
+        String::from_sql(bytes).and_then(|v| {
+            Self::from_str(&v).map_err(|e| format!("invalid asset status: {e}").into())
+        })
     }
 }
 
-impl<B: Backend> ToSql<Integer, B> for Mills
+impl<B: Backend> ToSql<Text, B> for AssetStatus
 where
-    i32: ToSql<Integer, B>,
+    str: ToSql<Text, B>,
 {
     fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, B>) -> diesel::serialize::Result {
-        i32::to_sql(&self.0, out)
+        str::to_sql(self.into(), out)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+pub struct AssetStatusForm {
+    pub status: AssetStatus,
+    /// Also cancel the asset's resting and stop orders, releasing what they reserved.
+    #[serde(default)]
+    pub cancel_orders: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct AssetStatusChange {
+    pub asset_id: i32,
+    pub status: AssetStatus,
+    /// Number of orders cancelled.
+    pub cancelled: usize,
+}
+
+/// # Set Asset Status
+///
+/// Halt, resume or delist an equity or equity option. Orders for halted or delisted assets are
+/// rejected, and their resting orders can be cancelled at the same time.
+#[openapi(tag = "Assets")]
+#[put("/assets/<asset_id>/status", data = "<form>")]
+pub async fn set_asset_status(
+    _check: AdminCheck,
+    asset_id: i32,
+    mut meta: Connection<Meta>,
+    mut orders: Connection<Orders>,
+    accounting: Connection<Accounting>,
+    instruments: &State<Instruments>,
+    form: Json<AssetStatusForm>,
+) -> Result<Json<AssetStatusChange>, Status> {
+    use super::schema::{equities, equity_options};
+
+    let mut updated = diesel::update(equities::table.find(asset_id))
+        .set(equities::status.eq(form.status))
+        .execute(&mut meta)
+        .await;
+    if let Ok(0) = updated {
+        updated = diesel::update(equity_options::table.find(asset_id))
+            .set(equity_options::status.eq(form.status))
+            .execute(&mut meta)
+            .await;
+    }
+    match updated {
+        Ok(0) => return Err(Status::NotFound),
+        Ok(_) => instruments.invalidate(asset_id),
+        Err(e) => {
+            error!("error setting status of asset {asset_id}: {e}");
+            return Err(Status::InternalServerError);
+        }
     }
+
+    let cancelled = if form.cancel_orders {
+        cancel_orders(
+            &mut meta,
+            orders.as_mut(),
+            &accounting,
+            asset_id,
+            form.status,
+        )
+        .await?
+    } else {
+        0
+    };
+
+    Ok(Json(AssetStatusChange {
+        asset_id,
+        status: form.status,
+        cancelled,
+    }))
+}
+
+/// Take every order for an asset off its books and trigger books and release their
+/// reservations, returning how many were cancelled. Their cancellations are recorded as caused by
+/// the asset's new status.
+async fn cancel_orders<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    asset_id: i32,
+    status: AssetStatus,
+) -> Result<usize, Status> {
+    let reason = match status {
+        AssetStatus::Delisted => CancelReason::Delisted,
+        AssetStatus::Active | AssetStatus::Halted => CancelReason::Halted,
+    };
+
+    let cancelled: Vec<(Uuid, Uuid, Book, Price, u32, u32)> =
+        redis::Script::new(include_str!("scripts/cancel.lua"))
+            .prepare_invoke()
+            .key(keys::asset(asset_id, "bids"))
+            .key(keys::asset(asset_id, "offers"))
+            .key(keys::asset(asset_id, "stop_bids"))
+            .key(keys::asset(asset_id, "stop_offers"))
+            .key(keys::asset(asset_id, "expiries"))
+            .key(keys::asset(asset_id, "events"))
+            .arg(Utc::now().timestamp_millis())
+            .arg(reason)
+            .arg(asset_id)
+            .arg(keys::order_prefix(asset_id))
+            .invoke_async(orders)
+            .await
+            .map_err(|e| {
+                error!("error cancelling orders for asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?;
+
+    let releases = cancelled
+        .iter()
+        .filter_map(
+            |&(order_id, account_id, book, price, size, original_size)| {
+                expiry::release(
+                    asset_id,
+                    book,
+                    (order_id, account_id, price, size, original_size),
+                )
+            },
+        )
+        .collect_vec();
+    if !releases.is_empty() {
+        ledger::void_reservations(meta, accounting, releases)
+            .await
+            .map_err(|e| {
+                error!("error releasing reservations of cancelled orders: {e}");
+                Status::InternalServerError
+            })?;
+    }
+
+    Ok(cancelled.len())
 }
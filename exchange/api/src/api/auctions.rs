@@ -0,0 +1,336 @@
+use chrono::{DateTime, Utc};
+use redis_derive::{FromRedisValue, ToRedisArgs};
+use rocket::{get, http::Status, put, serde::json::Json};
+use rocket_db_pools::{deadpool_redis::redis, diesel::AsyncPgConnection, Connection};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tigerbeetle_unofficial as tb;
+use tracing::error;
+
+use super::{
+    accounts::{self, asset_account, Book, TriggeredOrder},
+    auth::{AdminCheck, UserCheck},
+    keys,
+    ledger::{self, Reservation},
+    types::{Price, Uuid},
+    valuation::{self, Fill},
+};
+use crate::{Accounting, Meta, Orders};
+
+/// Where an asset is in its trading day. Assets trade continuously until they are first moved
+/// to another phase.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    ToRedisArgs,
+    FromRedisValue,
+    strum_macros::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[redis(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TradingPhase {
+    /// Orders are collected on the book without matching.
+    PreOpen,
+    /// Orders are collected on the book without matching, with the indicative opening price
+    /// published, until the book is uncrossed to open continuous trading.
+    OpeningAuction,
+    /// Orders match as they arrive.
+    #[default]
+    Continuous,
+    /// Orders are collected on the book without matching, with the indicative closing price
+    /// published, until the book is uncrossed to close the day.
+    ClosingAuction,
+    /// No orders are accepted.
+    Closed,
+    /// Orders are collected on the book without matching after trades would have moved the price
+    /// too far too fast, until the book is uncrossed to resume continuous trading.
+    VolatilityAuction,
+}
+
+impl TradingPhase {
+    /// Whether orders are collected on the book without matching.
+    pub fn is_call(self) -> bool {
+        matches!(
+            self,
+            Self::PreOpen | Self::OpeningAuction | Self::ClosingAuction | Self::VolatilityAuction
+        )
+    }
+
+    /// The phase an asset moves to from this one.
+    pub fn next(self) -> Self {
+        match self {
+            Self::PreOpen => Self::OpeningAuction,
+            Self::OpeningAuction => Self::Continuous,
+            Self::Continuous => Self::ClosingAuction,
+            Self::ClosingAuction => Self::Closed,
+            Self::Closed => Self::PreOpen,
+            Self::VolatilityAuction => Self::Continuous,
+        }
+    }
+
+    /// Whether the book is uncrossed on leaving this phase.
+    fn uncrosses(self) -> bool {
+        matches!(
+            self,
+            Self::OpeningAuction | Self::ClosingAuction | Self::VolatilityAuction
+        )
+    }
+}
+
+/// Current trading phase of an asset.
+pub async fn phase<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+) -> redis::RedisResult<TradingPhase> {
+    redis::cmd("GET")
+        .arg(keys::asset(asset_id, "phase"))
+        .query_async::<_, Option<TradingPhase>>(orders)
+        .await
+        .map(Option::unwrap_or_default)
+}
+
+/// A limit order taking part in an uncross: its ID, account ID, limit price, original size, size
+/// filled before and expiry in milliseconds since the epoch (0 if none).
+type AuctionOrder = (Uuid, Uuid, Price, u32, u32, i64);
+
+/// Run the auction script for an asset: the phase it was in, the equilibrium price and volume,
+/// the uncrossing trades and the stop orders they triggered.
+async fn auction<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+    transition: Option<(TradingPhase, TradingPhase)>,
+) -> redis::RedisResult<(
+    TradingPhase,
+    Option<Price>,
+    u32,
+    Vec<(AuctionOrder, AuctionOrder, u32)>,
+    Vec<TriggeredOrder>,
+)> {
+    let (expected, next, uncross) = match transition {
+        Some((from, to)) => (from.to_string(), to.to_string(), from.uncrosses()),
+        None => (String::new(), String::new(), false),
+    };
+
+    redis::Script::new(include_str!("scripts/auction.lua"))
+        .prepare_invoke()
+        .key(keys::asset(asset_id, "phase"))
+        .key(keys::asset(asset_id, "bids"))
+        .key(keys::asset(asset_id, "offers"))
+        .key(keys::asset(asset_id, "last"))
+        .key(keys::asset(asset_id, "stop_bids"))
+        .key(keys::asset(asset_id, "stop_offers"))
+        .key(keys::asset(asset_id, "expiries"))
+        .key(keys::asset(asset_id, "volatility"))
+        .key(keys::asset(asset_id, "events"))
+        .arg(expected)
+        .arg(next)
+        .arg(uncross as u8)
+        .arg(Utc::now().timestamp_millis())
+        .arg(asset_id)
+        .arg(keys::order_prefix(asset_id))
+        .invoke_async(orders)
+        .await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Auction {
+    pub phase: TradingPhase,
+    /// Price the book would be uncrossed at if the auction ended now, during an auction.
+    pub indicative_price: Option<Price>,
+    /// Units that would trade if the auction ended now, during an auction.
+    pub indicative_volume: u32,
+}
+
+/// # Get Auction
+///
+/// Show an asset's trading phase and, during a call, the price and volume the book would be
+/// uncrossed at if the call ended now.
+#[openapi(tag = "Assets")]
+#[get("/assets/<asset_id>/auction", rank = 2)]
+pub async fn get_auction(
+    _check: UserCheck,
+    asset_id: i32,
+    mut orders: Connection<Orders>,
+) -> Result<Json<Auction>, Status> {
+    let (phase, price, volume, _, _) =
+        auction(orders.as_mut(), asset_id, None)
+            .await
+            .map_err(|e| {
+                error!("error computing indicative auction price for asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?;
+
+    Ok(Json(if phase.is_call() {
+        Auction {
+            phase,
+            indicative_price: price,
+            indicative_volume: volume,
+        }
+    } else {
+        Auction {
+            phase,
+            indicative_price: None,
+            indicative_volume: 0,
+        }
+    }))
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct PhaseForm {
+    pub phase: TradingPhase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PhaseChange {
+    pub phase: TradingPhase,
+    /// Price the book was uncrossed at, when leaving an auction.
+    pub price: Option<Price>,
+    /// Units traded in the uncross.
+    pub volume: u32,
+}
+
+/// # Set Trading Phase
+///
+/// Move an asset on to the next phase of its trading day: pre-open, opening auction, continuous,
+/// closing auction, closed, then pre-open again. Leaving an auction uncrosses the book at the
+/// single price that executes the most volume, with every trade at that price.
+///
+/// Assets interrupted by a volatility auction resume continuous trading on their own once the
+/// auction is over, but can be moved on sooner.
+///
+/// Self-trade prevention does not apply to the uncross.
+#[openapi(tag = "Assets")]
+#[put("/assets/<asset_id>/phase", data = "<form>")]
+pub async fn set_trading_phase(
+    _check: AdminCheck,
+    asset_id: i32,
+    mut orders: Connection<Orders>,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+    form: Json<PhaseForm>,
+) -> Result<Json<PhaseChange>, Status> {
+    let current = phase(orders.as_mut(), asset_id).await.map_err(|e| {
+        error!("error fetching trading phase of asset {asset_id}: {e}");
+        Status::InternalServerError
+    })?;
+    if form.phase != current.next() {
+        return Err(Status::Conflict);
+    }
+
+    transition(
+        &mut meta,
+        orders.as_mut(),
+        &accounting,
+        asset_id,
+        current,
+        form.phase,
+    )
+    .await
+    .map(Json)
+}
+
+/// Move an asset from phase `current` to `next`, uncrossing the book and settling the trades when
+/// leaving an auction. Fails with [`Status::Conflict`] if the asset is no longer in `current`.
+pub async fn transition<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    asset_id: i32,
+    current: TradingPhase,
+    next: TradingPhase,
+) -> Result<PhaseChange, Status> {
+    let (previous, price, volume, matched, triggered) =
+        auction(orders, asset_id, Some((current, next)))
+            .await
+            .map_err(|e| {
+                error!("error moving asset {asset_id} to {next}: {e}");
+                Status::InternalServerError
+            })?;
+    if previous != current {
+        // changed by someone else in the meantime
+        return Err(Status::Conflict);
+    }
+
+    let now = Utc::now();
+    let uncross_id = uuid::Uuid::now_v7();
+    let mut fills = Vec::with_capacity(matched.len());
+    let mut buyer_accounts = Vec::with_capacity(matched.len());
+    let mut settlement = Vec::new();
+    for (bid, offer, size) in matched {
+        let price = price.expect("the book is only uncrossed at an equilibrium price");
+        let [(buy, buy_filled), (sell, sell_filled)] = [(bid, Book::Bids), (offer, Book::Offers)]
+            .map(
+                |((order_id, account_id, limit, original_size, filled, expires), book)| {
+                    (
+                        Reservation {
+                            order_id: order_id.0,
+                            account_id: account_id.0,
+                            asset_id,
+                            book,
+                            price: Some(limit),
+                            size: original_size,
+                            expires: DateTime::from_timestamp_millis(expires)
+                                .filter(|_| expires > 0),
+                        },
+                        filled,
+                    )
+                },
+            );
+
+        let fill_id = uuid::Uuid::new_v5(
+            &uncross_id,
+            &[buy.order_id.as_bytes().as_slice(), sell.order_id.as_bytes()].concat(),
+        );
+        settlement.extend(ledger::settle(
+            fill_id,
+            (&buy, buy_filled),
+            (&sell, sell_filled),
+            price,
+            size,
+        ));
+        buyer_accounts.push(asset_account(buy.account_id, asset_id));
+        fills.push(Fill {
+            id: fill_id,
+            asset_id,
+            buy_order_id: buy.order_id,
+            sell_order_id: sell.order_id,
+            buyer_id: buy.account_id,
+            seller_id: sell.account_id,
+            price,
+            size: size as i32,
+            created: now,
+        });
+    }
+
+    // The book has already been uncrossed, so failures here are logged rather than failing the
+    // request, and failed transfers are left for the worker to retry.
+    if let Err(e) = valuation::record_fills(meta, &fills).await {
+        error!("error recording uncrossing trades for asset {asset_id}: {e}");
+    }
+    if !settlement.is_empty() {
+        if let Err(e) = ledger::create_accounts(accounting, buyer_accounts).await {
+            error!("error creating buyer asset accounts for asset {asset_id}: {e:?}");
+        }
+        if let Err(e) =
+            ledger::create_or_queue_transfers(meta, orders, accounting, settlement).await
+        {
+            error!("error settling uncross of asset {asset_id}: {e}");
+        }
+    }
+    accounts::trigger_stops(meta, orders, accounting, asset_id, triggered, now).await;
+
+    Ok(PhaseChange {
+        phase: next,
+        price: price.filter(|_| current.uncrosses()),
+        volume: if current.uncrosses() { volume } else { 0 },
+    })
+}
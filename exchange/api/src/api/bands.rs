@@ -0,0 +1,306 @@
+use chrono::Utc;
+use rocket::{get, http::Status, put, serde::json::Json, State};
+use rocket_db_pools::{
+    deadpool_redis::redis::{self, ToRedisArgs},
+    diesel::AsyncPgConnection,
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tigerbeetle_unofficial as tb;
+use tracing::error;
+
+use super::{
+    auctions::{self, TradingPhase},
+    auth::{AdminCheck, UserCheck},
+    instruments::Instruments,
+    keys, reconciliation,
+    rejection::{OrderError, Rejection},
+    types::Price,
+};
+use crate::{Meta, Orders};
+
+/// How far and how fast an asset's price may move. Both limits are in basis points of a
+/// reference price, and are off unless set.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct PriceBands {
+    /// Limit orders priced further than this from the last trade are rejected, and market
+    /// orders stop matching there.
+    pub price_band: Option<u32>,
+    /// Trading is interrupted by a volatility auction when trades would move the price further
+    /// than this from where it was at the start of the volatility window.
+    pub volatility_threshold: Option<u32>,
+    /// Length of the volatility window in seconds.
+    #[serde(default = "default_volatility_window")]
+    pub volatility_window: u32,
+    /// How long a volatility auction collects orders, in seconds, before the book is uncrossed
+    /// and continuous trading resumes.
+    #[serde(default = "default_halt_duration")]
+    pub halt_duration: u32,
+}
+
+fn default_volatility_window() -> u32 {
+    60
+}
+
+fn default_halt_duration() -> u32 {
+    300
+}
+
+impl Default for PriceBands {
+    fn default() -> Self {
+        Self {
+            price_band: None,
+            volatility_threshold: None,
+            volatility_window: default_volatility_window(),
+            halt_duration: default_halt_duration(),
+        }
+    }
+}
+
+impl ToRedisArgs for PriceBands {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite,
+    {
+        // limits that are off are left out of the hash
+        for (field, value) in [
+            ("price_band", self.price_band),
+            ("volatility_threshold", self.volatility_threshold),
+            ("volatility_window", Some(self.volatility_window)),
+            ("halt_duration", Some(self.halt_duration)),
+        ] {
+            if let Some(value) = value {
+                field.write_redis_args(out);
+                value.write_redis_args(out);
+            }
+        }
+    }
+}
+
+impl PriceBands {
+    /// Lowest and highest limit price accepted given the last trade, if there is a price band.
+    pub fn collar(&self, last: Option<Price>) -> Option<(Price, Price)> {
+        let (band, last) = (self.price_band?, last?);
+        let width = last.mills() * band as i64 / 10_000;
+        Some((
+            Price::from_mills(last.mills() - width)?,
+            Price::from_mills(last.mills() + width)?,
+        ))
+    }
+}
+
+/// Price band, volatility threshold, volatility window and halt duration as stored in an asset's
+/// bands hash, if set.
+type BandFields = (Option<u32>, Option<u32>, Option<u32>, Option<u32>);
+
+/// Price bands of an asset and the price of its last trade.
+pub async fn get<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+) -> redis::RedisResult<(PriceBands, Option<Price>)> {
+    let ((price_band, volatility_threshold, volatility_window, halt_duration), last): (
+        BandFields,
+        Option<Price>,
+    ) = redis::pipe()
+        .cmd("HMGET")
+        .arg(keys::asset(asset_id, "bands"))
+        .arg(&[
+            "price_band",
+            "volatility_threshold",
+            "volatility_window",
+            "halt_duration",
+        ])
+        .cmd("GET")
+        .arg(keys::asset(asset_id, "last"))
+        .query_async(orders)
+        .await?;
+
+    let bands = PriceBands {
+        price_band,
+        volatility_threshold,
+        volatility_window: volatility_window.unwrap_or_else(default_volatility_window),
+        halt_duration: halt_duration.unwrap_or_else(default_halt_duration),
+    };
+    Ok((bands, last))
+}
+
+/// How far above the best offer market buys may trade when their asset has no price band, in
+/// basis points.
+const DEFAULT_MARKET_BUY_COLLAR: u32 = 1_000;
+
+/// Highest price a market buy may trade at, which its funds are reserved at: the best offer, or
+/// the last trade if there are no offers, widened by the asset's price band or
+/// [`DEFAULT_MARKET_BUY_COLLAR`] if it has none. `None` if there is nothing to price it from.
+pub async fn market_buy_limit<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+) -> redis::RedisResult<Option<Price>> {
+    let (bands, last) = get(orders, asset_id).await?;
+    let best_offer: Vec<(String, Price)> = redis::cmd("ZRANGE")
+        .arg(keys::asset(asset_id, "offers"))
+        .arg(0)
+        .arg(0)
+        .arg("WITHSCORES")
+        .query_async(orders)
+        .await?;
+
+    let band = bands.price_band.unwrap_or(DEFAULT_MARKET_BUY_COLLAR);
+    Ok(best_offer
+        .first()
+        .map(|&(_, price)| price)
+        .or(last)
+        .map(|reference| {
+            let width = reference.mills().saturating_mul(band as i64) / 10_000;
+            Price::from_mills(reference.mills().saturating_add(width)).unwrap_or(Price::MAX)
+        }))
+}
+
+/// Reject a limit order priced outside its asset's collar around the last trade.
+pub async fn check<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+    price: Price,
+) -> Result<(), OrderError> {
+    let (bands, last) = get(orders, asset_id).await.map_err(|e| {
+        error!("error fetching price bands of asset {asset_id}: {e}");
+        Status::InternalServerError
+    })?;
+
+    match bands.collar(last) {
+        Some((lower, upper)) if price < lower || price > upper => Err(Rejection::OutsideBand {
+            price,
+            lower,
+            upper,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// # Get Price Bands
+///
+/// Show how far from the last trade an asset's orders may be priced, and how far trades may move
+/// its price before trading is interrupted.
+#[openapi(tag = "Assets")]
+#[get("/assets/<asset_id>/bands")]
+pub async fn get_price_bands(
+    _check: UserCheck,
+    asset_id: i32,
+    mut orders: Connection<Orders>,
+) -> Result<Json<PriceBands>, Status> {
+    get(orders.as_mut(), asset_id)
+        .await
+        .map(|(bands, _)| Json(bands))
+        .map_err(|e| {
+            error!("error fetching price bands of asset {asset_id}: {e}");
+            Status::InternalServerError
+        })
+}
+
+/// # Set Price Bands
+///
+/// Set the price collar and volatility interruption limits of an asset, replacing the previous
+/// ones. Limits left out are turned off.
+#[openapi(tag = "Assets")]
+#[put("/assets/<asset_id>/bands", data = "<form>")]
+pub async fn set_price_bands(
+    _check: AdminCheck,
+    asset_id: i32,
+    mut orders: Connection<Orders>,
+    mut meta: Connection<Meta>,
+    instruments: &State<Instruments>,
+    form: Json<PriceBands>,
+) -> Result<Json<PriceBands>, Status> {
+    if form.price_band == Some(0) || form.volatility_threshold == Some(0) {
+        return Err(Status::UnprocessableEntity);
+    }
+    if instruments
+        .get(&mut meta, asset_id)
+        .await
+        .map_err(|e| {
+            error!("error fetching trading rules for asset {asset_id}: {e}");
+            Status::InternalServerError
+        })?
+        .is_err()
+    {
+        return Err(Status::NotFound);
+    }
+
+    let key = keys::asset(asset_id, "bands");
+    redis::pipe()
+        .atomic()
+        .del(&key)
+        .ignore()
+        .cmd("HSET")
+        .arg(&key)
+        .arg(*form)
+        .ignore()
+        .query_async::<_, ()>(orders.as_mut())
+        .await
+        .map_err(|e| {
+            error!("error setting price bands of asset {asset_id}: {e}");
+            Status::InternalServerError
+        })?;
+
+    Ok(form)
+}
+
+/// Uncross the books of assets whose volatility auction is over and resume continuous trading,
+/// returning how many reopened.
+pub async fn reopen_assets<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+) -> Result<usize, Status> {
+    let asset_ids = reconciliation::asset_ids(meta).await?;
+    if asset_ids.is_empty() {
+        return Ok(0);
+    }
+
+    // each asset in a volatility auction holds when it reopens, in its own slot
+    let mut pipe = redis::pipe();
+    for &asset_id in &asset_ids {
+        pipe.cmd("GET").arg(keys::asset(asset_id, "reopening"));
+    }
+    let reopenings: Vec<Option<i64>> = pipe.query_async(orders).await.map_err(|e| {
+        error!("error fetching assets due to reopen: {e}");
+        Status::InternalServerError
+    })?;
+
+    let now = Utc::now().timestamp_millis();
+    let mut reopened = 0;
+    for (asset_id, _) in asset_ids
+        .into_iter()
+        .zip(reopenings)
+        .filter(|&(_, reopening)| reopening.is_some_and(|reopening| reopening <= now))
+    {
+        match auctions::transition(
+            meta,
+            orders,
+            accounting,
+            asset_id,
+            TradingPhase::VolatilityAuction,
+            TradingPhase::Continuous,
+        )
+        .await
+        {
+            Ok(_) => reopened += 1,
+            // already reopened by an admin
+            Err(status) if status == Status::Conflict => {}
+            Err(status) => return Err(status),
+        }
+
+        redis::cmd("DEL")
+            .arg(keys::asset(asset_id, "reopening"))
+            .query_async::<_, ()>(orders)
+            .await
+            .map_err(|e| {
+                error!("error removing reopening of asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?;
+    }
+
+    Ok(reopened)
+}
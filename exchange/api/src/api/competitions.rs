@@ -0,0 +1,531 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    prelude::Insertable,
+    result::{DatabaseErrorKind, Error::DatabaseError},
+    ExpressionMethods, Queryable, Selectable,
+};
+use itertools::Itertools;
+use rocket::{get, http::Status, post, put, serde::json::Json, FromFormField};
+use rocket_db_pools::{
+    deadpool_redis::redis,
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        scoped_futures::ScopedFutureExt,
+        AsyncConnection, AsyncPgConnection,
+    },
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tigerbeetle_unofficial as tb;
+use tracing::error;
+
+use super::{
+    accounts::{asset_account_id, posted_balance, UserIdCheck},
+    auth::{AdminCheck, UserCheck},
+    ledger::{self, LedgerError, TransferCode, CASH_LEDGER, LOOKUP_BATCH_SIZE},
+    schema::{competition_assets, competition_entries, competitions, leaderboard_snapshots},
+    types::{Notional, Price},
+    valuation, List, ADMIN_ACCOUNT_ID,
+};
+use crate::{Accounting, Meta, Orders};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Selectable, JsonSchema)]
+#[diesel(table_name = competitions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Competition {
+    /// A unique identifier for this competition.
+    pub id: i32,
+    pub name: String,
+    /// Date & time the competition starts, in RFC 3339 format.
+    pub starts: DateTime<Utc>,
+    /// Date & time the competition ends, in RFC 3339 format.
+    pub ends: DateTime<Utc>,
+    /// Cash each participant is given on entering, used to compute returns.
+    pub starting_cash: Notional,
+    /// Date & time of creation in RFC 3339 format.
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CompetitionDetails {
+    #[serde(flatten)]
+    pub competition: Competition,
+    /// Assets whose positions count towards portfolio value. Empty if every equity counts.
+    pub asset_ids: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct CreateCompetitionForm {
+    pub name: String,
+    pub starts: DateTime<Utc>,
+    pub ends: DateTime<Utc>,
+    pub starting_cash: Notional,
+    /// Assets whose positions count towards portfolio value. Leave empty to count every equity.
+    #[serde(default)]
+    pub asset_ids: Vec<i32>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = competitions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct NewCompetition<'a> {
+    name: &'a str,
+    starts: DateTime<Utc>,
+    ends: DateTime<Utc>,
+    starting_cash: Notional,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = competition_assets)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct CompetitionAsset {
+    competition_id: i32,
+    asset_id: i32,
+}
+
+/// # Create Competition
+///
+/// Create a trading competition ranking accounts between two dates.
+#[openapi(tag = "Competitions")]
+#[post("/competitions", data = "<form>")]
+pub async fn create_competition(
+    _check: AdminCheck,
+    mut conn: Connection<Meta>,
+    form: Json<CreateCompetitionForm>,
+) -> Result<Json<CompetitionDetails>, Status> {
+    let form = form.0;
+
+    conn.transaction(|conn| {
+        async move {
+            let competition: Competition = diesel::insert_into(competitions::table)
+                .values(NewCompetition {
+                    name: &form.name,
+                    starts: form.starts,
+                    ends: form.ends,
+                    starting_cash: form.starting_cash,
+                })
+                .get_result(conn)
+                .await?;
+
+            diesel::insert_into(competition_assets::table)
+                .values(
+                    form.asset_ids
+                        .iter()
+                        .map(|&asset_id| CompetitionAsset {
+                            competition_id: competition.id,
+                            asset_id,
+                        })
+                        .collect_vec(),
+                )
+                .execute(conn)
+                .await?;
+
+            Ok(CompetitionDetails {
+                competition,
+                asset_ids: form.asset_ids,
+            })
+        }
+        .scope_boxed()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| match e {
+        DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Status::Conflict,
+        DatabaseError(DatabaseErrorKind::CheckViolation, _) => Status::UnprocessableEntity,
+        e => {
+            error!("error creating competition: {e}");
+            Status::InternalServerError
+        }
+    })
+}
+
+/// # List Competitions
+#[openapi(tag = "Competitions")]
+#[get("/competitions")]
+pub async fn list_competitions(
+    _check: UserCheck,
+    mut conn: Connection<Meta>,
+) -> Result<Json<List<Competition>>, Status> {
+    competitions::table
+        .order(competitions::starts.desc())
+        .load(&mut conn)
+        .await
+        .map(List::from)
+        .map(Json)
+        .map_err(|e| {
+            error!("error listing competitions: {e}");
+            Status::InternalServerError
+        })
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = competition_entries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct Entry {
+    competition_id: i32,
+    account_id: uuid::Uuid,
+}
+
+/// # Enter Competition
+///
+/// Enter an account into a competition that hasn't ended, depositing the competition's starting
+/// cash into it. Entering again has no further effect.
+#[openapi(tag = "Competitions")]
+#[put("/accounts/<account_id>/competitions/<competition_id>")]
+pub async fn enter_competition(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    competition_id: i32,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+) -> Result<(), Status> {
+    let competition: Competition = competitions::table
+        .find(competition_id)
+        .first(&mut meta)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => Status::NotFound,
+            e => {
+                error!("error fetching competition {competition_id}: {e}");
+                Status::InternalServerError
+            }
+        })?;
+    if competition.ends <= Utc::now() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    diesel::insert_into(competition_entries::table)
+        .values(Entry {
+            competition_id,
+            account_id,
+        })
+        .on_conflict_do_nothing()
+        .execute(&mut meta)
+        .await
+        .map_err(|e| {
+            error!("error entering account {account_id} into competition {competition_id}: {e}");
+            Status::InternalServerError
+        })?;
+
+    // the deposit's ID is derived from the entry, so entering again doesn't deposit twice
+    let Some(amount) = competition
+        .starting_cash
+        .to_ledger()
+        .filter(|&amount| amount > 0)
+    else {
+        return Ok(());
+    };
+    let id = uuid::Uuid::new_v5(
+        &account_id,
+        format!("competition:{competition_id}").as_bytes(),
+    );
+    ledger::create_transfers_once(
+        &mut meta,
+        &accounting,
+        vec![tb::Transfer::new(id.as_u128())
+            .with_code(TransferCode::Deposit.into())
+            .with_amount(amount)
+            .with_ledger(CASH_LEDGER)
+            .with_debit_account_id(account_id.as_u128())
+            .with_credit_account_id(ADMIN_ACCOUNT_ID.as_u128())],
+    )
+    .await
+    .map_err(|e| match e {
+        LedgerError::Create(e) => {
+            error!("error depositing starting cash for account {account_id}: {e:?}");
+            Status::BadRequest
+        }
+        e => {
+            error!("error depositing starting cash for account {account_id}: {e}");
+            Status::InternalServerError
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, FromFormField, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RankBy {
+    /// Rank by portfolio value.
+    #[default]
+    Value,
+    /// Rank by return on the starting cash.
+    Return,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Standing {
+    pub rank: usize,
+    pub account_id: uuid::Uuid,
+    /// Cash plus positions in the competition's assets, valued at their mark prices.
+    pub value: Notional,
+    /// Change in value relative to the starting cash.
+    pub r#return: f64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Leaderboard {
+    pub competition: Competition,
+    pub standings: Vec<Standing>,
+}
+
+/// # Get Leaderboard
+///
+/// Rank accounts in a competition, or in the latest competition to have started if none is given.
+/// Competitions that have ended are ranked as of the last snapshot taken before they ended.
+#[openapi(tag = "Competitions")]
+#[get("/leaderboard?<competition_id>&<rank_by>")]
+pub async fn get_leaderboard(
+    _check: UserCheck,
+    competition_id: Option<i32>,
+    rank_by: Option<RankBy>,
+    mut meta: Connection<Meta>,
+    mut orders: Connection<Orders>,
+    accounting: Connection<Accounting>,
+) -> Result<Json<Leaderboard>, Status> {
+    let competition: Competition = match competition_id {
+        Some(competition_id) => competitions::table.find(competition_id).first(&mut meta),
+        None => competitions::table
+            .filter(competitions::starts.le(Utc::now()))
+            .order(competitions::starts.desc())
+            .first(&mut meta),
+    }
+    .await
+    .map_err(|e| match e {
+        diesel::result::Error::NotFound => Status::NotFound,
+        e => {
+            error!("error fetching competition: {e}");
+            Status::InternalServerError
+        }
+    })?;
+
+    let mut standings = match final_standings(&mut meta, &competition).await? {
+        Some(standings) => standings,
+        None => standings(&mut meta, orders.as_mut(), &accounting, &competition).await?,
+    };
+    if let RankBy::Return = rank_by.unwrap_or_default() {
+        standings.sort_by(|a, b| b.r#return.total_cmp(&a.r#return));
+        for (i, standing) in standings.iter_mut().enumerate() {
+            standing.rank = i + 1;
+        }
+    }
+
+    Ok(Json(Leaderboard {
+        competition,
+        standings,
+    }))
+}
+
+/// Standings of a competition that has ended, from the last snapshot taken before it ended, if
+/// there is one.
+async fn final_standings(
+    meta: &mut AsyncPgConnection,
+    competition: &Competition,
+) -> Result<Option<Vec<Standing>>, Status> {
+    use super::schema::leaderboard_snapshots::dsl::*;
+
+    if competition.ends > Utc::now() {
+        return Ok(None);
+    }
+    let last: Option<DateTime<Utc>> = leaderboard_snapshots
+        .filter(competition_id.eq(competition.id))
+        .filter(taken.le(competition.ends))
+        .select(diesel::dsl::max(taken))
+        .first(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching last leaderboard snapshot: {e}");
+            Status::InternalServerError
+        })?;
+    let Some(last) = last else {
+        return Ok(None);
+    };
+
+    let values: Vec<(uuid::Uuid, Notional)> = leaderboard_snapshots
+        .filter(competition_id.eq(competition.id))
+        .filter(taken.eq(last))
+        .select((account_id, value))
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching leaderboard snapshot: {e}");
+            Status::InternalServerError
+        })?;
+    Ok(Some(rank(competition, values)))
+}
+
+/// Rank participants by the value of their portfolios.
+fn rank(competition: &Competition, values: Vec<(uuid::Uuid, Notional)>) -> Vec<Standing> {
+    let mut standings = values
+        .into_iter()
+        .map(|(account_id, value)| Standing {
+            rank: 0,
+            account_id,
+            value,
+            r#return: value
+                .checked_sub(competition.starting_cash)
+                .and_then(|gain| gain.ratio(competition.starting_cash))
+                .unwrap_or_default(),
+        })
+        .collect_vec();
+
+    standings.sort_by_key(|standing| Reverse(standing.value));
+    for (i, standing) in standings.iter_mut().enumerate() {
+        standing.rank = i + 1;
+    }
+    standings
+}
+
+/// Value every participant's portfolio and rank them by value.
+pub async fn standings<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    competition: &Competition,
+) -> Result<Vec<Standing>, Status> {
+    let mut asset_ids: Vec<i32> = competition_assets::table
+        .filter(competition_assets::competition_id.eq(competition.id))
+        .select(competition_assets::asset_id)
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching competition assets: {e}");
+            Status::InternalServerError
+        })?;
+    if asset_ids.is_empty() {
+        use super::schema::equities::dsl::*;
+        asset_ids = equities.select(id).load(meta).await.map_err(|e| {
+            error!("error fetching equities: {e}");
+            Status::InternalServerError
+        })?;
+    }
+
+    let participants: Vec<uuid::Uuid> = competition_entries::table
+        .filter(competition_entries::competition_id.eq(competition.id))
+        .select(competition_entries::account_id)
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching participants: {e}");
+            Status::InternalServerError
+        })?;
+
+    let mut marks = HashMap::with_capacity(asset_ids.len());
+    for &asset_id in &asset_ids {
+        let mark = valuation::mark(orders, asset_id).await.map_err(|e| {
+            error!("error fetching mark price: {e}");
+            Status::InternalServerError
+        })?;
+        marks.insert(asset_id, mark.unwrap_or(Price::ZERO));
+    }
+
+    let tb_ids = participants
+        .iter()
+        .flat_map(|&account_id| {
+            std::iter::once(account_id.as_u128()).chain(
+                asset_ids
+                    .iter()
+                    .map(move |&asset_id| asset_account_id(account_id, asset_id)),
+            )
+        })
+        .collect_vec();
+
+    let mut balances = HashMap::with_capacity(tb_ids.len());
+    for chunk in tb_ids.chunks(LOOKUP_BATCH_SIZE) {
+        let accounts: Vec<tb::Account> =
+            accounting
+                .lookup_accounts(chunk.to_vec())
+                .await
+                .map_err(|e| {
+                    error!("error fetching balances from tigerbeetle: {e}");
+                    Status::InternalServerError
+                })?;
+        balances.extend(
+            accounts
+                .iter()
+                .map(|account| (account.id(), posted_balance(account))),
+        );
+    }
+
+    let mut values = Vec::with_capacity(participants.len());
+    for account_id in participants {
+        let mut value = Notional::from_mills(
+            balances
+                .get(&account_id.as_u128())
+                .copied()
+                .unwrap_or_default(),
+        );
+        for asset_id in &asset_ids {
+            let units = balances
+                .get(&asset_account_id(account_id, *asset_id))
+                .copied()
+                .unwrap_or_default();
+            value = marks[asset_id]
+                .checked_mul(units)
+                .and_then(|position| value.checked_add(position))
+                .ok_or_else(|| {
+                    error!("portfolio value of account {account_id} overflowed");
+                    Status::InternalServerError
+                })?;
+        }
+
+        values.push((account_id, value));
+    }
+
+    Ok(rank(competition, values))
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = leaderboard_snapshots)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct Snapshot {
+    competition_id: i32,
+    account_id: uuid::Uuid,
+    taken: DateTime<Utc>,
+    value: Notional,
+}
+
+/// Record the standings of every competition that is running.
+pub async fn take_snapshots<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+) -> Result<(), Status> {
+    let now = Utc::now();
+    let running: Vec<Competition> = competitions::table
+        .filter(competitions::starts.le(now))
+        .filter(competitions::ends.gt(now))
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching running competitions: {e}");
+            Status::InternalServerError
+        })?;
+
+    for competition in running {
+        let snapshots = standings(meta, orders, accounting, &competition)
+            .await?
+            .into_iter()
+            .map(|standing| Snapshot {
+                competition_id: competition.id,
+                account_id: standing.account_id,
+                taken: now,
+                value: standing.value,
+            })
+            .collect_vec();
+
+        diesel::insert_into(leaderboard_snapshots::table)
+            .values(snapshots)
+            .execute(meta)
+            .await
+            .map_err(|e| {
+                error!("error recording leaderboard snapshot: {e}");
+                Status::InternalServerError
+            })?;
+    }
+
+    Ok(())
+}
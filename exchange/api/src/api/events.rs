@@ -0,0 +1,125 @@
+use redis_derive::{FromRedisValue, ToRedisArgs};
+use rocket_db_pools::deadpool_redis::redis::{self, FromRedisValue as _};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    accounts::Book,
+    types::{Price, Uuid},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, FromRedisValue)]
+#[serde(rename_all = "snake_case")]
+#[redis(rename_all = "snake_case")]
+pub enum OrderEventKind {
+    /// The order passed its checks and entered matching.
+    Accepted,
+    /// The order traded its last units.
+    Filled,
+    /// The order traded, with units left over.
+    PartiallyFilled,
+    /// Units of the order were taken off the book without trading.
+    Cancelled,
+    /// The order's good 'til date passed.
+    Expired,
+}
+
+/// Key of the stream every asset's order events are copied to by the worker, for consumers that
+/// follow all assets at once. An event may be copied more than once, so each copy carries the ID
+/// it has in its asset's stream as `source_id`.
+pub const EVENTS_KEY: &str = "events";
+
+/// Roughly how many events the stream of every asset's events is trimmed to.
+const EVENTS_LENGTH: usize = 1_000_000;
+
+/// Copy an entry with ID `id` of an asset's event stream to the stream of every asset's events.
+pub async fn publish<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    id: &str,
+    fields: &redis::Value,
+) -> redis::RedisResult<()> {
+    let fields = Vec::<Vec<u8>>::from_redis_value(fields)?;
+    redis::cmd("XADD")
+        .arg(EVENTS_KEY)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(EVENTS_LENGTH)
+        .arg("*")
+        .arg(fields)
+        .arg("source_id")
+        .arg(id)
+        .query_async(orders)
+        .await
+}
+
+/// Why units of an order were cancelled.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    ToRedisArgs,
+    FromRedisValue,
+)]
+#[serde(rename_all = "snake_case")]
+#[redis(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// The order would have traded with another order from its account.
+    SelfTrade,
+    /// A fill or kill order couldn't be filled in full.
+    FillOrKill,
+    /// What was left of a market or immediate or cancel order after matching.
+    Unfilled,
+    /// The order was replaced by an amended copy.
+    Amended,
+    /// Its asset was halted.
+    Halted,
+    /// Its asset was delisted.
+    Delisted,
+    /// Reconciliation found no reservation for it in the ledger.
+    Unfunded,
+    /// Its account cancelled it.
+    Requested,
+}
+
+/// A change to an order, as appended to its asset's event stream by the scripts that change the
+/// books.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, FromRedisValue)]
+pub struct OrderEvent {
+    pub event: OrderEventKind,
+    pub order_id: Uuid,
+    pub account_id: Uuid,
+    pub asset_id: i32,
+    pub side: Book,
+    /// The trade price for fills, and the order's limit price otherwise. Zero for market orders.
+    pub price: Price,
+    /// Units the event applies to.
+    pub size: u32,
+    /// Units of the order still open afterwards.
+    pub remaining: u32,
+    /// When the event happened, in milliseconds since the epoch.
+    pub timestamp: i64,
+    /// Set for cancellations.
+    pub reason: Option<CancelReason>,
+    /// The other order in a fill.
+    pub counterparty: Option<Uuid>,
+    /// The amended order replacing a cancelled one, which is accepted under its own ID.
+    pub replaced_by: Option<Uuid>,
+    /// Time priority of an accepted order if it rests on the book, or of an iceberg order whose
+    /// next slice went to the back of the queue.
+    pub priority: Option<i64>,
+    /// Good 'til date expiry of an accepted order, in milliseconds since the epoch.
+    pub expires: Option<i64>,
+    /// Visible size of an accepted iceberg order.
+    pub display_size: Option<u32>,
+    /// Trigger price of an accepted stop order.
+    pub trigger: Option<Price>,
+    /// Type and time in force of an accepted order, or for a stop order of the order it becomes
+    /// once triggered.
+    pub order_type: Option<String>,
+    pub time_in_force: Option<String>,
+}
@@ -0,0 +1,158 @@
+use chrono::{DateTime, Days, NaiveTime, Utc};
+use itertools::Itertools;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use rocket_db_pools::{deadpool_redis::redis, diesel::AsyncPgConnection};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use tigerbeetle_unofficial as tb;
+use tracing::error;
+
+use super::{
+    accounts::Book,
+    keys,
+    ledger::{self, Reservation},
+    reconciliation,
+    types::{Price, Uuid},
+};
+
+/// The most orders removed by one run of the expiry script.
+const SWEEP_BATCH_SIZE: usize = 1000;
+
+/// The trading day, configured by `orders.day_close` as the UTC time of day it ends (21:00 by
+/// default).
+#[derive(Debug, Clone, Copy)]
+pub struct TradingDay {
+    close: NaiveTime,
+}
+
+impl TradingDay {
+    /// The first end of a trading day after `now`, when day orders expire.
+    pub fn close_after(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let close = now.date_naive().and_time(self.close).and_utc();
+        if close > now {
+            close
+        } else {
+            close + Days::new(1)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for TradingDay {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let close = req
+            .rocket()
+            .figment()
+            .extract_inner("orders.day_close")
+            .unwrap_or(NaiveTime::from_hms_opt(21, 0, 0).expect("valid time of day"));
+        Outcome::Success(Self { close })
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for TradingDay {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// An order removed from the book because it expired: its ID, account ID, price, remaining size
+/// and original size.
+pub type ExpiredOrder = (Uuid, Uuid, Price, u32, u32);
+
+/// Transfer voiding the reservation of an expired or cancelled order resting on `book`.
+pub fn release(
+    asset_id: i32,
+    book: Book,
+    (order_id, account_id, price, size, original_size): ExpiredOrder,
+) -> Option<tb::Transfer> {
+    Reservation {
+        order_id: order_id.0,
+        account_id: account_id.0,
+        asset_id,
+        book,
+        price: Some(price),
+        size: original_size,
+        // only released, so the reservation's timeout doesn't matter
+        expires: None,
+    }
+    .release(original_size - size)
+}
+
+/// Remove good 'til date and day orders past their expiry from the book and release their
+/// reservations, returning how many were removed.
+///
+/// Each asset is swept in batches until no expired orders are left, so the orders that all expire
+/// at the end of the trading day are cleared in one run. Orders are also dropped when they are
+/// reached while matching, and their reservations time out in the ledger on their own shortly
+/// after they expire, so this mostly keeps the books tidy.
+pub async fn expire_orders<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+) -> Result<usize, Status> {
+    let mut removed = 0;
+    for asset_id in reconciliation::asset_ids(meta).await? {
+        loop {
+            let expired: Vec<(Uuid, Uuid, Book, Price, u32, u32)> =
+                redis::Script::new(include_str!("scripts/expire.lua"))
+                    .prepare_invoke()
+                    .key(keys::asset(asset_id, "expiries"))
+                    .key(keys::asset(asset_id, "bids"))
+                    .key(keys::asset(asset_id, "offers"))
+                    .key(keys::asset(asset_id, "stop_bids"))
+                    .key(keys::asset(asset_id, "stop_offers"))
+                    .key(keys::asset(asset_id, "events"))
+                    .arg(Utc::now().timestamp_millis())
+                    .arg(SWEEP_BATCH_SIZE)
+                    .arg(asset_id)
+                    .arg(keys::order_prefix(asset_id))
+                    .invoke_async(orders)
+                    .await
+                    .map_err(|e| {
+                        error!("error expiring orders for asset {asset_id}: {e}");
+                        Status::InternalServerError
+                    })?;
+
+            let releases = expired
+                .iter()
+                .filter_map(
+                    |&(order_id, account_id, book, price, size, original_size)| {
+                        release(
+                            asset_id,
+                            book,
+                            (order_id, account_id, price, size, original_size),
+                        )
+                    },
+                )
+                .collect_vec();
+
+            if !releases.is_empty() {
+                ledger::void_reservations(meta, accounting, releases)
+                    .await
+                    .map_err(|e| {
+                        error!("error releasing reservations of expired orders: {e}");
+                        Status::InternalServerError
+                    })?;
+            }
+
+            removed += expired.len();
+            if expired.len() < SWEEP_BATCH_SIZE {
+                break;
+            }
+        }
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,585 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    backend::Backend,
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    prelude::Insertable,
+    serialize::{self, Output, ToSql},
+    sql_types::Text,
+    AsChangeset, BoolExpressionMethods, ExpressionMethods, OptionalExtension, Queryable,
+    Selectable, SelectableHelper,
+};
+use itertools::Itertools;
+use rocket::{http::Status, post, serde::json::Json, FromFormField};
+use rocket_db_pools::{
+    deadpool_redis::redis,
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        AsyncPgConnection,
+    },
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use strum_macros::{EnumString, IntoStaticStr};
+use tigerbeetle_unofficial as tb;
+use tracing::{error, info, warn};
+
+use super::{
+    accounts::{asset_account_id, Book, OrderType, TimeInForce},
+    auth::AdminCheck,
+    events::{OrderEvent, OrderEventKind},
+    keys,
+    ledger::{CASH_LEDGER, LOOKUP_BATCH_SIZE},
+    reconciliation,
+    schema::{fills, orders},
+    types::{Notional, Price, Uuid},
+    ADMIN_ACCOUNT_ID,
+};
+use crate::{Accounting, Meta, Orders};
+
+/// What became of an order.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    FromSqlRow,
+    AsExpression,
+    EnumString,
+    IntoStaticStr,
+    JsonSchema,
+    FromFormField,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum OrderState {
+    /// The order is on the book or waiting for its trigger price.
+    Open,
+    /// The order traded all its units.
+    Filled,
+    /// The rest of the order was cancelled.
+    Cancelled,
+    /// The rest of the order expired.
+    Expired,
+}
+
+impl<B: Backend> FromSql<Text, B> for OrderState
+where
+    String: FromSql<Text, B>,
+{
+    fn from_sql(bytes: <B as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        String::from_sql(bytes).and_then(|v| {
+            Self::from_str(&v).map_err(|e| format!("invalid order state: {e}").into())
+        })
+    }
+}
+
+impl<B: Backend> ToSql<Text, B> for OrderState
+where
+    str: ToSql<Text, B>,
+{
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, B>) -> serialize::Result {
+        str::to_sql(self.into(), out)
+    }
+}
+
+/// An order as recorded from the event stream.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, AsChangeset)]
+#[diesel(table_name = orders)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(treat_none_as_null = true)]
+struct OrderRow {
+    id: uuid::Uuid,
+    account_id: uuid::Uuid,
+    asset_id: i32,
+    side: Book,
+    price: Price,
+    size: i32,
+    original_size: i32,
+    priority: Option<i64>,
+    expires: Option<i64>,
+    display_size: Option<i32>,
+    trigger: Option<Price>,
+    order_type: Option<String>,
+    time_in_force: Option<String>,
+    open: bool,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    status: OrderState,
+}
+
+/// Apply an order event to the order table.
+///
+/// Events for an order are applied in the order they happened, and each sets the order's
+/// remaining size outright, so an event applied again leaves the table as it was.
+pub async fn record(meta: &mut AsyncPgConnection, event: &OrderEvent) -> diesel::QueryResult<()> {
+    let timestamp = DateTime::from_timestamp_millis(event.timestamp).unwrap_or_default();
+
+    if event.event == OrderEventKind::Accepted {
+        // triggered stop orders are accepted again, this time for the book
+        let row = OrderRow {
+            id: event.order_id.0,
+            account_id: event.account_id.0,
+            asset_id: event.asset_id,
+            side: event.side,
+            price: event.price,
+            size: event.remaining as i32,
+            original_size: event.size as i32,
+            priority: event.priority,
+            expires: event.expires.filter(|&expires| expires > 0),
+            display_size: event
+                .display_size
+                .filter(|&display_size| display_size > 0)
+                .map(|display_size| display_size as i32),
+            trigger: event.trigger,
+            order_type: event.order_type.clone(),
+            time_in_force: event.time_in_force.clone(),
+            open: true,
+            created: timestamp,
+            updated: timestamp,
+            status: OrderState::Open,
+        };
+        diesel::insert_into(orders::table)
+            .values(&row)
+            .on_conflict(orders::id)
+            .do_update()
+            .set(&row)
+            .execute(meta)
+            .await?;
+        return Ok(());
+    }
+
+    let status = match event.event {
+        _ if event.remaining > 0 => OrderState::Open,
+        OrderEventKind::Filled => OrderState::Filled,
+        OrderEventKind::Expired => OrderState::Expired,
+        _ => OrderState::Cancelled,
+    };
+    diesel::update(orders::table.find(event.order_id.0))
+        .set((
+            orders::size.eq(event.remaining as i32),
+            orders::open.eq(event.remaining > 0),
+            orders::updated.eq(timestamp),
+            orders::status.eq(status),
+        ))
+        .execute(meta)
+        .await?;
+    if let Some(priority) = event.priority {
+        diesel::update(orders::table.find(event.order_id.0))
+            .set(orders::priority.eq(priority))
+            .execute(meta)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Most orders listed per page.
+const ORDERS_PAGE_SIZE: usize = 100;
+
+/// An order of an account, as recorded from the order event streams.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Order {
+    pub id: uuid::Uuid,
+    pub account_id: uuid::Uuid,
+    pub asset_id: i32,
+    pub side: Book,
+    /// Stop orders that have triggered show as the order they became.
+    #[serde(flatten)]
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub expires: Option<DateTime<Utc>>,
+    /// Visible size of an iceberg order.
+    pub display_size: Option<u32>,
+    /// Units the order was placed for.
+    pub original_size: u32,
+    /// Units still open.
+    pub size: u32,
+    /// Units traded.
+    pub filled: u32,
+    /// Average price of the units traded, if any were.
+    pub average_price: Option<Price>,
+    /// Total value of the units traded.
+    pub dollar_volume: Notional,
+    pub status: OrderState,
+    pub created: DateTime<Utc>,
+    /// When the order last changed.
+    pub updated: DateTime<Utc>,
+}
+
+impl Order {
+    fn new(row: OrderRow, filled: u32, dollar_volume: Notional) -> Self {
+        let order_type = match (row.trigger, row.order_type.as_deref()) {
+            (Some(trigger), Some("limit")) => OrderType::StopLimit {
+                trigger,
+                price: row.price,
+            },
+            (Some(trigger), _) => OrderType::Stop { trigger },
+            (None, Some("market")) => OrderType::Market,
+            // orders recorded without their type are market orders if they have no price
+            (None, None) if row.price == Price::ZERO => OrderType::Market,
+            (None, _) => OrderType::Limit { price: row.price },
+        };
+        let expires = row.expires.and_then(DateTime::from_timestamp_millis);
+
+        Self {
+            id: row.id,
+            account_id: row.account_id,
+            asset_id: row.asset_id,
+            side: row.side,
+            order_type,
+            time_in_force: row
+                .time_in_force
+                .and_then(|time_in_force| time_in_force.parse().ok())
+                .unwrap_or(match expires {
+                    Some(_) => TimeInForce::Gtd,
+                    None => TimeInForce::Gtc,
+                }),
+            expires,
+            display_size: row.display_size.map(|display_size| display_size as u32),
+            original_size: row.original_size as u32,
+            size: row.size as u32,
+            filled,
+            average_price: (filled > 0).then(|| {
+                Price::from_mills((dollar_volume.mills() / filled as i128) as i64)
+                    .unwrap_or_default()
+            }),
+            dollar_volume,
+            status: row.status,
+            created: row.created,
+            updated: row.updated,
+        }
+    }
+}
+
+/// Criteria for listing an account's orders.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrderFilter {
+    pub asset_id: Option<i32>,
+    pub side: Option<Book>,
+    pub status: Option<OrderState>,
+}
+
+/// One of an account's orders, with what it has traded.
+pub async fn find_order(
+    meta: &mut AsyncPgConnection,
+    account_id: uuid::Uuid,
+    order_id: uuid::Uuid,
+) -> diesel::QueryResult<Option<Order>> {
+    let row: Option<OrderRow> = orders::table
+        .find(order_id)
+        .filter(orders::account_id.eq(account_id))
+        .select(OrderRow::as_select())
+        .first(meta)
+        .await
+        .optional()?;
+
+    match row {
+        Some(row) => {
+            let mut traded = traded(meta, &[row.id]).await?;
+            let (filled, dollar_volume) = traded.remove(&row.id).unwrap_or_default();
+            Ok(Some(Order::new(row, filled, dollar_volume)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A page of an account's orders matching `filter`, newest first, starting `cursor` orders in,
+/// along with the cursor of the next page, or zero if this is the last.
+pub async fn list_orders(
+    meta: &mut AsyncPgConnection,
+    account_id: uuid::Uuid,
+    filter: OrderFilter,
+    cursor: usize,
+) -> diesel::QueryResult<(Vec<Order>, usize)> {
+    let mut query = orders::table
+        .filter(orders::account_id.eq(account_id))
+        .into_boxed();
+    if let Some(asset_id) = filter.asset_id {
+        query = query.filter(orders::asset_id.eq(asset_id));
+    }
+    if let Some(side) = filter.side {
+        query = query.filter(orders::side.eq(side));
+    }
+    if let Some(status) = filter.status {
+        query = query.filter(orders::status.eq(status));
+    }
+    let rows: Vec<OrderRow> = query
+        .order((orders::created.desc(), orders::id))
+        .offset(cursor as i64)
+        .limit(ORDERS_PAGE_SIZE as i64)
+        .select(OrderRow::as_select())
+        .load(meta)
+        .await?;
+
+    let next = if rows.len() == ORDERS_PAGE_SIZE {
+        cursor + rows.len()
+    } else {
+        0
+    };
+    let mut traded = traded(meta, &rows.iter().map(|row| row.id).collect_vec()).await?;
+    let orders = rows
+        .into_iter()
+        .map(|row| {
+            let (filled, dollar_volume) = traded.remove(&row.id).unwrap_or_default();
+            Order::new(row, filled, dollar_volume)
+        })
+        .collect();
+    Ok((orders, next))
+}
+
+/// Units traded by each of the orders, and their total value.
+async fn traded(
+    meta: &mut AsyncPgConnection,
+    order_ids: &[uuid::Uuid],
+) -> diesel::QueryResult<HashMap<uuid::Uuid, (u32, Notional)>> {
+    if order_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let fills: Vec<(uuid::Uuid, uuid::Uuid, Price, i32)> = fills::table
+        .filter(
+            fills::buy_order_id
+                .eq_any(order_ids)
+                .or(fills::sell_order_id.eq_any(order_ids)),
+        )
+        .select((
+            fills::buy_order_id,
+            fills::sell_order_id,
+            fills::price,
+            fills::size,
+        ))
+        .load(meta)
+        .await?;
+
+    let order_ids: HashSet<&uuid::Uuid> = order_ids.iter().collect();
+    let mut traded: HashMap<uuid::Uuid, (u32, Notional)> = HashMap::new();
+    for (buy_order_id, sell_order_id, price, size) in fills {
+        for order_id in [buy_order_id, sell_order_id] {
+            if order_ids.contains(&order_id) {
+                let (filled, dollar_volume) = traded.entry(order_id).or_default();
+                *filled += size as u32;
+                *dollar_volume = dollar_volume.saturating_add(price * size as u32);
+            }
+        }
+    }
+    Ok(traded)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct RebuiltBook {
+    pub asset_id: i32,
+    /// Orders restored to the asset's books and trigger books.
+    pub orders: usize,
+}
+
+/// Cash or units of an asset held back for open orders, according to the books and to the
+/// ledger.
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct ReservedTotal {
+    /// Asset held back, or none for cash.
+    pub asset_id: Option<i32>,
+    /// What the orders on the books should have reserved.
+    pub books: u128,
+    /// What the ledger holds in pending reservations.
+    pub ledger: u128,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Recovery {
+    pub rebuilt: Vec<RebuiltBook>,
+    /// Assets with open orders whose books weren't empty, which were left alone.
+    pub skipped: Vec<i32>,
+    /// Reserved totals that differ between the books and the ledger once rebuilt.
+    pub mismatched: Vec<ReservedTotal>,
+}
+
+/// Restore open orders from the order table to assets whose books are empty, then check the
+/// reservations the books call for against those held in the ledger.
+pub async fn rebuild_books<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+) -> Result<Recovery, Status> {
+    let rows: Vec<OrderRow> = orders::table
+        .filter(orders::open.eq(true))
+        .order((orders::asset_id, orders::created))
+        .select(OrderRow::as_select())
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching open orders: {e}");
+            Status::InternalServerError
+        })?;
+
+    let mut rebuilt = Vec::new();
+    let mut skipped = Vec::new();
+    for (asset_id, rows) in rows
+        .into_iter()
+        .into_group_map_by(|row| row.asset_id)
+        .into_iter()
+        .sorted_by_key(|&(asset_id, _)| asset_id)
+    {
+        let script = redis::Script::new(include_str!("scripts/rebuild.lua"));
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(keys::asset(asset_id, "bids"))
+            .key(keys::asset(asset_id, "offers"))
+            .key(keys::asset(asset_id, "stop_bids"))
+            .key(keys::asset(asset_id, "stop_offers"))
+            .key(keys::asset(asset_id, "sequence"))
+            .key(keys::asset(asset_id, "expiries"))
+            .arg(asset_id)
+            .arg(keys::order_prefix(asset_id));
+        for row in &rows {
+            invocation
+                .arg(Uuid(row.id))
+                .arg(Uuid(row.account_id))
+                .arg(row.side)
+                .arg(row.price)
+                .arg(row.size)
+                .arg(row.original_size)
+                .arg(row.priority.unwrap_or(0))
+                .arg(row.expires.unwrap_or(0))
+                .arg(row.display_size.unwrap_or(0))
+                // prices are positive, so no trigger is sent as zero
+                .arg(row.trigger.map_or(0, Price::mills))
+                .arg(row.order_type.as_deref().unwrap_or(""))
+                .arg(row.time_in_force.as_deref().unwrap_or(""));
+        }
+
+        let restored: Option<usize> = invocation.invoke_async(orders).await.map_err(|e| {
+            error!("error rebuilding books of asset {asset_id}: {e}");
+            Status::InternalServerError
+        })?;
+        match restored {
+            Some(restored) => {
+                // the account indexes are in other slots, so they are restored separately
+                let mut pipe = redis::pipe();
+                for row in &rows {
+                    pipe.cmd("ZADD")
+                        .arg(keys::account_orders(row.account_id))
+                        .arg(asset_id)
+                        .arg(Uuid(row.id))
+                        .ignore();
+                }
+                pipe.query_async::<_, ()>(orders).await.map_err(|e| {
+                    error!("error restoring account indexes for asset {asset_id}: {e}");
+                    Status::InternalServerError
+                })?;
+
+                info!("restored {restored} orders to the books of asset {asset_id}");
+                rebuilt.push(RebuiltBook {
+                    asset_id,
+                    orders: restored,
+                });
+            }
+            None => skipped.push(asset_id),
+        }
+    }
+
+    let mismatched = reserved_totals(meta, orders, accounting)
+        .await?
+        .into_iter()
+        .filter(|total| total.books != total.ledger)
+        .collect_vec();
+    for total in &mismatched {
+        warn!(
+            "books call for {} reserved in ledger {:?}, but the ledger holds {}",
+            total.books, total.asset_id, total.ledger
+        );
+    }
+
+    Ok(Recovery {
+        rebuilt,
+        skipped,
+        mismatched,
+    })
+}
+
+/// What the orders on the books should have reserved and what the ledger holds in pending
+/// reservations, for cash and each asset.
+async fn reserved_totals<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+) -> Result<Vec<ReservedTotal>, Status> {
+    let asset_ids = reconciliation::asset_ids(meta).await?;
+
+    let mut books: HashMap<u32, u128> = HashMap::new();
+    for &asset_id in &asset_ids {
+        for (order_id, record) in reconciliation::open_orders(orders, asset_id).await? {
+            let filled = record.original_size - record.size;
+            if let Some(transfer) = record.reservation(order_id.0).reserve(filled) {
+                *books.entry(transfer.ledger()).or_default() += transfer.amount();
+            }
+        }
+    }
+
+    // every reservation is made from one of the admin account's cash or asset accounts
+    let admin_accounts = std::iter::once(ADMIN_ACCOUNT_ID.as_u128())
+        .chain(
+            asset_ids
+                .iter()
+                .map(|&asset_id| asset_account_id(ADMIN_ACCOUNT_ID, asset_id)),
+        )
+        .collect_vec();
+    let mut ledger: HashMap<u32, u128> = HashMap::new();
+    for chunk in admin_accounts.chunks(LOOKUP_BATCH_SIZE) {
+        let accounts = accounting
+            .lookup_accounts(chunk.to_vec())
+            .await
+            .map_err(|e| {
+                error!("error fetching admin accounts from tigerbeetle: {e}");
+                Status::InternalServerError
+            })?;
+        ledger.extend(
+            accounts
+                .iter()
+                .map(|account| (account.ledger(), account.debits_pending())),
+        );
+    }
+
+    Ok(books
+        .keys()
+        .chain(ledger.keys())
+        .copied()
+        .unique()
+        .sorted()
+        .map(|id| ReservedTotal {
+            asset_id: (id != CASH_LEDGER).then_some(id as i32),
+            books: books.get(&id).copied().unwrap_or_default(),
+            ledger: ledger.get(&id).copied().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// # Rebuild Order Books
+///
+/// Restore the open orders recorded from the order event stream to every asset whose books and
+/// trigger books are empty, as after Redis loses its data, then compare what the books call for
+/// with the reservations held in the ledger. Assets whose books aren't empty are left alone.
+/// Trading should be halted while books are rebuilt.
+#[openapi(tag = "Assets")]
+#[post("/books/rebuild")]
+pub async fn rebuild_order_books(
+    _check: AdminCheck,
+    mut meta: Connection<Meta>,
+    mut orders: Connection<Orders>,
+    accounting: Connection<Accounting>,
+) -> Result<Json<Recovery>, Status> {
+    rebuild_books(&mut meta, orders.as_mut(), &accounting)
+        .await
+        .map(Json)
+}
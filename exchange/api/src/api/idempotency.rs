@@ -0,0 +1,242 @@
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    serde::json,
+    Request,
+};
+use rocket_db_pools::deadpool_redis::redis;
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::openapi3::{Parameter, ParameterValue},
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::error;
+
+use super::keys;
+
+/// Name of the header carrying an idempotency key.
+const HEADER: &str = "Idempotency-Key";
+
+/// The longest idempotency key accepted.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// How long responses are kept for replay, in seconds.
+const RESPONSE_TTL: u64 = 24 * 60 * 60;
+
+/// An optional `Idempotency-Key` header, chosen by the client so that retrying a request doesn't
+/// repeat it.
+///
+/// Requests with the same key, account and route have what they create derived from the key, and
+/// get the response to the first of them. Keys are kept for a day.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKey(Option<String>);
+
+/// What is kept under an idempotency key: a fingerprint of the request that claimed it, and its
+/// response once it has one.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Stored<T> {
+    Pending { request: uuid::Uuid },
+    Done { request: uuid::Uuid, response: T },
+}
+
+impl IdempotencyKey {
+    /// ID of what a request creates: derived from the key if there is one, so that retries create
+    /// the same thing with the same ledger transfers, or new otherwise.
+    pub fn id(&self, account_id: uuid::Uuid, scope: &str) -> uuid::Uuid {
+        match &self.0 {
+            Some(key) => uuid::Uuid::new_v5(&account_id, format!("{scope}:{key}").as_bytes()),
+            None => uuid::Uuid::now_v7(),
+        }
+    }
+
+    /// ID of the `index`th of several things a request creates, derived from the key and the
+    /// index if there is a key, or new otherwise.
+    pub fn item_id(&self, account_id: uuid::Uuid, scope: &str, index: usize) -> uuid::Uuid {
+        match &self.0 {
+            Some(key) => {
+                uuid::Uuid::new_v5(&account_id, format!("{scope}:{key}:{index}").as_bytes())
+            }
+            None => uuid::Uuid::now_v7(),
+        }
+    }
+
+    /// Claim the key for a request, or return the response to the request that claimed it first.
+    ///
+    /// Fails with [`Status::Conflict`] while the first request is still in flight, and with
+    /// [`Status::UnprocessableEntity`] if the key was used for a different request.
+    pub async fn claim<C: redis::aio::ConnectionLike, T: DeserializeOwned>(
+        &self,
+        orders: &mut C,
+        account_id: uuid::Uuid,
+        scope: &str,
+        request: &impl Serialize,
+    ) -> Result<Option<T>, Status> {
+        let Some(key) = &self.0 else {
+            return Ok(None);
+        };
+        let key = keys::idempotency(account_id, scope, key);
+        let fingerprint = fingerprint(account_id, request)?;
+
+        let pending = encode(&Stored::<()>::Pending {
+            request: fingerprint,
+        })?;
+        let claimed: bool = redis::cmd("SET")
+            .arg(&key)
+            .arg(pending)
+            .arg("NX")
+            .arg("EX")
+            .arg(RESPONSE_TTL)
+            .query_async(orders)
+            .await
+            .map_err(|e| {
+                error!("error claiming idempotency key: {e}");
+                Status::InternalServerError
+            })?;
+        if claimed {
+            return Ok(None);
+        }
+
+        let stored: Option<String> = redis::cmd("GET")
+            .arg(&key)
+            .query_async(orders)
+            .await
+            .map_err(|e| {
+                error!("error fetching idempotent response: {e}");
+                Status::InternalServerError
+            })?;
+        // expired in the meantime, so as good as in flight
+        let Some(stored) = stored else {
+            return Err(Status::Conflict);
+        };
+        match json::from_str(&stored).map_err(|e| {
+            error!("error decoding idempotent response: {e}");
+            Status::InternalServerError
+        })? {
+            Stored::Pending { request } | Stored::Done { request, .. }
+                if request != fingerprint =>
+            {
+                Err(Status::UnprocessableEntity)
+            }
+            Stored::Pending { .. } => Err(Status::Conflict),
+            Stored::Done { response, .. } => Ok(Some(response)),
+        }
+    }
+
+    /// Keep the response to a request that claimed the key, for replay to its retries.
+    pub async fn store<C: redis::aio::ConnectionLike>(
+        &self,
+        orders: &mut C,
+        account_id: uuid::Uuid,
+        scope: &str,
+        request: &impl Serialize,
+        response: &impl Serialize,
+    ) {
+        let Some(key) = &self.0 else {
+            return;
+        };
+
+        let stored = match fingerprint(account_id, request)
+            .and_then(|request| encode(&Stored::Done { request, response }))
+        {
+            Ok(stored) => stored,
+            Err(_) => return,
+        };
+        if let Err(e) = redis::cmd("SET")
+            .arg(keys::idempotency(account_id, scope, key))
+            .arg(stored)
+            .arg("XX")
+            .arg("EX")
+            .arg(RESPONSE_TTL)
+            .query_async::<_, ()>(orders)
+            .await
+        {
+            error!("error storing idempotent response: {e}");
+        }
+    }
+
+    /// Give up the key after a request failed without a response worth replaying, so that it can
+    /// be retried.
+    pub async fn release<C: redis::aio::ConnectionLike>(
+        &self,
+        orders: &mut C,
+        account_id: uuid::Uuid,
+        scope: &str,
+    ) {
+        let Some(key) = &self.0 else {
+            return;
+        };
+
+        if let Err(e) = redis::cmd("DEL")
+            .arg(keys::idempotency(account_id, scope, key))
+            .query_async::<_, ()>(orders)
+            .await
+        {
+            error!("error releasing idempotency key: {e}");
+        }
+    }
+}
+
+/// Fingerprint of a request, to tell retries from other requests reusing a key.
+fn fingerprint(account_id: uuid::Uuid, request: &impl Serialize) -> Result<uuid::Uuid, Status> {
+    json::to_string(request)
+        .map(|request| uuid::Uuid::new_v5(&account_id, request.as_bytes()))
+        .map_err(|e| {
+            error!("error encoding idempotent request: {e}");
+            Status::InternalServerError
+        })
+}
+
+/// Encode what is kept under an idempotency key.
+fn encode<T: Serialize>(stored: &Stored<T>) -> Result<String, Status> {
+    json::to_string(stored).map_err(|e| {
+        error!("error encoding idempotent response: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one(HEADER) {
+            None => Outcome::Success(Self(None)),
+            Some(key) if key.is_empty() || key.len() > MAX_KEY_LENGTH => {
+                Outcome::Error((Status::BadRequest, ()))
+            }
+            Some(key) => Outcome::Success(Self(Some(key.to_owned()))),
+        }
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for IdempotencyKey {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: HEADER.to_owned(),
+            location: "header".to_owned(),
+            description: Some(
+                "Key chosen by the client, up to 255 characters, under which retries of the \
+                 request get the first response instead of being carried out again."
+                    .to_owned(),
+            ),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: gen.json_schema::<String>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        }))
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDate, Utc};
+use diesel::{OptionalExtension, QueryResult};
+use rocket_db_pools::diesel::{
+    prelude::{QueryDsl, RunQueryDsl},
+    AsyncPgConnection,
+};
+
+use super::{assets::AssetStatus, rejection::Rejection, types::Price};
+
+/// How long trading rules are served from the cache before being read again.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The trading rules for an asset.
+#[derive(Debug, Clone, Copy)]
+pub struct Instrument {
+    pub asset_id: i32,
+    pub tick_size: Price,
+    pub lot_size: i32,
+    /// Last day an option can be traded. Equities don't expire.
+    pub expiration_date: Option<NaiveDate>,
+    pub status: AssetStatus,
+}
+
+impl Instrument {
+    /// Check an order for `size` units at `price` (or at market) against the asset's rules.
+    pub fn check(&self, size: u32, price: Option<Price>) -> Result<(), Rejection> {
+        if self
+            .expiration_date
+            .is_some_and(|expiration_date| expiration_date < Utc::now().date_naive())
+        {
+            return Err(Rejection::NotTradable {
+                asset_id: self.asset_id,
+            });
+        }
+        if self.status != AssetStatus::Active {
+            return Err(Rejection::Unavailable {
+                asset_id: self.asset_id,
+                status: self.status,
+            });
+        }
+
+        if size == 0 || size % self.lot_size as u32 != 0 {
+            return Err(Rejection::InvalidSize {
+                size,
+                lot_size: self.lot_size,
+            });
+        }
+
+        match price {
+            Some(price) if !price.is_positive() || !price.is_multiple_of(self.tick_size) => {
+                Err(Rejection::InvalidPrice {
+                    price,
+                    tick_size: self.tick_size,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Cache of the trading rules of equities and equity options, kept in managed state.
+#[derive(Debug, Default)]
+pub struct Instruments {
+    cache: RwLock<HashMap<i32, (Instant, Instrument)>>,
+}
+
+impl Instruments {
+    /// Look up the trading rules for an asset, or [`Rejection::UnknownAsset`] if there is no
+    /// equity or equity option with this ID.
+    pub async fn get(
+        &self,
+        meta: &mut AsyncPgConnection,
+        asset_id: i32,
+    ) -> QueryResult<Result<Instrument, Rejection>> {
+        if let Some((fetched, instrument)) = self.cache.read().unwrap().get(&asset_id) {
+            if fetched.elapsed() < CACHE_TTL {
+                return Ok(Ok(*instrument));
+            }
+        }
+
+        let Some(instrument) = fetch(meta, asset_id).await? else {
+            // unknown assets aren't cached, so that newly created assets can be traded at once
+            return Ok(Err(Rejection::UnknownAsset { asset_id }));
+        };
+
+        self.cache
+            .write()
+            .unwrap()
+            .insert(asset_id, (Instant::now(), instrument));
+        Ok(Ok(instrument))
+    }
+
+    /// Drop an asset's cached trading rules, so that changes to them apply at once.
+    pub fn invalidate(&self, asset_id: i32) {
+        self.cache.write().unwrap().remove(&asset_id);
+    }
+}
+
+async fn fetch(meta: &mut AsyncPgConnection, asset_id: i32) -> QueryResult<Option<Instrument>> {
+    use super::schema::{equities, equity_options};
+
+    let equity: Option<(Price, i32, AssetStatus)> = equities::table
+        .find(asset_id)
+        .select((equities::tick_size, equities::lot_size, equities::status))
+        .first(meta)
+        .await
+        .optional()?;
+    if let Some((tick_size, lot_size, status)) = equity {
+        return Ok(Some(Instrument {
+            asset_id,
+            tick_size,
+            lot_size,
+            expiration_date: None,
+            status,
+        }));
+    }
+
+    let option: Option<(NaiveDate, Price, i32, AssetStatus)> = equity_options::table
+        .find(asset_id)
+        .select((
+            equity_options::expiration_date,
+            equity_options::tick_size,
+            equity_options::lot_size,
+            equity_options::status,
+        ))
+        .first(meta)
+        .await
+        .optional()?;
+    Ok(option.map(
+        |(expiration_date, tick_size, lot_size, status)| Instrument {
+            asset_id,
+            tick_size,
+            lot_size,
+            expiration_date: Some(expiration_date),
+            status,
+        },
+    ))
+}
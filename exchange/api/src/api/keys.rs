@@ -0,0 +1,48 @@
+use std::fmt::Display;
+
+/// Key of one of an asset's books, trigger books or other structures, e.g. `{7}:bids`.
+///
+/// Every key the order scripts touch for an asset, its order hashes included, carries the asset's
+/// hash tag, so that each script's keys fall in a single Redis Cluster slot. Account indexes span
+/// assets, so they are kept up to date from the asset event streams instead.
+///
+/// This only lays the keys out for a cluster. The orders pool still connects to a single node,
+/// and the scripts build the keys of the order hashes they match against from the asset's order
+/// prefix rather than being passed them, so running on Redis Cluster would also take a
+/// cluster-aware client and scripts that declare every key they touch.
+pub fn asset(asset_id: i32, name: impl Display) -> String {
+    format!("{{{asset_id}}}:{name}")
+}
+
+/// Prefix of the keys of an asset's order hashes, which end with the order's ID as raw bytes.
+pub fn order_prefix(asset_id: i32) -> String {
+    asset(asset_id, "order:")
+}
+
+/// Key of the hash holding an order on one of the asset's books or trigger books.
+pub fn order(asset_id: i32, order_id: uuid::Uuid) -> Vec<u8> {
+    let mut key = order_prefix(asset_id).into_bytes();
+    key.extend_from_slice(order_id.as_bytes());
+    key
+}
+
+/// Key of what is kept under an account's idempotency key for requests to one kind of route.
+pub fn idempotency(account_id: uuid::Uuid, scope: &str, key: &str) -> Vec<u8> {
+    let mut idempotency_key = b"idempotency:".to_vec();
+    idempotency_key.extend_from_slice(account_id.as_bytes());
+    idempotency_key.extend_from_slice(format!(":{scope}:{key}").as_bytes());
+    idempotency_key
+}
+
+/// Key of the sorted set of an account's open orders, each scored by its asset's ID.
+pub fn account_orders(account_id: uuid::Uuid) -> Vec<u8> {
+    let mut key = b"account:".to_vec();
+    key.extend_from_slice(account_id.as_bytes());
+    key
+}
+
+/// Key of the lock held by whichever worker process is running a job, so that only one runs it
+/// at a time.
+pub fn job_lock(job: &str) -> String {
+    format!("lock:{job}")
+}
@@ -0,0 +1,922 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::{prelude::Insertable, BoolExpressionMethods, ExpressionMethods};
+use itertools::Itertools;
+use rocket::{
+    get,
+    http::Status,
+    serde::json::{self, Json},
+};
+use rocket_db_pools::{
+    deadpool_redis::redis,
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        AsyncPgConnection,
+    },
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tigerbeetle_unofficial::{
+    self as tb,
+    error::{
+        CreateAccountErrorKind, CreateAccountsError, CreateTransferErrorKind, CreateTransfersError,
+    },
+};
+use tracing::{error, warn};
+
+use super::{
+    accounts::{asset_account_id, Book, UserIdCheck},
+    schema::transfers::dsl,
+    types::{Price, Timestamp},
+    ADMIN_ACCOUNT_ID,
+};
+use crate::{Accounting, Meta};
+
+/// The TigerBeetle ledger holding cash. Every other ledger holds units of the asset with the same ID.
+pub const CASH_LEDGER: u32 = u32::MAX;
+
+/// The reason for a transfer, stored in its TigerBeetle `code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[repr(u16)]
+pub enum TransferCode {
+    /// Cash paid into an account by an admin.
+    Deposit = 1,
+    /// Cash paid out of an account by an admin.
+    Withdrawal = 2,
+    /// Cash or assets held back for an open order.
+    OrderReservation = 3,
+    /// Cash or assets exchanged between the counterparties of a trade.
+    TradeSettlement = 4,
+    /// Fees charged by the exchange.
+    Fee = 5,
+    /// Release of a reservation.
+    ///
+    /// TigerBeetle requires a voiding transfer to carry the code of the reservation it voids,
+    /// so statements recognize voids by their flags rather than by this code.
+    Void = 6,
+}
+
+impl TransferCode {
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(Self::Deposit),
+            2 => Some(Self::Withdrawal),
+            3 => Some(Self::OrderReservation),
+            4 => Some(Self::TradeSettlement),
+            5 => Some(Self::Fee),
+            6 => Some(Self::Void),
+            _ => None,
+        }
+    }
+
+    fn of(transfer: &tb::Transfer) -> Option<Self> {
+        if transfer
+            .flags()
+            .contains(tb::transfer::Flags::VOID_PENDING_TRANSFER)
+        {
+            Some(Self::Void)
+        } else {
+            Self::from_code(transfer.code())
+        }
+    }
+}
+
+impl From<TransferCode> for u16 {
+    fn from(code: TransferCode) -> Self {
+        code as u16
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LedgerError {
+    #[error("record transfers: {0}")]
+    Record(diesel::result::Error),
+    #[error("create transfers: {0}")]
+    Create(CreateTransfersError),
+}
+
+impl LedgerError {
+    /// Whether the ledger rejected the transfers, rather than couldn't be reached, so that
+    /// creating them again will fail the same way.
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, Self::Create(CreateTransfersError::Api(_)))
+    }
+
+    /// Whether the ledger refused the transfers because an account doesn't hold enough cash or
+    /// units to cover them.
+    pub fn is_insufficient(&self) -> bool {
+        match self {
+            Self::Create(CreateTransfersError::Api(errs)) => errs
+                .as_slice()
+                .iter()
+                .any(|err| exceeds_balance(err.kind())),
+            _ => false,
+        }
+    }
+}
+
+/// Whether a transfer failed because it would take an account past its balance. User accounts
+/// can't be credited past their debits, but either side is checked in case that changes.
+pub fn exceeds_balance(kind: CreateTransferErrorKind) -> bool {
+    matches!(
+        kind,
+        CreateTransferErrorKind::ExceedsCredits | CreateTransferErrorKind::ExceedsDebits
+    )
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::transfers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct TransferRecord {
+    id: uuid::Uuid,
+    debit_account_id: uuid::Uuid,
+    credit_account_id: uuid::Uuid,
+}
+
+impl From<&tb::Transfer> for TransferRecord {
+    fn from(transfer: &tb::Transfer) -> Self {
+        Self {
+            id: uuid::Uuid::from_u128(transfer.id()),
+            debit_account_id: uuid::Uuid::from_u128(transfer.debit_account_id()),
+            credit_account_id: uuid::Uuid::from_u128(transfer.credit_account_id()),
+        }
+    }
+}
+
+/// Look up transfers in TigerBeetle, in batches it can handle. Transfers that don't exist are
+/// left out.
+pub async fn lookup_transfers(
+    accounting: &tb::Client,
+    ids: Vec<u128>,
+) -> Result<Vec<tb::Transfer>, Status> {
+    let mut transfers = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(LOOKUP_BATCH_SIZE) {
+        transfers.extend(
+            accounting
+                .lookup_transfers(chunk.to_vec())
+                .await
+                .map_err(|e| {
+                    error!("error fetching transfers from tigerbeetle: {e}");
+                    Status::InternalServerError
+                })?,
+        );
+    }
+    Ok(transfers)
+}
+
+/// Create transfers in TigerBeetle, indexing them by account so they show up in statements.
+///
+/// Transfers are indexed before they are created, so an index entry may exist for a transfer
+/// that failed. Statements only show transfers that TigerBeetle knows about.
+pub async fn create_transfers(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    transfers: Vec<tb::Transfer>,
+) -> Result<(), LedgerError> {
+    diesel::insert_into(dsl::transfers)
+        .values(transfers.iter().map(TransferRecord::from).collect_vec())
+        .on_conflict_do_nothing()
+        .execute(meta)
+        .await
+        .map_err(LedgerError::Record)?;
+
+    accounting
+        .create_transfers(transfers)
+        .await
+        .map_err(LedgerError::Create)
+}
+
+/// Key of the stream of transfers that failed when first created, retried by the worker.
+pub const SETTLEMENTS_KEY: &str = "settlements";
+
+/// Key of the stream of settlements the ledger rejected when retried, set aside for
+/// investigation so that they don't hold up the settlements behind them.
+pub const DEAD_SETTLEMENTS_KEY: &str = "settlements:dead";
+
+/// A transfer waiting in the settlement stream.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QueuedTransfer {
+    id: u128,
+    debit_account_id: u128,
+    credit_account_id: u128,
+    amount: u128,
+    pending_id: u128,
+    ledger: u32,
+    code: u16,
+    flags: u16,
+    /// Queued before reservations had timeouts if missing.
+    #[serde(default)]
+    timeout: u32,
+}
+
+impl From<&tb::Transfer> for QueuedTransfer {
+    fn from(transfer: &tb::Transfer) -> Self {
+        Self {
+            id: transfer.id(),
+            debit_account_id: transfer.debit_account_id(),
+            credit_account_id: transfer.credit_account_id(),
+            amount: transfer.amount(),
+            pending_id: transfer.pending_id(),
+            ledger: transfer.ledger(),
+            code: transfer.code(),
+            flags: transfer.flags().bits(),
+            timeout: transfer.timeout(),
+        }
+    }
+}
+
+impl From<QueuedTransfer> for tb::Transfer {
+    fn from(transfer: QueuedTransfer) -> Self {
+        tb::Transfer::new(transfer.id)
+            .with_debit_account_id(transfer.debit_account_id)
+            .with_credit_account_id(transfer.credit_account_id)
+            .with_amount(transfer.amount)
+            .with_pending_id(transfer.pending_id)
+            .with_ledger(transfer.ledger)
+            .with_code(transfer.code)
+            .with_flags(tb::transfer::Flags::from_bits_retain(transfer.flags))
+            .with_timeout(transfer.timeout)
+    }
+}
+
+/// Create transfers for changes already made to the book, queueing them on the settlement stream
+/// for the worker to retry if that fails.
+pub async fn create_or_queue_transfers<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    transfers: Vec<tb::Transfer>,
+) -> Result<(), LedgerError> {
+    let queued = transfers.iter().map(QueuedTransfer::from).collect_vec();
+    let res = create_transfers(meta, accounting, transfers).await;
+    if res.is_err() {
+        if let Err(e) = queue_transfers(orders, &queued).await {
+            error!("error queueing transfers for settlement: {e}");
+        }
+    }
+    res
+}
+
+/// Add transfers to the settlement stream, to be created together.
+pub async fn queue_transfers<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    transfers: &[QueuedTransfer],
+) -> redis::RedisResult<()> {
+    let transfers = json::to_string(&transfers).map_err(|e| {
+        redis::RedisError::from((
+            redis::ErrorKind::TypeError,
+            "unserializable transfers",
+            e.to_string(),
+        ))
+    })?;
+
+    redis::cmd("XADD")
+        .arg(SETTLEMENTS_KEY)
+        .arg("*")
+        .arg("transfers")
+        .arg(transfers)
+        .query_async(orders)
+        .await
+}
+
+/// Set aside a settlement the ledger rejected, with the ID it had in the settlement stream and
+/// the reason it was rejected.
+pub async fn dead_letter<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    id: &str,
+    transfers: &str,
+    reason: &str,
+) -> redis::RedisResult<()> {
+    redis::cmd("XADD")
+        .arg(DEAD_SETTLEMENTS_KEY)
+        .arg("*")
+        .arg("settlement")
+        .arg(id)
+        .arg("transfers")
+        .arg(transfers)
+        .arg("reason")
+        .arg(reason)
+        .query_async(orders)
+        .await
+}
+
+/// Create transfers taken off the settlement stream.
+///
+/// Transfer IDs are deterministic, so transfers created by an earlier attempt are skipped rather
+/// than failing the retry. Since linked transfers are created all together or not at all, the
+/// rest of their chain fails along with them.
+///
+/// Reservations may have timed out while their transfers were queued, releasing what they held,
+/// so voiding them fails. Chains of nothing but voids are then done with. Chains that also settle
+/// trades are created again without the dead voids and the reservations that would have replaced
+/// them, so that the trades are still settled, or fail for good if they no longer can be.
+pub async fn retry_transfers(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    transfers: Vec<QueuedTransfer>,
+) -> Result<(), LedgerError> {
+    let mut transfers = transfers.into_iter().map(tb::Transfer::from).collect_vec();
+    loop {
+        let errs = match create_transfers(meta, accounting, transfers.clone()).await {
+            Err(LedgerError::Create(CreateTransfersError::Api(errs))) => errs,
+            res => return res,
+        };
+        let skippable = errs.as_slice().iter().all(|err| {
+            matches!(
+                err.kind(),
+                CreateTransferErrorKind::Exists
+                    | CreateTransferErrorKind::LinkedEventFailed
+                    | CreateTransferErrorKind::PendingTransferExpired
+            )
+        });
+        if !skippable {
+            return Err(LedgerError::Create(CreateTransfersError::Api(errs)));
+        }
+
+        let expired: HashSet<usize> = errs
+            .as_slice()
+            .iter()
+            .filter(|err| matches!(err.kind(), CreateTransferErrorKind::PendingTransferExpired))
+            .map(|err| err.index() as usize)
+            .collect();
+        match without_expired_voids(&transfers, &expired) {
+            Some(rebuilt) => transfers = rebuilt,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// The transfers with the chains holding voids at `expired` rebuilt without those voids and the
+/// reservations replacing them, or `None` if no chain needs rebuilding. Chains of nothing but
+/// voids are left out, as there is nothing left for them to do.
+fn without_expired_voids(
+    transfers: &[tb::Transfer],
+    expired: &HashSet<usize>,
+) -> Option<Vec<tb::Transfer>> {
+    let is_void = |transfer: &tb::Transfer| {
+        transfer
+            .flags()
+            .contains(tb::transfer::Flags::VOID_PENDING_TRANSFER)
+    };
+    let accounts = |transfer: &tb::Transfer| {
+        (
+            transfer.ledger(),
+            transfer.debit_account_id(),
+            transfer.credit_account_id(),
+        )
+    };
+
+    let mut rebuilt = Vec::with_capacity(transfers.len());
+    let mut changed = false;
+    let mut start = 0;
+    for (end, transfer) in transfers.iter().enumerate() {
+        if transfer.flags().contains(tb::transfer::Flags::LINKED) && end + 1 < transfers.len() {
+            continue;
+        }
+        let chain = &transfers[start..=end];
+        let dead = (start..=end)
+            .filter(|i| expired.contains(i))
+            .map(|i| accounts(&transfers[i]))
+            .collect_vec();
+        start = end + 1;
+
+        if dead.is_empty() {
+            rebuilt.extend_from_slice(chain);
+        } else if chain.iter().all(is_void) {
+            changed = true;
+        } else {
+            changed = true;
+            let rest = chain
+                .iter()
+                .filter(|transfer| {
+                    let replaces_dead = is_void(transfer)
+                        || transfer.flags().contains(tb::transfer::Flags::PENDING);
+                    !(replaces_dead && dead.contains(&accounts(transfer)))
+                })
+                .map(|transfer| {
+                    let flags = transfer.flags() - tb::transfer::Flags::LINKED;
+                    transfer.with_flags(flags)
+                })
+                .collect_vec();
+            rebuilt.extend(link(rest));
+        }
+    }
+
+    changed.then_some(rebuilt)
+}
+
+/// Create transfers whose IDs are derived from an idempotency key, skipping those already created
+/// by an earlier attempt at the same request. Reservations made again later in the day time out
+/// a little sooner, which is also taken for the same transfer.
+pub async fn create_transfers_once(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    transfers: Vec<tb::Transfer>,
+) -> Result<(), LedgerError> {
+    match create_transfers(meta, accounting, transfers).await {
+        Err(LedgerError::Create(CreateTransfersError::Api(errs)))
+            if errs.as_slice().iter().all(|err| {
+                matches!(
+                    err.kind(),
+                    CreateTransferErrorKind::Exists
+                        | CreateTransferErrorKind::ExistsWithDifferentTimeout
+                )
+            }) =>
+        {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Create transfers voiding reservations. Reservations that have already timed out are skipped,
+/// since the ledger released what they held when they did.
+pub async fn void_reservations(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    voids: Vec<tb::Transfer>,
+) -> Result<(), LedgerError> {
+    match create_transfers(meta, accounting, voids).await {
+        Err(LedgerError::Create(CreateTransfersError::Api(errs)))
+            if errs.as_slice().iter().all(|err| {
+                matches!(err.kind(), CreateTransferErrorKind::PendingTransferExpired)
+            }) =>
+        {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// The most accounts TigerBeetle will look up or create in one request.
+pub const LOOKUP_BATCH_SIZE: usize = 8190;
+
+/// Create accounts in TigerBeetle, ignoring any that already exist.
+pub async fn create_accounts(
+    accounting: &tb::Client,
+    accounts: Vec<tb::Account>,
+) -> Result<(), CreateAccountsError> {
+    match accounting.create_accounts(accounts).await {
+        Err(CreateAccountsError::Api(errs))
+            if errs
+                .as_slice()
+                .iter()
+                .all(|err| matches!(err.kind(), CreateAccountErrorKind::Exists)) =>
+        {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// How long after an order expires its reservation times out, releasing what it holds. The book
+/// stops matching an order once it expires, so this only has to cover the clocks of the API and
+/// the ledger disagreeing.
+const EXPIRY_GRACE: Duration = Duration::from_secs(60);
+
+/// Furthest ahead an order can expire, which bounds how long its reservation is held.
+pub const MAX_EXPIRY_DAYS: i64 = 366;
+
+/// Funds or assets held back for an order, as a TigerBeetle pending transfer.
+///
+/// A pending transfer can only be posted or voided once, so each fill voids the order's
+/// reservation and replaces it with one covering the remaining size.
+///
+/// Reservations for orders that expire time out shortly after them, so that their funds/assets
+/// are released even if the order is lost before it can be removed from the book.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub order_id: uuid::Uuid,
+    pub account_id: uuid::Uuid,
+    pub asset_id: i32,
+    pub book: Book,
+    /// Limit price of the order, or for market bids the highest price they may trade at. Bids
+    /// without a price, such as stop buys yet to trigger, don't reserve funds.
+    pub price: Option<Price>,
+    /// Original size of the order.
+    pub size: u32,
+    /// When the order expires, for good 'til date and day orders.
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Reservation {
+    /// ID of the pending transfer holding the reservation once `filled` units have traded.
+    pub fn transfer_id(&self, filled: u32) -> u128 {
+        if filled == 0 {
+            self.order_id.as_u128()
+        } else {
+            uuid::Uuid::new_v5(&self.order_id, &filled.to_be_bytes()).as_u128()
+        }
+    }
+
+    fn amount(&self, filled: u32) -> Option<u128> {
+        let remaining = self.size.saturating_sub(filled);
+        match self.book {
+            Book::Bids => self.price.and_then(|price| (price * remaining).to_ledger()),
+            Book::Offers => Some(remaining as u128),
+        }
+        .filter(|&amount| amount > 0)
+    }
+
+    fn transfer(&self, id: u128, amount: u128) -> tb::Transfer {
+        let transfer = tb::Transfer::new(id)
+            .with_code(TransferCode::OrderReservation.into())
+            .with_amount(amount);
+
+        match self.book {
+            Book::Bids => transfer
+                .with_ledger(CASH_LEDGER)
+                .with_debit_account_id(ADMIN_ACCOUNT_ID.as_u128())
+                .with_credit_account_id(self.account_id.as_u128()),
+            Book::Offers => transfer
+                .with_ledger(self.asset_id as u32)
+                .with_debit_account_id(asset_account_id(ADMIN_ACCOUNT_ID, self.asset_id))
+                .with_credit_account_id(asset_account_id(self.account_id, self.asset_id)),
+        }
+    }
+
+    /// Seconds from now until the reservation should time out, or zero if it shouldn't.
+    fn timeout(&self) -> u32 {
+        self.expires.map_or(0, |expires| {
+            let remaining = (expires - Utc::now()).num_seconds().max(0) as u64;
+            (remaining + EXPIRY_GRACE.as_secs()).min(u32::MAX as u64) as u32
+        })
+    }
+
+    /// Pending transfer reserving what the order needs once `filled` units have traded.
+    pub fn reserve(&self, filled: u32) -> Option<tb::Transfer> {
+        self.amount(filled).map(|amount| {
+            self.transfer(self.transfer_id(filled), amount)
+                .with_flags(tb::transfer::Flags::PENDING)
+                .with_timeout(self.timeout())
+        })
+    }
+
+    /// Transfer voiding the reservation held once `filled` units have traded.
+    pub fn release(&self, filled: u32) -> Option<tb::Transfer> {
+        self.amount(filled).map(|amount| {
+            let pending_id = self.transfer_id(filled);
+            self.transfer(void_id(pending_id), amount)
+                .with_pending_id(pending_id)
+                .with_flags(tb::transfer::Flags::VOID_PENDING_TRANSFER)
+        })
+    }
+}
+
+/// Whether a pending transfer has timed out by `now`, in nanoseconds since the epoch, releasing
+/// what it held.
+pub fn timed_out(transfer: &tb::Transfer, now: u64) -> bool {
+    transfer.timeout() > 0 && expiry_ns(transfer) <= now
+}
+
+/// When a pending transfer times out, in nanoseconds since the epoch.
+fn expiry_ns(transfer: &tb::Transfer) -> u64 {
+    timestamp_ns(transfer) + transfer.timeout() as u64 * 1_000_000_000
+}
+
+/// When TigerBeetle committed a transfer, in nanoseconds since the epoch.
+pub fn timestamp_ns(transfer: &tb::Transfer) -> u64 {
+    transfer
+        .timestamp()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// ID of the transfer voiding the pending transfer `pending_id`.
+pub fn void_id(pending_id: u128) -> u128 {
+    uuid::Uuid::new_v5(&uuid::Uuid::from_u128(pending_id), b"void").as_u128()
+}
+
+/// Linked transfers reducing an order's reservation by `size` units that won't trade, given the
+/// size it had filled before.
+pub fn decrement(reservation: &Reservation, filled: u32, size: u32) -> Vec<tb::Transfer> {
+    let mut chain = Vec::new();
+    chain.extend(reservation.release(filled));
+    chain.extend(reservation.reserve(filled + size));
+    link(chain)
+}
+
+/// Linked transfers settling a trade of `size` units at `price` between a buy and a sell
+/// order, each given with the size it had filled before the trade.
+pub fn settle(
+    fill_id: uuid::Uuid,
+    (buy, buy_filled): (&Reservation, u32),
+    (sell, sell_filled): (&Reservation, u32),
+    price: Price,
+    size: u32,
+) -> Vec<tb::Transfer> {
+    let mut chain = Vec::new();
+    for (reservation, filled) in [(buy, buy_filled), (sell, sell_filled)] {
+        chain.extend(reservation.release(filled));
+        chain.extend(reservation.reserve(filled + size));
+    }
+
+    chain.push(
+        tb::Transfer::new(uuid::Uuid::new_v5(&fill_id, b"cash").as_u128())
+            .with_code(TransferCode::TradeSettlement.into())
+            .with_amount(
+                (price * size)
+                    .to_ledger()
+                    .expect("orders are only accepted at positive prices"),
+            )
+            .with_ledger(CASH_LEDGER)
+            .with_debit_account_id(sell.account_id.as_u128())
+            .with_credit_account_id(buy.account_id.as_u128()),
+    );
+    chain.push(
+        tb::Transfer::new(uuid::Uuid::new_v5(&fill_id, b"asset").as_u128())
+            .with_code(TransferCode::TradeSettlement.into())
+            .with_amount(size as u128)
+            .with_ledger(buy.asset_id as u32)
+            .with_debit_account_id(asset_account_id(buy.account_id, buy.asset_id))
+            .with_credit_account_id(asset_account_id(sell.account_id, sell.asset_id)),
+    );
+
+    link(chain)
+}
+
+/// Link a chain of transfers so that they succeed or fail together.
+fn link(chain: Vec<tb::Transfer>) -> Vec<tb::Transfer> {
+    let last = chain.len().saturating_sub(1);
+    chain
+        .into_iter()
+        .enumerate()
+        .map(|(i, transfer)| {
+            if i < last {
+                let flags = transfer.flags() | tb::transfer::Flags::LINKED;
+                transfer.with_flags(flags)
+            } else {
+                transfer
+            }
+        })
+        .collect()
+}
+
+/// Change in an account's available balance (debits posted less credits posted and pending)
+/// caused by a transfer.
+fn available_change(account_id: u128, transfer: &tb::Transfer) -> i128 {
+    let amount = transfer.amount() as i128;
+    let is_debit = transfer.debit_account_id() == account_id;
+    let flags = transfer.flags();
+
+    if flags.contains(tb::transfer::Flags::VOID_PENDING_TRANSFER) {
+        if is_debit {
+            0
+        } else {
+            amount
+        }
+    } else if flags.contains(tb::transfer::Flags::POST_PENDING_TRANSFER) {
+        if is_debit {
+            amount
+        } else {
+            0
+        }
+    } else if flags.contains(tb::transfer::Flags::PENDING) {
+        if is_debit {
+            0
+        } else {
+            -amount
+        }
+    } else if is_debit {
+        amount
+    } else {
+        -amount
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct LedgerEntry {
+    /// ID of the TigerBeetle transfer behind this entry.
+    pub id: uuid::Uuid,
+    pub kind: TransferCode,
+    /// Cash or units of the asset moved by the transfer.
+    pub amount: u128,
+    /// Whether the transfer reserved its amount rather than moving it.
+    pub pending: bool,
+    /// Whether this entry is a pending transfer timing out, releasing what it reserved.
+    pub expired: bool,
+    /// Change in the available balance caused by this entry.
+    pub change: i128,
+    /// Available balance after this entry.
+    pub balance: i128,
+    /// Date & time the transfer was committed, in RFC 3339 format.
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Statement {
+    /// Available balance at the start of the statement.
+    pub opening_balance: i128,
+    /// Available balance at the end of the statement.
+    pub closing_balance: i128,
+    pub entries: Vec<LedgerEntry>,
+}
+
+/// Longest period a statement can cover.
+const MAX_STATEMENT_DAYS: i64 = 31;
+
+/// # Get Statement
+///
+/// List the transfers that changed an account's available cash, or its available units of
+/// an asset if `asset_id` is given, between `from` and `to`, along with reservations that timed
+/// out and released what they held. Statements cover at most 31 days, and default to the 31 days
+/// up to `to`.
+#[openapi(tag = "Accounts")]
+#[get("/accounts/<account_id>/statement?<asset_id>&<from>&<to>")]
+pub async fn get_statement_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    asset_id: Option<i32>,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    mut meta: Connection<Meta>,
+    accounting: Connection<Accounting>,
+) -> Result<Json<Statement>, Status> {
+    let tb_id = match asset_id {
+        Some(asset_id) => asset_account_id(account_id, asset_id),
+        None => account_id.as_u128(),
+    };
+    let to = to.map(|to| to.0).unwrap_or_else(Utc::now);
+    let from = from
+        .map(|from| from.0)
+        .unwrap_or(to - chrono::Duration::days(MAX_STATEMENT_DAYS));
+    if from > to || to - from > chrono::Duration::days(MAX_STATEMENT_DAYS) {
+        return Err(Status::BadRequest);
+    }
+
+    // Every transfer since `from` is needed to unwind the current balance to the opening balance.
+    let ids: Vec<uuid::Uuid> = dsl::transfers
+        .filter(
+            dsl::debit_account_id
+                .eq(uuid::Uuid::from_u128(tb_id))
+                .or(dsl::credit_account_id.eq(uuid::Uuid::from_u128(tb_id))),
+        )
+        .filter(dsl::created.ge(from))
+        .select(dsl::id)
+        .load(&mut meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching transfer index: {e}");
+            Status::InternalServerError
+        })?;
+
+    let account = accounting
+        .lookup_accounts(vec![tb_id])
+        .await
+        .map_err(|e| {
+            error!("error fetching account from tigerbeetle: {e}");
+            Status::InternalServerError
+        })?
+        .into_iter()
+        .next()
+        .ok_or(Status::NotFound)?;
+
+    let mut transfers = lookup_transfers(
+        &accounting,
+        ids.into_iter().map(|id| id.as_u128()).collect(),
+    )
+    .await?;
+
+    let from_ns = from.timestamp_nanos_opt().unwrap_or_default() as u64;
+    let to_ns = to.timestamp_nanos_opt().unwrap_or(i64::MAX) as u64;
+    transfers.retain(|transfer| timestamp_ns(transfer) >= from_ns);
+
+    // Pending transfers that timed out before being posted or voided released what they held
+    // without a transfer of their own, so their release is listed when it happened.
+    let now_ns = Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64;
+    let resolved: HashSet<u128> = transfers
+        .iter()
+        .filter(|transfer| {
+            transfer.flags().intersects(
+                tb::transfer::Flags::POST_PENDING_TRANSFER
+                    | tb::transfer::Flags::VOID_PENDING_TRANSFER,
+            )
+        })
+        .map(|transfer| transfer.pending_id())
+        .collect();
+
+    // So did reservations from before `from` that timed out since. Reservations are credited to
+    // the account, and voids of them have IDs derived from theirs.
+    let horizon = from
+        - chrono::Duration::days(MAX_EXPIRY_DAYS)
+        - chrono::Duration::from_std(EXPIRY_GRACE).unwrap_or_default();
+    let earlier_ids: Vec<uuid::Uuid> = dsl::transfers
+        .filter(dsl::credit_account_id.eq(uuid::Uuid::from_u128(tb_id)))
+        .filter(dsl::created.lt(from))
+        .filter(dsl::created.ge(horizon))
+        .select(dsl::id)
+        .load(&mut meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching transfer index: {e}");
+            Status::InternalServerError
+        })?;
+    let earlier = lookup_transfers(
+        &accounting,
+        earlier_ids.into_iter().map(|id| id.as_u128()).collect(),
+    )
+    .await?
+    .into_iter()
+    .filter(|transfer| {
+        transfer.flags().contains(tb::transfer::Flags::PENDING)
+            && timed_out(transfer, now_ns)
+            && expiry_ns(transfer) >= from_ns
+    })
+    .collect_vec();
+    let voided: HashSet<u128> = lookup_transfers(
+        &accounting,
+        earlier
+            .iter()
+            .map(|transfer| void_id(transfer.id()))
+            .collect(),
+    )
+    .await?
+    .iter()
+    .map(tb::Transfer::pending_id)
+    .collect();
+    let earlier = earlier
+        .into_iter()
+        .filter(|transfer| !voided.contains(&transfer.id()))
+        .collect_vec();
+
+    let mut changes = transfers
+        .iter()
+        .map(|transfer| (timestamp_ns(transfer), transfer, false))
+        .chain(
+            transfers
+                .iter()
+                .filter(|transfer| {
+                    transfer.flags().contains(tb::transfer::Flags::PENDING)
+                        && timed_out(transfer, now_ns)
+                        && !resolved.contains(&transfer.id())
+                })
+                .map(|transfer| (expiry_ns(transfer), transfer, true)),
+        )
+        .chain(
+            earlier
+                .iter()
+                .map(|transfer| (expiry_ns(transfer), transfer, true)),
+        )
+        .collect_vec();
+    changes.sort_by_key(|&(timestamp, _, _)| timestamp);
+    let change = |transfer: &tb::Transfer, expired: bool| {
+        let change = available_change(tb_id, transfer);
+        if expired {
+            -change
+        } else {
+            change
+        }
+    };
+
+    let current_balance = account.debits_posted() as i128
+        - account.credits_posted() as i128
+        - account.credits_pending() as i128;
+    let opening_balance = current_balance
+        - changes
+            .iter()
+            .map(|&(_, transfer, expired)| change(transfer, expired))
+            .sum::<i128>();
+
+    let mut balance = opening_balance;
+    let mut entries = Vec::new();
+    for &(timestamp, transfer, expired) in changes
+        .iter()
+        .take_while(|&&(timestamp, _, _)| timestamp < to_ns)
+    {
+        let change = change(transfer, expired);
+        balance += change;
+
+        let Some(kind) = TransferCode::of(transfer) else {
+            warn!(
+                "transfer {} has unknown code {}",
+                uuid::Uuid::from_u128(transfer.id()),
+                transfer.code()
+            );
+            continue;
+        };
+
+        entries.push(LedgerEntry {
+            id: uuid::Uuid::from_u128(transfer.id()),
+            kind,
+            amount: transfer.amount(),
+            pending: transfer.flags().contains(tb::transfer::Flags::PENDING) && !expired,
+            expired,
+            change,
+            balance,
+            timestamp: Utc.timestamp_nanos(timestamp as i64),
+        });
+    }
+
+    Ok(Json(Statement {
+        opening_balance,
+        closing_balance: balance,
+        entries,
+    }))
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::Utc;
+use diesel::ExpressionMethods;
+use itertools::Itertools;
+use rocket::{http::Status, post, serde::json::Json};
+use rocket_db_pools::{
+    deadpool_redis::redis,
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        AsyncPgConnection,
+    },
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tigerbeetle_unofficial as tb;
+use tracing::{error, warn};
+
+use super::{
+    accounts::{asset_account_id, Book, OrderRecord},
+    auth::AdminCheck,
+    events::CancelReason,
+    keys,
+    ledger::{self, lookup_transfers, TransferCode, CASH_LEDGER, SETTLEMENTS_KEY},
+    schema::{equities, equity_options, transfers},
+    types::Uuid,
+    ADMIN_ACCOUNT_ID,
+};
+use crate::{Accounting, Meta, Orders};
+
+/// How long the book and the ledger may disagree about an order before it counts as a
+/// mismatch, so that orders being placed or settled while they are compared aren't reported.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// A reservation held in the ledger for an order that isn't open.
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct OrphanedReservation {
+    /// ID of the pending TigerBeetle transfer holding the reservation.
+    pub transfer_id: uuid::Uuid,
+    /// Asset held back, or none for cash.
+    pub asset_id: Option<i32>,
+    /// Cash or units of the asset held back.
+    pub amount: u128,
+}
+
+impl From<&tb::Transfer> for OrphanedReservation {
+    fn from(transfer: &tb::Transfer) -> Self {
+        Self {
+            transfer_id: uuid::Uuid::from_u128(transfer.id()),
+            asset_id: (transfer.ledger() != CASH_LEDGER).then_some(transfer.ledger() as i32),
+            amount: transfer.amount(),
+        }
+    }
+}
+
+/// An open order without a reservation in the ledger.
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct UnfundedOrder {
+    pub order_id: uuid::Uuid,
+    pub account_id: uuid::Uuid,
+    pub asset_id: i32,
+    pub side: Book,
+    /// Units left on the order.
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Reconciliation {
+    pub orphaned_reservations: Vec<OrphanedReservation>,
+    pub unfunded_orders: Vec<UnfundedOrder>,
+    /// Whether the mismatches were repaired, by voiding orphaned reservations and cancelling
+    /// unfunded orders.
+    pub repaired: bool,
+}
+
+/// Compare the orders on the books with the reservations held for them in the ledger, and
+/// optionally repair mismatches that outlast the grace period.
+///
+/// Repairs wait until the settlement stream is empty, as queued settlements may yet create or
+/// void the reservations in question.
+pub async fn reconcile<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    repair: bool,
+) -> Result<Reconciliation, Status> {
+    let (mut orphaned, mut unfunded) = mismatches(meta, orders, accounting).await?;
+
+    let mut repaired = false;
+    if repair && !(orphaned.is_empty() && unfunded.is_empty()) {
+        let settling: usize = redis::cmd("XLEN")
+            .arg(SETTLEMENTS_KEY)
+            .query_async(orders)
+            .await
+            .map_err(|e| {
+                error!("error checking for queued settlements: {e}");
+                Status::InternalServerError
+            })?;
+
+        if settling > 0 {
+            warn!("not repairing mismatches while {settling} settlements are queued");
+        } else {
+            // only mismatches still there after the grace period are repaired
+            tokio::time::sleep(GRACE_PERIOD).await;
+            let (orphaned_again, unfunded_again) = mismatches(meta, orders, accounting).await?;
+            let transfer_ids: HashSet<u128> = orphaned.iter().map(tb::Transfer::id).collect();
+            let order_sizes: HashSet<(uuid::Uuid, u32)> = unfunded
+                .iter()
+                .map(|order| (order.order_id, order.size))
+                .collect();
+            orphaned = orphaned_again
+                .into_iter()
+                .filter(|transfer| transfer_ids.contains(&transfer.id()))
+                .collect();
+            unfunded = unfunded_again
+                .into_iter()
+                .filter(|order| order_sizes.contains(&(order.order_id, order.size)))
+                .collect();
+
+            void_reservations(meta, accounting, &orphaned).await?;
+            cancel_orders(orders, &unfunded).await?;
+            repaired = true;
+        }
+    }
+
+    for transfer in &orphaned {
+        warn!(
+            "reservation {} is held for an order that isn't open",
+            uuid::Uuid::from_u128(transfer.id())
+        );
+    }
+    for order in &unfunded {
+        warn!("order {} has no reservation", order.order_id);
+    }
+
+    Ok(Reconciliation {
+        orphaned_reservations: orphaned.iter().map(OrphanedReservation::from).collect(),
+        unfunded_orders: unfunded,
+        repaired,
+    })
+}
+
+/// IDs of every equity and equity option.
+pub async fn asset_ids(meta: &mut AsyncPgConnection) -> Result<Vec<i32>, Status> {
+    let mut asset_ids: Vec<i32> = equities::table
+        .select(equities::id)
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching equities: {e}");
+            Status::InternalServerError
+        })?;
+    asset_ids.extend(
+        equity_options::table
+            .select(equity_options::id)
+            .load::<i32>(meta)
+            .await
+            .map_err(|e| {
+                error!("error fetching equity options: {e}");
+                Status::InternalServerError
+            })?,
+    );
+    Ok(asset_ids)
+}
+
+/// Every order on an asset's books and trigger books.
+pub async fn open_orders<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+) -> Result<Vec<(Uuid, OrderRecord)>, Status> {
+    redis::Script::new(include_str!("scripts/open_orders.lua"))
+        .prepare_invoke()
+        .key(keys::asset(asset_id, "bids"))
+        .key(keys::asset(asset_id, "offers"))
+        .key(keys::asset(asset_id, "stop_bids"))
+        .key(keys::asset(asset_id, "stop_offers"))
+        .arg(keys::order_prefix(asset_id))
+        .invoke_async(orders)
+        .await
+        .map_err(|e| {
+            error!("error fetching open orders for asset {asset_id}: {e}");
+            Status::InternalServerError
+        })
+}
+
+/// Reservations held for orders that aren't open, and open orders without a reservation.
+async fn mismatches<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+) -> Result<(Vec<tb::Transfer>, Vec<UnfundedOrder>), Status> {
+    let asset_ids = asset_ids(meta).await?;
+
+    // reservations created after this are too new to tell whether their order is open
+    let now = Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64;
+    let cutoff = now - GRACE_PERIOD.as_nanos() as u64;
+
+    let mut open = Vec::new();
+    for &asset_id in &asset_ids {
+        open.extend(open_orders(orders, asset_id).await?);
+    }
+
+    // every reservation is made from one of the admin account's cash or asset accounts
+    let admin_accounts = std::iter::once(ADMIN_ACCOUNT_ID.as_u128())
+        .chain(
+            asset_ids
+                .iter()
+                .map(|&asset_id| asset_account_id(ADMIN_ACCOUNT_ID, asset_id)),
+        )
+        .map(uuid::Uuid::from_u128)
+        .collect_vec();
+    let transfer_ids: Vec<uuid::Uuid> = transfers::table
+        .filter(transfers::debit_account_id.eq_any(admin_accounts))
+        .select(transfers::id)
+        .load(meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching transfer index: {e}");
+            Status::InternalServerError
+        })?;
+
+    let pending = lookup_transfers(
+        accounting,
+        transfer_ids.iter().map(uuid::Uuid::as_u128).collect(),
+    )
+    .await?
+    .into_iter()
+    .filter(|transfer| {
+        transfer.code() == u16::from(TransferCode::OrderReservation)
+            && transfer.flags().contains(tb::transfer::Flags::PENDING)
+    })
+    .collect_vec();
+    let voided: HashSet<u128> = lookup_transfers(
+        accounting,
+        pending
+            .iter()
+            .map(|transfer| ledger::void_id(transfer.id()))
+            .collect(),
+    )
+    .await?
+    .iter()
+    .map(tb::Transfer::pending_id)
+    .collect();
+    // reservations of expiring orders that have timed out no longer hold anything
+    let mut held: HashMap<u128, tb::Transfer> = pending
+        .into_iter()
+        .filter(|transfer| !voided.contains(&transfer.id()) && !ledger::timed_out(transfer, now))
+        .map(|transfer| (transfer.id(), transfer))
+        .collect();
+
+    let mut unfunded = Vec::new();
+    for (order_id, record) in open {
+        let reservation = record.reservation(order_id.0);
+        let filled = record.original_size - record.size;
+        // market bids reserve nothing
+        if reservation.reserve(filled).is_none() {
+            continue;
+        }
+        if held.remove(&reservation.transfer_id(filled)).is_none() {
+            unfunded.push(UnfundedOrder {
+                order_id: order_id.0,
+                account_id: record.account_id.0,
+                asset_id: record.asset_id,
+                side: record.side,
+                size: record.size,
+            });
+        }
+    }
+
+    let orphaned = held
+        .into_values()
+        .filter(|transfer| ledger::timestamp_ns(transfer) < cutoff)
+        .sorted_by_key(ledger::timestamp_ns)
+        .collect();
+    Ok((orphaned, unfunded))
+}
+
+/// Void reservations held for orders that aren't open.
+async fn void_reservations(
+    meta: &mut AsyncPgConnection,
+    accounting: &tb::Client,
+    orphaned: &[tb::Transfer],
+) -> Result<(), Status> {
+    if orphaned.is_empty() {
+        return Ok(());
+    }
+
+    let voids = orphaned
+        .iter()
+        .map(|pending| {
+            tb::Transfer::new(ledger::void_id(pending.id()))
+                .with_debit_account_id(pending.debit_account_id())
+                .with_credit_account_id(pending.credit_account_id())
+                .with_amount(pending.amount())
+                .with_pending_id(pending.id())
+                .with_ledger(pending.ledger())
+                .with_code(pending.code())
+                .with_flags(tb::transfer::Flags::VOID_PENDING_TRANSFER)
+        })
+        .collect_vec();
+    ledger::void_reservations(meta, accounting, voids)
+        .await
+        .map_err(|e| {
+            error!("error voiding orphaned reservations: {e}");
+            Status::InternalServerError
+        })
+}
+
+/// Cancel open orders without a reservation. Orders that have changed since they were found are
+/// left for the next reconciliation.
+async fn cancel_orders<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    unfunded: &[UnfundedOrder],
+) -> Result<(), Status> {
+    for (asset_id, unfunded) in unfunded.iter().into_group_map_by(|order| order.asset_id) {
+        let script = redis::Script::new(include_str!("scripts/remove.lua"));
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(keys::asset(asset_id, "bids"))
+            .key(keys::asset(asset_id, "offers"))
+            .key(keys::asset(asset_id, "stop_bids"))
+            .key(keys::asset(asset_id, "stop_offers"))
+            .key(keys::asset(asset_id, "expiries"))
+            .key(keys::asset(asset_id, "events"))
+            .arg(Utc::now().timestamp_millis())
+            .arg(CancelReason::Unfunded)
+            .arg(asset_id)
+            .arg(keys::order_prefix(asset_id));
+        for order in unfunded {
+            invocation.arg(Uuid(order.order_id)).arg(order.size);
+        }
+
+        invocation
+            .invoke_async::<_, Vec<Uuid>>(orders)
+            .await
+            .map_err(|e| {
+                error!("error cancelling unfunded orders for asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?;
+    }
+
+    Ok(())
+}
+
+/// # Reconcile Orders
+///
+/// Compare the orders on the books with the reservations held for them in the ledger, listing
+/// reservations held for orders that aren't open and open orders without a reservation. With
+/// `repair`, mismatches that last a few seconds are fixed by voiding the reservations and
+/// cancelling the orders.
+#[openapi(tag = "Assets")]
+#[post("/reconciliation?<repair>")]
+pub async fn reconcile_orders(
+    _check: AdminCheck,
+    repair: Option<bool>,
+    mut meta: Connection<Meta>,
+    mut orders: Connection<Orders>,
+    accounting: Connection<Accounting>,
+) -> Result<Json<Reconciliation>, Status> {
+    reconcile(
+        &mut meta,
+        orders.as_mut(),
+        &accounting,
+        repair.unwrap_or(false),
+    )
+    .await
+    .map(Json)
+}
@@ -0,0 +1,148 @@
+use rocket::{
+    http::Status,
+    response::{self, status, Responder},
+    serde::json::Json,
+    Request,
+};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::openapi3::Responses,
+    response::OpenApiResponderInner,
+    util::{add_schema_response, ensure_status_code_exists},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    assets::AssetStatus,
+    auctions::TradingPhase,
+    types::{Notional, Price},
+};
+
+/// Why an order was refused before reaching the book.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, thiserror::Error)]
+#[serde(rename_all = "snake_case", tag = "reason")]
+pub enum Rejection {
+    #[error("asset {asset_id} does not exist")]
+    UnknownAsset { asset_id: i32 },
+    #[error("asset {asset_id} is not open for trading")]
+    NotTradable { asset_id: i32 },
+    #[error("asset {asset_id} is {status}")]
+    Unavailable { asset_id: i32, status: AssetStatus },
+    #[error("price {price} is not a positive multiple of the tick size {tick_size}")]
+    InvalidPrice { price: Price, tick_size: Price },
+    #[error("size {size} is not a positive multiple of the lot size {lot_size}")]
+    InvalidSize { size: u32, lot_size: i32 },
+    #[error(
+        "good 'til date orders need an expiry in the future, at most a year ahead, and day and \
+         other orders can't be given one"
+    )]
+    InvalidExpiry,
+    #[error("post-only orders must be limit orders that can rest on the book")]
+    PostOnlyNotResting,
+    #[error("post-only order would have traded against a resting order")]
+    WouldTrade,
+    #[error(
+        "display size {display_size} must be between 1 and the order size {size}, on a limit order \
+         that can rest on the book"
+    )]
+    InvalidDisplaySize { display_size: u32, size: u32 },
+    #[error("only limit and stop-limit orders have a limit price to amend")]
+    NoLimitPrice,
+    #[error("only limit orders that can rest on the book are accepted during the {phase} phase")]
+    CallPhase { phase: TradingPhase },
+    #[error("price {price} is outside the band from {lower} to {upper} around the last trade")]
+    OutsideBand {
+        price: Price,
+        lower: Price,
+        upper: Price,
+    },
+    #[error("size {size} is over the account's limit of {max} per order")]
+    OrderSizeLimit { size: u32, max: i32 },
+    #[error("order value {notional} is over the account's limit of {max} per order")]
+    NotionalLimit { notional: Notional, max: Notional },
+    #[error("the account already has {open} open orders, and is limited to {max}")]
+    OpenOrderLimit { open: i32, max: i32 },
+    #[error(
+        "buying {size} more units on top of {position} held and {bids} bid for would go over the \
+         account's position limit of {max}"
+    )]
+    PositionLimit {
+        position: i128,
+        bids: u64,
+        size: u32,
+        max: i64,
+    },
+    #[error("the account doesn't have the cash or units available to reserve for the order")]
+    InsufficientFunds,
+    #[error("price {price} is more than {max} basis points from the last trade at {reference}")]
+    PriceDeviation {
+        price: Price,
+        reference: Price,
+        max: i32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RejectionBody {
+    #[serde(flatten)]
+    rejection: Rejection,
+    /// Human-readable description of the rejection.
+    message: String,
+}
+
+impl From<Rejection> for RejectionBody {
+    fn from(rejection: Rejection) -> Self {
+        Self {
+            message: rejection.to_string(),
+            rejection,
+        }
+    }
+}
+
+/// Error returned by order endpoints: either a rejection of the order itself, or a failure
+/// to process it.
+#[derive(Debug)]
+pub enum OrderError {
+    Rejected(Rejection),
+    Failed(Status),
+}
+
+impl From<Rejection> for OrderError {
+    fn from(rejection: Rejection) -> Self {
+        Self::Rejected(rejection)
+    }
+}
+
+impl From<Status> for OrderError {
+    fn from(status: Status) -> Self {
+        Self::Failed(status)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for OrderError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Self::Rejected(rejection) => status::Custom(
+                Status::UnprocessableEntity,
+                Json(RejectionBody::from(rejection)),
+            )
+            .respond_to(req),
+            Self::Failed(status) => status.respond_to(req),
+        }
+    }
+}
+
+impl OpenApiResponderInner for OrderError {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        add_schema_response(
+            &mut responses,
+            422,
+            "application/json",
+            gen.json_schema::<RejectionBody>(),
+        )?;
+        ensure_status_code_exists(&mut responses, 500);
+        Ok(responses)
+    }
+}
@@ -0,0 +1,300 @@
+use diesel::{
+    AsChangeset, ExpressionMethods, Insertable, OptionalExtension, Queryable, Selectable,
+    SelectableHelper,
+};
+use itertools::Itertools;
+use rocket::{get, http::Status, put, serde::json::Json};
+use rocket_db_pools::{
+    deadpool_redis::redis,
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        AsyncPgConnection,
+    },
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tigerbeetle_unofficial as tb;
+use tracing::error;
+
+use super::{
+    accounts::{asset_account_id, posted_balance, Book, CreateOrderForm, OrderType, UserIdCheck},
+    auth::AdminCheck,
+    bands, keys,
+    rejection::{OrderError, Rejection},
+    schema::risk_limits,
+    types::{Notional, Price},
+};
+use crate::Meta;
+
+/// Pre-trade limits on an account's orders. Limits that aren't set aren't checked.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Serialize,
+    Deserialize,
+    Queryable,
+    Selectable,
+    Insertable,
+    AsChangeset,
+    JsonSchema,
+)]
+#[diesel(table_name = risk_limits)]
+#[diesel(treat_none_as_null = true)]
+pub struct RiskLimits {
+    /// Most units a single order can be for.
+    pub max_order_size: Option<i32>,
+    /// Largest value a single order can have, at its limit price, its trigger price for stop
+    /// orders, or the last trade for market orders.
+    pub max_notional: Option<Notional>,
+    /// Most orders the account can have on the books and trigger books at once, as of when its
+    /// index of open orders was last brought up to date.
+    pub max_open_orders: Option<i32>,
+    /// Most units of any one asset the account can hold once a buy order and its other open bids
+    /// fill.
+    pub max_position: Option<i64>,
+    /// Furthest a limit price can be from the last trade, in basis points.
+    pub max_price_deviation: Option<i32>,
+}
+
+/// Limits set for an account, or none.
+async fn limits(
+    meta: &mut AsyncPgConnection,
+    account_id: uuid::Uuid,
+) -> diesel::QueryResult<RiskLimits> {
+    risk_limits::table
+        .find(account_id)
+        .select(RiskLimits::as_select())
+        .first(meta)
+        .await
+        .optional()
+        .map(Option::unwrap_or_default)
+}
+
+/// Units an account is bidding for in an asset across its open orders, other than `except`.
+async fn open_bid_size<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    account_id: uuid::Uuid,
+    asset_id: i32,
+    except: Option<uuid::Uuid>,
+) -> redis::RedisResult<u64> {
+    let order_ids: Vec<super::types::Uuid> = redis::cmd("ZRANGEBYSCORE")
+        .arg(keys::account_orders(account_id))
+        .arg(asset_id)
+        .arg(asset_id)
+        .query_async(orders)
+        .await?;
+
+    let order_ids = order_ids
+        .into_iter()
+        .filter(|order_id| Some(order_id.0) != except)
+        .collect_vec();
+    if order_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut pipe = redis::pipe();
+    for order_id in &order_ids {
+        pipe.cmd("HMGET")
+            .arg(keys::order(asset_id, order_id.0))
+            .arg("side")
+            .arg("size");
+    }
+    let open: Vec<(Option<String>, Option<u32>)> = pipe.query_async(orders).await?;
+
+    // orders that left the book since the index was last brought up to date have no fields left
+    Ok(open
+        .into_iter()
+        .filter(|(side, _)| side.as_deref() == Some("bids"))
+        .map(|(_, size)| size.unwrap_or(0) as u64)
+        .sum())
+}
+
+/// Check an order against its account's risk limits before anything is reserved for it. An order
+/// replacing one of the account's open orders, `replaces`, doesn't count towards its open order
+/// limit, and the order it replaces doesn't count towards its position limit.
+#[allow(clippy::too_many_arguments)]
+pub async fn check<C: redis::aio::ConnectionLike>(
+    meta: &mut AsyncPgConnection,
+    orders: &mut C,
+    accounting: &tb::Client,
+    account_id: uuid::Uuid,
+    asset_id: i32,
+    book: Book,
+    form: &CreateOrderForm,
+    replaces: Option<uuid::Uuid>,
+) -> Result<(), OrderError> {
+    let limits = limits(meta, account_id).await.map_err(|e| {
+        error!("error fetching risk limits for account {account_id}: {e}");
+        Status::InternalServerError
+    })?;
+
+    if let Some(max) = limits.max_order_size {
+        if form.size > max as u32 {
+            return Err(Rejection::OrderSizeLimit {
+                size: form.size,
+                max,
+            }
+            .into());
+        }
+    }
+
+    let last = if limits.max_notional.is_some() || limits.max_price_deviation.is_some() {
+        bands::get(orders, asset_id)
+            .await
+            .map_err(|e| {
+                error!("error fetching last trade of asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?
+            .1
+    } else {
+        None
+    };
+
+    if let Some(max) = limits.max_notional {
+        let price = match form.order_type {
+            OrderType::Market => last,
+            OrderType::Stop { trigger } => Some(trigger),
+            OrderType::Limit { price } | OrderType::StopLimit { price, .. } => Some(price),
+        };
+        // market orders for assets that have never traded can't be valued
+        if let Some(notional) = price.map(|price| price * form.size) {
+            if notional > max {
+                return Err(Rejection::NotionalLimit { notional, max }.into());
+            }
+        }
+    }
+
+    if let (Some(max), Some(price), Some(reference)) =
+        (limits.max_price_deviation, form.order_type.price(), last)
+    {
+        if deviation(price, reference) > max as i128 {
+            return Err(Rejection::PriceDeviation {
+                price,
+                reference,
+                max,
+            }
+            .into());
+        }
+    }
+
+    if let (Some(max), None) = (limits.max_open_orders, replaces) {
+        let open: i32 = redis::cmd("ZCARD")
+            .arg(keys::account_orders(account_id))
+            .query_async(orders)
+            .await
+            .map_err(|e| {
+                error!("error counting open orders for account {account_id}: {e}");
+                Status::InternalServerError
+            })?;
+        if open >= max {
+            return Err(Rejection::OpenOrderLimit { open, max }.into());
+        }
+    }
+
+    if let (Some(max), Book::Bids) = (limits.max_position, book) {
+        let position = accounting
+            .lookup_accounts(vec![asset_account_id(account_id, asset_id)])
+            .await
+            .map_err(|e| {
+                error!("error fetching position of account {account_id} in asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?
+            .into_iter()
+            .next()
+            .map_or(0, |account| posted_balance(&account));
+        // units already bid for count as held, since the bids can fill at any time
+        let bids = open_bid_size(orders, account_id, asset_id, replaces)
+            .await
+            .map_err(|e| {
+                error!("error fetching open bids of account {account_id} in asset {asset_id}: {e}");
+                Status::InternalServerError
+            })?;
+        if position + bids as i128 + form.size as i128 > max as i128 {
+            return Err(Rejection::PositionLimit {
+                position,
+                bids,
+                size: form.size,
+                max,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Distance of `price` from `reference` in basis points of `reference`.
+fn deviation(price: Price, reference: Price) -> i128 {
+    let reference = reference.mills() as i128;
+    if reference == 0 {
+        return 0;
+    }
+    (price.mills() as i128 - reference).abs() * 10_000 / reference.abs()
+}
+
+/// # Get Risk Limits
+///
+/// Show the limits the account's orders are checked against before they reach the book.
+#[openapi(tag = "Accounts")]
+#[get("/accounts/<account_id>/risk_limits")]
+pub async fn get_risk_limits_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    mut meta: Connection<Meta>,
+) -> Result<Json<RiskLimits>, Status> {
+    limits(&mut meta, account_id).await.map(Json).map_err(|e| {
+        error!("error fetching risk limits for account {account_id}: {e}");
+        Status::InternalServerError
+    })
+}
+
+/// # Set Risk Limits
+///
+/// Replace the limits the account's orders are checked against. Limits left out are no longer
+/// checked.
+#[openapi(tag = "Accounts")]
+#[put("/accounts/<account_id>/risk_limits", data = "<form>")]
+pub async fn set_risk_limits_for_account(
+    _check: AdminCheck,
+    account_id: uuid::Uuid,
+    mut meta: Connection<Meta>,
+    form: Json<RiskLimits>,
+) -> Result<Json<RiskLimits>, Status> {
+    let invalid = [
+        form.max_order_size,
+        form.max_open_orders,
+        form.max_price_deviation,
+    ]
+    .into_iter()
+    .flatten()
+    .any(|limit| limit <= 0)
+        || form.max_position.is_some_and(|limit| limit <= 0)
+        || form.max_notional.is_some_and(|limit| !limit.is_positive());
+    if invalid {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    diesel::insert_into(risk_limits::table)
+        .values((risk_limits::account_id.eq(account_id), *form))
+        .on_conflict(risk_limits::account_id)
+        .do_update()
+        .set(*form)
+        .returning(RiskLimits::as_returning())
+        .get_result(&mut meta)
+        .await
+        .map(Json)
+        .map_err(|e| match e {
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::ForeignKeyViolation,
+                _,
+            ) => Status::NotFound,
+            e => {
+                error!("error setting risk limits for account {account_id}: {e}");
+                Status::InternalServerError
+            }
+        })
+}
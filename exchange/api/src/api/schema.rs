@@ -1,11 +1,40 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    competition_assets (competition_id, asset_id) {
+        competition_id -> Int4,
+        asset_id -> Int4,
+    }
+}
+
+diesel::table! {
+    competition_entries (competition_id, account_id) {
+        competition_id -> Int4,
+        account_id -> Uuid,
+        entered -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    competitions (id) {
+        id -> Int4,
+        name -> Text,
+        starts -> Timestamptz,
+        ends -> Timestamptz,
+        starting_cash -> Int8,
+        created -> Timestamptz,
+    }
+}
+
 diesel::table! {
     equities (id) {
         id -> Int4,
         ticker -> Text,
         description -> Nullable<Text>,
         created -> Timestamptz,
+        tick_size -> Int8,
+        lot_size -> Int4,
+        status -> Text,
     }
 }
 
@@ -15,7 +44,75 @@ diesel::table! {
         underlying -> Int4,
         expiration_date -> Date,
         contract_type -> Text,
-        strike_price -> Int4,
+        strike_price -> Int8,
+        created -> Timestamptz,
+        tick_size -> Int8,
+        lot_size -> Int4,
+        status -> Text,
+    }
+}
+
+diesel::table! {
+    fills (id) {
+        id -> Uuid,
+        asset_id -> Int4,
+        buy_order_id -> Uuid,
+        sell_order_id -> Uuid,
+        buyer_id -> Uuid,
+        seller_id -> Uuid,
+        price -> Int8,
+        size -> Int4,
+        created -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    leaderboard_snapshots (competition_id, account_id, taken) {
+        competition_id -> Int4,
+        account_id -> Uuid,
+        taken -> Timestamptz,
+        value -> Int8,
+    }
+}
+
+diesel::table! {
+    orders (id) {
+        id -> Uuid,
+        account_id -> Uuid,
+        asset_id -> Int4,
+        side -> Text,
+        price -> Int8,
+        size -> Int4,
+        original_size -> Int4,
+        priority -> Nullable<Int8>,
+        expires -> Nullable<Int8>,
+        display_size -> Nullable<Int4>,
+        trigger -> Nullable<Int8>,
+        order_type -> Nullable<Text>,
+        time_in_force -> Nullable<Text>,
+        open -> Bool,
+        created -> Timestamptz,
+        updated -> Timestamptz,
+        status -> Text,
+    }
+}
+
+diesel::table! {
+    risk_limits (account_id) {
+        account_id -> Uuid,
+        max_order_size -> Nullable<Int4>,
+        max_notional -> Nullable<Int8>,
+        max_open_orders -> Nullable<Int4>,
+        max_position -> Nullable<Int8>,
+        max_price_deviation -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    transfers (id) {
+        id -> Uuid,
+        debit_account_id -> Uuid,
+        credit_account_id -> Uuid,
         created -> Timestamptz,
     }
 }
@@ -26,13 +123,28 @@ diesel::table! {
         email -> Text,
         password -> Text,
         role_flags -> Int8,
+        self_trade_prevention -> Text,
     }
 }
 
+diesel::joinable!(competition_assets -> competitions (competition_id));
+diesel::joinable!(competition_entries -> competitions (competition_id));
+diesel::joinable!(competition_entries -> users (account_id));
 diesel::joinable!(equity_options -> equities (underlying));
+diesel::joinable!(leaderboard_snapshots -> competitions (competition_id));
+diesel::joinable!(leaderboard_snapshots -> users (account_id));
+diesel::joinable!(risk_limits -> users (account_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    competition_assets,
+    competition_entries,
+    competitions,
     equities,
     equity_options,
+    fills,
+    leaderboard_snapshots,
+    orders,
+    risk_limits,
+    transfers,
     users,
 );
@@ -0,0 +1,59 @@
+-- Amend an order. Reducing the size of an order on the book keeps its ID and its place in the
+-- queue, and is asked for by passing the order's own ID as the new ID. Any other change takes the
+-- order out, to be submitted again as an amended copy under the new ID.
+
+local order_key, book, stop_book, expiries, events = unpack(KEYS)
+local order_id, new_order_id, account_id, size, price, now = unpack(ARGV)
+local size = tonumber(size)
+
+local order = redis.call('HMGET', order_key,
+    'account_id', 'price', 'size', 'original_size', 'trigger', 'visible', 'expires', 'asset_id', 'side')
+if order[1] ~= account_id then
+    return nil
+end
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+-- Record a change to the order in the asset's event stream.
+local function emit(event, event_size, remaining, ...)
+    local fields = {
+        'event', event,
+        'order_id', order_id,
+        'account_id', account_id,
+        'asset_id', order[8],
+        'side', order[9],
+        'price', order[2],
+        'size', event_size,
+        'remaining', remaining,
+        'timestamp', now,
+        ...
+    }
+    redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*', unpack(fields))
+end
+
+local remaining = tonumber(order[3])
+-- price, size and original size of the order before the change
+local previous = {order[2], remaining, tonumber(order[4])}
+
+if new_order_id == order_id then
+    -- the order may have traded down below the new size since it was looked up
+    if order[5] or tonumber(price) ~= tonumber(order[2]) or size > remaining then
+        return {'stale', unpack(previous)}
+    end
+    redis.call('HSET', order_key, 'size', size)
+    if order[6] then
+        redis.call('HSET', order_key, 'visible', math.min(tonumber(order[6]), size))
+    end
+    if size < remaining then
+        emit('cancelled', remaining - size, size, 'reason', 'amended')
+    end
+    return {'amended', unpack(previous)}
+end
+
+emit('cancelled', remaining, 0, 'reason', 'amended', 'replaced_by', new_order_id)
+redis.call('ZREM', expiries, order_id)
+redis.call('ZREM', book, order_id)
+redis.call('ZREM', stop_book, order_id)
+redis.call('DEL', order_key)
+return {'replaced', unpack(previous)}
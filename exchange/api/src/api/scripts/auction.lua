@@ -0,0 +1,194 @@
+-- Find the price that would execute the most volume if an asset's book were uncrossed now, and
+-- optionally uncross it at that price and move the asset on to its next trading phase. Uncrossing
+-- trades are appended to the asset's event stream.
+
+local phase_key, book_bid, book_offer, last_trade, stop_bids, stop_offers, expiries, volatility_key,
+    events = unpack(KEYS)
+local expected, next_phase, uncross, now, asset_id, order_prefix = unpack(ARGV)
+local now = tonumber(now)
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+local phase = redis.call('GET', phase_key) or 'continuous'
+if expected ~= '' and phase ~= expected then
+    return {phase, false, 0, {}, {}}
+end
+
+-- Key of the hash holding an order of this asset.
+local function key(id)
+    return order_prefix .. id
+end
+
+local function remove_order(book, id)
+    redis.call('ZREM', book, id)
+    redis.call('ZREM', expiries, id)
+    redis.call('DEL', key(id))
+end
+
+-- Live orders on one side of the book, best price first, then by time priority. Hidden iceberg
+-- quantity takes part in full.
+local function collect(book, better)
+    local orders = {}
+    for i, id in ipairs(redis.call('ZRANGE', book, 0, -1)) do
+        local order = redis.call('HMGET', key(id),
+            'account_id', 'price', 'size', 'original_size', 'expires', 'priority', 'display_size')
+        local expires = tonumber(order[5])
+        if not (expires and expires <= now) then
+            table.insert(orders, {
+                id = id,
+                account_id = order[1],
+                price_arg = order[2],
+                price = tonumber(order[2]),
+                size = tonumber(order[3]),
+                original_size = tonumber(order[4]),
+                priority = tonumber(order[6]) or 0,
+                display_size = tonumber(order[7]),
+                expires = expires or 0,
+                arrival = i,
+            })
+        end
+    end
+    table.sort(orders, function(a, b)
+        if a.price ~= b.price then
+            return better(a.price, b.price)
+        end
+        if a.priority ~= b.priority then
+            return a.priority < b.priority
+        end
+        return a.arrival < b.arrival
+    end)
+    return orders
+end
+
+local bids = collect(book_bid, function(a, b) return a > b end)
+local offers = collect(book_offer, function(a, b) return a < b end)
+
+-- every limit price on the book is a candidate, lowest first
+local price_args, prices = {}, {}
+for _, orders in ipairs({bids, offers}) do
+    for _, order in ipairs(orders) do
+        if not price_args[order.price] then
+            price_args[order.price] = order.price_arg
+            table.insert(prices, order.price)
+        end
+    end
+end
+table.sort(prices)
+
+-- units offered at or below, and bid at or above, each candidate price
+local supply, demand = {}, {}
+local next_order, total = 1, 0
+for i, price in ipairs(prices) do
+    while offers[next_order] and offers[next_order].price <= price do
+        total = total + offers[next_order].size
+        next_order = next_order + 1
+    end
+    supply[i] = total
+end
+next_order, total = 1, 0
+for i = #prices, 1, -1 do
+    while bids[next_order] and bids[next_order].price >= prices[i] do
+        total = total + bids[next_order].size
+        next_order = next_order + 1
+    end
+    demand[i] = total
+end
+
+-- Maximise executed volume, then minimise the unmatched surplus, then stay closest to the last
+-- trade, then take the lowest price.
+local reference = tonumber(redis.call('GET', last_trade))
+local best
+for i, price in ipairs(prices) do
+    local volume = math.min(supply[i], demand[i])
+    local surplus = math.abs(supply[i] - demand[i])
+    local distance = reference and math.abs(price - reference) or 0
+    if volume > 0 and (best == nil
+            or volume > best.volume
+            or (volume == best.volume and surplus < best.surplus)
+            or (volume == best.volume and surplus == best.surplus and distance < best.distance)) then
+        best = { price = price, volume = volume, surplus = surplus, distance = distance }
+    end
+end
+
+local fills = {}
+local triggered = {}
+if best and uncross == '1' then
+    local price_arg = price_args[best.price]
+    local remaining = best.volume
+    local bid_index, offer_index = 1, 1
+    while remaining > 0 do
+        local bid, offer = bids[bid_index], offers[offer_index]
+        local size = math.min(remaining, bid.size, offer.size)
+
+        -- for each side: order id, account id, limit price, original size, size filled before,
+        -- expiry (0 if none)
+        table.insert(fills, {
+            {bid.id, bid.account_id, bid.price_arg, bid.original_size, bid.original_size - bid.size, bid.expires},
+            {offer.id, offer.account_id, offer.price_arg, offer.original_size, offer.original_size - offer.size,
+                offer.expires},
+            size,
+        })
+        bid.size = bid.size - size
+        offer.size = offer.size - size
+        remaining = remaining - size
+
+        for _, fill in ipairs({{bid, 'bids', offer}, {offer, 'offers', bid}}) do
+            local order, side, counterparty = unpack(fill)
+            redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*',
+                'event', order.size == 0 and 'filled' or 'partially_filled',
+                'order_id', order.id,
+                'account_id', order.account_id,
+                'asset_id', asset_id,
+                'side', side,
+                'price', price_arg,
+                'size', size,
+                'remaining', order.size,
+                'timestamp', now,
+                'counterparty', counterparty.id)
+        end
+
+        for _, side in ipairs({{bid, book_bid}, {offer, book_offer}}) do
+            local order, book = unpack(side)
+            if order.size == 0 then
+                remove_order(book, order.id)
+            else
+                redis.call('HSET', key(order.id), 'size', order.size)
+                if order.display_size then
+                    redis.call('HSET', key(order.id), 'visible', math.min(order.display_size, order.size))
+                end
+            end
+        end
+        if bid.size == 0 then
+            bid_index = bid_index + 1
+        end
+        if offer.size == 0 then
+            offer_index = offer_index + 1
+        end
+    end
+
+    redis.call('SET', last_trade, price_arg)
+    -- the next volatility window starts from the uncrossing price
+    redis.call('DEL', volatility_key)
+
+    -- take stop orders the uncrossing price reached out of the trigger books
+    local function trigger(stop_book, lower, upper)
+        for _, stop_id in ipairs(redis.call('ZRANGE', stop_book, lower, upper, 'BYSCORE')) do
+            local stop = redis.call('HMGET', key(stop_id),
+                'account_id', 'side', 'order_type', 'price', 'size', 'time_in_force', 'expires',
+                'display_size')
+            -- order id, account id, side, order type, price, size, time in force, expiry, display size
+            table.insert(triggered, {stop_id, unpack(stop)})
+            remove_order(stop_book, stop_id)
+        end
+    end
+    trigger(stop_bids, '-inf', price_arg)
+    trigger(stop_offers, price_arg, '+inf')
+end
+
+if next_phase ~= '' then
+    redis.call('SET', phase_key, next_phase)
+end
+
+-- phase before any change, equilibrium price and volume, uncrossing trades, triggered stops
+return {phase, best and price_args[best.price] or false, best and best.volume or 0, fills, triggered}
@@ -0,0 +1,44 @@
+-- Remove every order from the given books and trigger books, recording each in the asset's event
+-- stream
+
+local expiries, events = KEYS[#KEYS - 1], KEYS[#KEYS]
+local now, reason, asset_id, order_prefix = unpack(ARGV)
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+local cancelled = {}
+for i = 1, #KEYS - 2 do
+    local book = KEYS[i]
+    for _, order_id in ipairs(redis.call('ZRANGE', book, 0, -1)) do
+        local order = redis.call('HMGET', order_prefix .. order_id,
+            'account_id', 'side', 'price', 'size', 'original_size')
+        redis.call('ZREM', expiries, order_id)
+        redis.call('DEL', order_prefix .. order_id)
+
+        redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*',
+            'event', 'cancelled',
+            'order_id', order_id,
+            'account_id', order[1],
+            'asset_id', asset_id,
+            'side', order[2],
+            'price', order[3],
+            'size', order[4],
+            'remaining', 0,
+            'timestamp', now,
+            'reason', reason)
+
+        -- order id, account id, side, price, size, original size
+        table.insert(cancelled, {
+            order_id,
+            order[1],
+            order[2],
+            order[3],
+            tonumber(order[4]),
+            tonumber(order[5]),
+        })
+    end
+    redis.call('DEL', book)
+end
+
+return cancelled
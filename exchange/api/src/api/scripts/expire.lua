@@ -0,0 +1,50 @@
+-- Remove an asset's orders whose good 'til date has passed from its books, recording each in the
+-- asset's event stream
+
+local expiries, book_bid, book_offer, stop_bids, stop_offers, events = unpack(KEYS)
+local now, limit, asset_id, order_prefix = unpack(ARGV)
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+local books = {
+    bids = {book_bid, stop_bids},
+    offers = {book_offer, stop_offers},
+}
+
+local expired = {}
+local order_ids = redis.call('ZRANGE', expiries, '-inf', now, 'BYSCORE', 'LIMIT', 0, limit)
+for _, order_id in ipairs(order_ids) do
+    local order = redis.call('HMGET', order_prefix .. order_id,
+        'account_id', 'side', 'price', 'size', 'original_size')
+    if order[1] then
+        for _, book in ipairs(books[order[2]]) do
+            redis.call('ZREM', book, order_id)
+        end
+        redis.call('DEL', order_prefix .. order_id)
+
+        redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*',
+            'event', 'expired',
+            'order_id', order_id,
+            'account_id', order[1],
+            'asset_id', asset_id,
+            'side', order[2],
+            'price', order[3],
+            'size', order[4],
+            'remaining', 0,
+            'timestamp', now)
+
+        -- order id, account id, side, price, size, original size
+        table.insert(expired, {
+            order_id,
+            order[1],
+            order[2],
+            order[3],
+            tonumber(order[4]),
+            tonumber(order[5]),
+        })
+    end
+    redis.call('ZREM', expiries, order_id)
+end
+
+return expired
@@ -0,0 +1,12 @@
+-- Every order on an asset's given books and trigger books, with its fields
+
+local order_prefix = ARGV[1]
+
+local orders = {}
+for _, book in ipairs(KEYS) do
+    for _, order_id in ipairs(redis.call('ZRANGE', book, 0, -1)) do
+        table.insert(orders, {order_id, redis.call('HGETALL', order_prefix .. order_id)})
+    end
+end
+
+return orders
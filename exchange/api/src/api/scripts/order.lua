@@ -1,10 +1,16 @@
--- Match an order (market or limit)
+-- Match an order (market or limit), triggering any stop orders the resulting trades cross, and
+-- interrupting continuous trading if they would move the price too far too fast. Every change to
+-- an order is appended to the asset's event stream.
 
-local asset_id, book_bid, book_offer, account_id, order_id = unpack(KEYS)
-local side, size, order_type, price = unpack(ARGV)
+local book_bid, book_offer, last_trade, expiries, stop_bids, stop_offers, sequence, phase_key, bands_key,
+    volatility_key, reopening, events = unpack(KEYS)
+local asset_id, order_prefix, account_id, order_id, side, size, order_type, price_arg, time_in_force, post_only,
+    expires, display_size, self_trade_prevention, now, reserved_price = unpack(ARGV)
 local size = tonumber(size)
 local original_size = size
-local price = tonumber(price or 0)
+local price = tonumber(price_arg)
+local now = tonumber(now)
+local display_size = tonumber(display_size)
 
 local book_to_match
 local book_to_insert
@@ -20,59 +26,399 @@ elseif side == 'offers' then
     book_to_match = book_bid
     book_to_insert = book_offer
     score = price
-    lower, upper = -price, math.huge
+    lower, upper = -math.huge, -price
 end
 
+if order_type == 'market' then
+    lower, upper = -math.huge, math.huge
+    if side == 'bids' then
+        -- market buys only trade up to the price their funds are reserved at
+        upper = tonumber(reserved_price)
+    end
+end
 
-local completed_order_ids = {}
-local shares_filled = 0
-local dollar_volume_filled = 0
+local bands = redis.call('HMGET', bands_key, 'price_band', 'volatility_threshold', 'volatility_window',
+    'halt_duration')
+local price_band, volatility_threshold = tonumber(bands[1]), tonumber(bands[2])
+local reference = tonumber(redis.call('GET', last_trade))
 
-local candidates = redis.call('ZRANGE', book_to_match, lower, upper, 'BYSCORE', 'WITHSCORES')
-redis.log(redis.LOG_WARNING, book_to_match, lower, upper)
+-- never trade further from the last trade than the price band
+if price_band and reference then
+    if side == 'bids' then
+        upper = math.min(upper, reference * (1 + price_band / 10000))
+    else
+        upper = math.min(upper, -reference * (1 - price_band / 10000))
+    end
+end
 
-for i = 1, #candidates, 2 do 
-    local matching_order_id, matching_order_price = candidates[i], candidates[i+1]
-    local matching_size = tonumber(redis.call('HGET', matching_order_id, 'size'))
-    
-    if matching_size > size then
-        redis.call('HINCRBY', matching_order_id, 'size', -size)
-        size = 0
+-- Trades may only move the price so far from where it was at the start of the volatility window.
+-- A new window starts from the last trade once the previous one is over.
+local window_start
+if volatility_threshold and reference then
+    local window = redis.call('HMGET', volatility_key, 'price', 'since')
+    local since = tonumber(window[2])
+    if since and since + tonumber(bands[3]) * 1000 > now then
+        window_start = tonumber(window[1])
     else
-        table.insert(completed_order_ids, matching_order_id)
-        dollar_volume_filled = dollar_volume_filled + matching_order_price * matching_size
-        size = size - matching_size
+        redis.call('HSET', volatility_key, 'price', reference, 'since', now)
+        window_start = reference
     end
+end
+local function breaches(level_price)
+    return window_start ~= nil
+        and math.abs(tonumber(level_price) - window_start) * 10000 > volatility_threshold * window_start
+end
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+local resting_side = side == 'bids' and 'offers' or 'bids'
+
+-- Record a change to an order: what happened, to which order, how many units it affected and
+-- how many are left, along with any other fields given.
+local function emit(event, id, owner, order_side, order_price, event_size, remaining, ...)
+    local fields = {
+        'event', event,
+        'order_id', id,
+        'account_id', owner,
+        'asset_id', asset_id,
+        'side', order_side,
+        'price', order_price,
+        'size', event_size,
+        'remaining', remaining,
+        'timestamp', now,
+        ...
+    }
+    redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*', unpack(fields))
+end
+
+-- Key of the hash holding an order of this asset.
+local function key(id)
+    return order_prefix .. id
+end
+
+local function remove_order(book, id)
+    redis.call('ZREM', book, id)
+    redis.call('ZREM', expiries, id)
+    redis.call('DEL', key(id))
+end
+
+-- Collect resting orders that can match until they cover the order's size, dropping any that
+-- have expired along the way. Whole price levels are collected, since time priority within a
+-- level is decided by each order's sequence number.
+--
+-- Outside continuous trading, orders only accumulate on the book until it is uncrossed.
+local phase = redis.call('GET', phase_key) or 'continuous'
+local candidates = {}
+if phase == 'continuous' then
+    candidates = redis.call('ZRANGE', book_to_match, lower, upper, 'BYSCORE')
+end
+local levels = {}
+local expired = {}
+local available = 0
+local interrupted = false
+for i, matching_order_id in ipairs(candidates) do
+    local matching_order = redis.call('HMGET', key(matching_order_id),
+        'account_id', 'price', 'size', 'original_size', 'expires', 'display_size', 'visible', 'priority')
+    local matching_expires = tonumber(matching_order[5])
+    if matching_expires and matching_expires <= now then
+        -- order id, account id, price, size, original size
+        table.insert(expired, {
+            matching_order_id,
+            matching_order[1],
+            matching_order[2],
+            tonumber(matching_order[3]),
+            tonumber(matching_order[4]),
+        })
+        emit('expired', matching_order_id, matching_order[1], resting_side, matching_order[2],
+            matching_order[3], 0)
+        remove_order(book_to_match, matching_order_id)
+    else
+        local level = levels[#levels]
+        if level == nil or level.price ~= matching_order[2] then
+            if available >= size then
+                break
+            end
+            if post_only ~= '1' and breaches(matching_order[2]) then
+                -- stop short of the level and hand the book over to a reopening auction
+                interrupted = true
+                break
+            end
+            level = { price = matching_order[2], orders = {} }
+            table.insert(levels, level)
+        end
+        local matching_size = tonumber(matching_order[3])
+        -- the account's own orders never trade with this one
+        if matching_order[1] ~= account_id then
+            available = available + matching_size
+        end
+        table.insert(level.orders, {
+            id = matching_order_id,
+            account_id = matching_order[1],
+            size = matching_size,
+            original_size = tonumber(matching_order[4]),
+            display_size = tonumber(matching_order[6]),
+            visible = tonumber(matching_order[7]) or matching_size,
+            priority = tonumber(matching_order[8]) or 0,
+            expires = matching_expires or 0,
+            arrival = i,
+        })
+    end
+end
+
+-- Orders at a price trade in time priority, with orders sharing a sequence number (from before
+-- it was recorded) trading in the order they were collected.
+for _, level in ipairs(levels) do
+    table.sort(level.orders, function(a, b)
+        if a.priority ~= b.priority then
+            return a.priority < b.priority
+        end
+        return a.arrival < b.arrival
+    end)
+end
+
+-- Whether this order reaches one of the account's own resting orders before other accounts'
+-- orders cover its size. The hidden part of an iceberg order only trades after everything queued
+-- at its price when the order arrived.
+local function reaches_own()
+    local covered = 0
+    for _, level in ipairs(levels) do
+        local hidden = 0
+        for _, matching_order in ipairs(level.orders) do
+            if covered >= size then
+                return false
+            end
+            if matching_order.account_id == account_id then
+                return true
+            end
+            covered = covered + matching_order.visible
+            hidden = hidden + matching_order.size - matching_order.visible
+        end
+        covered = covered + hidden
+    end
+    return false
+end
+
+if interrupted then
+    redis.call('SET', phase_key, 'volatility_auction')
+    redis.call('SET', reopening, now + tonumber(bands[4]) * 1000)
+end
+
+if post_only == '1' and #levels > 0 then
+    return {0, {}, 'rejected', expired, {}, 0}
+end
+
+-- the order's time priority if it rests on the book, recorded with the rest of its details so
+-- that the book can be rebuilt from the event stream
+local priority = redis.call('INCR', sequence)
+local details = {'priority', priority, 'order_type', order_type, 'time_in_force', time_in_force}
+-- good 'til date and day orders leave the book when they expire
+local expiring = time_in_force == 'gtd' or time_in_force == 'day'
+if expiring then
+    table.insert(details, 'expires')
+    table.insert(details, expires)
+end
+if display_size > 0 then
+    table.insert(details, 'display_size')
+    table.insert(details, display_size)
+end
+emit('accepted', order_id, account_id, side, price_arg, size, size, unpack(details))
+-- Self-trade prevention other than cancelling the resting order would stop a fill or kill order
+-- short, or take units off it, if it reached one of the account's own orders, so those are killed
+-- too.
+if time_in_force == 'fok'
+    and (available < size or (self_trade_prevention ~= 'cancel_oldest' and reaches_own())) then
+    emit('cancelled', order_id, account_id, side, price_arg, size, 0, 'reason', 'fill_or_kill')
+    return {0, {}, 'cancelled', expired, {}, 0}
+end
 
-    if size == 0 then
+local fills = {}
+local fill_index = {}
+local touched = {}
+local last_price
+-- units of this order cancelled or decremented by self-trade prevention
+local prevented = 0
+local stopped = false
+
+-- Trade against a resting order, up to its visible size.
+local function trade(level, queue, matching_order)
+    local fill_size = math.min(size, matching_order.visible)
+
+    -- an iceberg order hit more than once is reported as a single fill at this price
+    local index = fill_index[matching_order.id]
+    if index then
+        fills[index][4] = fills[index][4] + fill_size
+    else
+        -- order id, account id, price, size, original size, size filled before, outcome, expiry
+        table.insert(fills, {
+            matching_order.id,
+            matching_order.account_id,
+            level.price,
+            fill_size,
+            matching_order.original_size,
+            matching_order.original_size - matching_order.size,
+            'trade',
+            matching_order.expires,
+        })
+        fill_index[matching_order.id] = #fills
+    end
+
+    matching_order.size = matching_order.size - fill_size
+    matching_order.visible = matching_order.visible - fill_size
+    size = size - fill_size
+    touched[matching_order.id] = matching_order
+    last_price = level.price
+
+    local refreshed = {}
+    if matching_order.size > 0 and matching_order.visible == 0 then
+        -- show the next slice of an iceberg order, behind everything else at its price
+        matching_order.visible = math.min(matching_order.display_size, matching_order.size)
+        matching_order.priority = redis.call('INCR', sequence)
+        table.insert(queue, matching_order)
+        refreshed = {'priority', matching_order.priority}
+    end
+
+    emit(matching_order.size == 0 and 'filled' or 'partially_filled', matching_order.id,
+        matching_order.account_id, resting_side, level.price, fill_size, matching_order.size,
+        'counterparty', order_id, unpack(refreshed))
+    emit(size == 0 and 'filled' or 'partially_filled', order_id, account_id, side, level.price,
+        fill_size, size, 'counterparty', matching_order.id)
+end
+
+-- Keep this order from trading against a resting order from the same account: cancel this
+-- order, the resting one, or both, or take the smaller size off both without trading.
+local function prevent(level, matching_order)
+    if self_trade_prevention == 'cancel_newest' then
+        stopped = true
+        return
+    end
+
+    local outcome, amount = 'cancel', matching_order.size
+    if self_trade_prevention == 'decrement' then
+        outcome, amount = 'decrement', math.min(size, matching_order.size)
+        size = size - amount
+        prevented = prevented + amount
+    end
+
+    -- order id, account id, price, size, original size, size filled before, outcome, expiry
+    table.insert(fills, {
+        matching_order.id,
+        matching_order.account_id,
+        level.price,
+        amount,
+        matching_order.original_size,
+        matching_order.original_size - matching_order.size,
+        outcome,
+        matching_order.expires,
+    })
+    matching_order.size = matching_order.size - amount
+    if matching_order.display_size then
+        matching_order.visible = math.min(matching_order.display_size, matching_order.size)
+    end
+    touched[matching_order.id] = matching_order
+
+    emit('cancelled', matching_order.id, matching_order.account_id, resting_side, level.price, amount,
+        matching_order.size, 'reason', 'self_trade')
+    if outcome == 'decrement' then
+        emit('cancelled', order_id, account_id, side, price_arg, amount, size, 'reason', 'self_trade')
+    end
+
+    if self_trade_prevention == 'cancel_both' then
+        stopped = true
+    end
+end
+
+for _, level in ipairs(levels) do
+    local queue = level.orders
+
+    local head = 1
+    while head <= #queue and size > 0 and not stopped do
+        local matching_order = queue[head]
+        head = head + 1
+
+        if matching_order.account_id == account_id then
+            prevent(level, matching_order)
+        else
+            trade(level, queue, matching_order)
+        end
+    end
+
+    if size == 0 or stopped then
         break
     end
 end
 
-local completed_orders = {}
-for _, order_id in ipairs(completed_order_ids) do
-    local account_id = redis.call('HGET', order_id, 'account_id')
-    redis.call('ZREM', account_id, order_id)
-    table.insert(completed_orders, order_id)
-    table.insert(completed_orders, redis.call('HGETALL', order_id))
+for matching_order_id, matching_order in pairs(touched) do
+    if matching_order.size == 0 then
+        remove_order(book_to_match, matching_order_id)
+    elseif matching_order.display_size then
+        redis.call('HSET', key(matching_order_id),
+            'size', matching_order.size,
+            'visible', matching_order.visible,
+            'priority', matching_order.priority
+        )
+    else
+        redis.call('HSET', key(matching_order_id), 'size', matching_order.size)
+    end
+end
+
+-- Take stop orders whose trigger the last trade crossed out of the trigger books, to be
+-- submitted as market or limit orders once this order has settled.
+local triggered = {}
+local function trigger(stop_book, lower, upper)
+    for _, stop_id in ipairs(redis.call('ZRANGE', stop_book, lower, upper, 'BYSCORE')) do
+        local stop = redis.call('HMGET', key(stop_id),
+            'account_id', 'side', 'order_type', 'price', 'size', 'time_in_force', 'expires',
+            'display_size')
+        -- order id, account id, side, order type, price, size, time in force, expiry, display size
+        table.insert(triggered, {stop_id, unpack(stop)})
+        remove_order(stop_book, stop_id)
+    end
 end
-if #completed_order_ids > 0 then
-    redis.call('ZREM', book_to_match, unpack(completed_order_ids))
-    redis.call('DEL', unpack(completed_order_ids))
+if last_price then
+    redis.call('SET', last_trade, last_price)
+    -- buy stops trigger at or above their trigger price, sell stops at or below
+    trigger(stop_bids, '-inf', last_price)
+    trigger(stop_offers, last_price, '+inf')
 end
 
-if order_type == 'limit' and size > 0 then
+local status
+if stopped then
+    emit('cancelled', order_id, account_id, side, price_arg, size, 0, 'reason', 'self_trade')
+    prevented = prevented + size
+    status = 'cancelled'
+elseif size == 0 then
+    -- an order reduced to nothing by self-trade prevention was not filled
+    status = prevented > 0 and 'cancelled' or 'filled'
+elseif order_type == 'limit' and (time_in_force == 'gtc' or expiring) then
     -- add remaining quantity to the order book_to_match
     redis.call('ZADD', book_to_insert, score, order_id)
-    redis.call('HSET', order_id, 
+    redis.call('HSET', key(order_id),
         'account_id', account_id,
-        'asset_id', asset_id, 
-        'price', price,
+        'asset_id', asset_id,
+        'side', side,
+        'price', price_arg,
         'size', size,
         'original_size', original_size,
-        'dollar_volume_filled', dollar_volume_filled
+        'priority', priority
     )
-    redis.call('ZADD', account_id, 0, order_id)
+    if display_size > 0 then
+        redis.call('HSET', key(order_id),
+            'display_size', display_size,
+            'visible', math.min(display_size, size)
+        )
+    end
+    if expiring then
+        redis.call('HSET', key(order_id), 'expires', expires)
+        redis.call('ZADD', expiries, expires, order_id)
+    end
+    status = 'resting'
+else
+    -- the rest of market, immediate or cancel and fill or kill orders doesn't stay on the book
+    emit('cancelled', order_id, account_id, side, price_arg, size, 0, 'reason', 'unfilled')
+    status = 'cancelled'
 end
 
-return {original_size - size, dollar_volume_filled, completed_orders}
+-- prices are stored and returned as the strings they were given as, since Lua numbers
+-- would be formatted with an exponent once large enough
+return {original_size - size, fills, status, expired, triggered, prevented}
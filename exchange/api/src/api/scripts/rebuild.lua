@@ -0,0 +1,72 @@
+-- Restore an asset's orders to its books and trigger books, unless any of them still exist
+
+local book_bid, book_offer, stop_bids, stop_offers, sequence, expiries = unpack(KEYS)
+local asset_id, order_prefix = ARGV[1], ARGV[2]
+
+if redis.call('EXISTS', book_bid, book_offer, stop_bids, stop_offers) > 0 then
+    return false
+end
+
+-- order id, account id, side, price, size, original size, priority, expiry, display size,
+-- trigger, order type, time in force
+local FIELDS = 12
+
+local restored, last_priority = 0, 0
+for i = 3, #ARGV, FIELDS do
+    local order_id, account_id, side, price, size, original_size, priority, expires, display_size, trigger,
+        order_type, time_in_force = unpack(ARGV, i, i + FIELDS - 1)
+
+    if trigger ~= '0' then
+        redis.call('ZADD', side == 'bids' and stop_bids or stop_offers, trigger, order_id)
+        redis.call('HSET', order_prefix .. order_id,
+            'account_id', account_id,
+            'asset_id', asset_id,
+            'side', side,
+            'trigger', trigger,
+            'order_type', order_type,
+            'price', price,
+            'size', size,
+            'original_size', original_size,
+            'time_in_force', time_in_force,
+            'expires', expires,
+            'display_size', display_size
+        )
+    else
+        if side == 'bids' then
+            redis.call('ZADD', book_bid, -tonumber(price), order_id)
+        else
+            redis.call('ZADD', book_offer, price, order_id)
+        end
+        redis.call('HSET', order_prefix .. order_id,
+            'account_id', account_id,
+            'asset_id', asset_id,
+            'side', side,
+            'price', price,
+            'size', size,
+            'original_size', original_size,
+            'priority', priority
+        )
+        if tonumber(display_size) > 0 then
+            redis.call('HSET', order_prefix .. order_id,
+                'display_size', display_size,
+                'visible', math.min(tonumber(display_size), tonumber(size))
+            )
+        end
+        if tonumber(expires) > 0 then
+            redis.call('HSET', order_prefix .. order_id, 'expires', expires)
+        end
+        last_priority = math.max(last_priority, tonumber(priority))
+    end
+
+    if tonumber(expires) > 0 then
+        redis.call('ZADD', expiries, expires, order_id)
+    end
+    restored = restored + 1
+end
+
+-- orders placed from now on go behind the restored ones
+if last_priority > (tonumber(redis.call('GET', sequence)) or 0) then
+    redis.call('SET', sequence, last_priority)
+end
+
+return restored
@@ -0,0 +1,42 @@
+-- Take an asset's orders off its books and trigger books, recording each in the asset's event
+-- stream. Orders that have changed size since they were read are left alone.
+
+local book_bid, book_offer, stop_bids, stop_offers, expiries, events = unpack(KEYS)
+local now, reason, asset_id, order_prefix = ARGV[1], ARGV[2], ARGV[3], ARGV[4]
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+local books = {
+    bids = {book_bid, stop_bids},
+    offers = {book_offer, stop_offers},
+}
+
+local removed = {}
+for i = 5, #ARGV, 2 do
+    local order_id, size = ARGV[i], ARGV[i + 1]
+    local order = redis.call('HMGET', order_prefix .. order_id, 'account_id', 'side', 'price', 'size')
+    if order[1] and order[4] == size then
+        for _, book in ipairs(books[order[2]]) do
+            redis.call('ZREM', book, order_id)
+        end
+        redis.call('ZREM', expiries, order_id)
+        redis.call('DEL', order_prefix .. order_id)
+
+        redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*',
+            'event', 'cancelled',
+            'order_id', order_id,
+            'account_id', order[1],
+            'asset_id', asset_id,
+            'side', order[2],
+            'price', order[3],
+            'size', order[4],
+            'remaining', 0,
+            'timestamp', now,
+            'reason', reason)
+
+        table.insert(removed, order_id)
+    end
+end
+
+return removed
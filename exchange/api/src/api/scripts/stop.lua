@@ -0,0 +1,53 @@
+-- Place a stop order in its asset's trigger book, unless the last trade already crossed its trigger.
+-- Placed orders are recorded in the asset's event stream.
+
+local stop_book, order_key, last_trade, expiries, events = unpack(KEYS)
+local order_id, account_id, side, trigger, asset_id, size, order_type, price, time_in_force, post_only, expires,
+    display_size, now = unpack(ARGV)
+
+-- streams are trimmed to roughly this many events
+local STREAM_LENGTH = 1000000
+
+local last = tonumber(redis.call('GET', last_trade))
+if last then
+    -- buy stops trigger at or above their trigger price, sell stops at or below
+    if (side == 'bids' and last >= tonumber(trigger)) or (side == 'offers' and last <= tonumber(trigger)) then
+        return 'triggered'
+    end
+end
+
+redis.call('ZADD', stop_book, trigger, order_id)
+redis.call('HSET', order_key,
+    'account_id', account_id,
+    'asset_id', asset_id,
+    'side', side,
+    'trigger', trigger,
+    'order_type', order_type,
+    'price', price,
+    'size', size,
+    'original_size', size,
+    'time_in_force', time_in_force,
+    'expires', expires,
+    'display_size', display_size
+)
+if time_in_force == 'gtd' or time_in_force == 'day' then
+    redis.call('ZADD', expiries, expires, order_id)
+end
+
+redis.call('XADD', events, 'MAXLEN', '~', STREAM_LENGTH, '*',
+    'event', 'accepted',
+    'order_id', order_id,
+    'account_id', account_id,
+    'asset_id', asset_id,
+    'side', side,
+    'price', price,
+    'size', size,
+    'remaining', size,
+    'timestamp', now,
+    'trigger', trigger,
+    'order_type', order_type,
+    'time_in_force', time_in_force,
+    'expires', expires,
+    'display_size', display_size)
+
+return 'pending'
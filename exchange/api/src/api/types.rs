@@ -5,6 +5,7 @@ use std::{
     str::FromStr,
 };
 
+use chrono::{DateTime, Utc};
 use diesel::{
     backend::Backend,
     deserialize::{self, FromSql, FromSqlRow},
@@ -13,7 +14,10 @@ use diesel::{
     sql_types::{Binary, Text},
 };
 use email_address::EmailAddress;
-use rocket::request::FromParam;
+use rocket::{
+    form::{self, FromFormField, ValueField},
+    request::FromParam,
+};
 use rocket_db_pools::deadpool_redis::redis::{self, FromRedisValue, ToRedisArgs};
 use schemars::{
     gen::SchemaGenerator,
@@ -23,6 +27,8 @@ use schemars::{
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 
+pub use protocol::price::{Notional, Price};
+
 #[derive(Debug, Clone, Deserialize, AsExpression, FromSqlRow)]
 #[serde(transparent)]
 #[diesel(sql_type = Text)]
@@ -217,3 +223,16 @@ impl ToRedisArgs for Uuid {
         out.write_arg(self.0.as_bytes())
     }
 }
+
+/// An RFC 3339 date & time, usable as a query parameter.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, Eq, PartialEq, JsonSchema)]
+#[serde(transparent)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl<'v> FromFormField<'v> for Timestamp {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        DateTime::parse_from_rfc3339(field.value)
+            .map(|timestamp| Self(timestamp.with_timezone(&Utc)))
+            .map_err(|e| form::Error::validation(format!("invalid timestamp: {e}")).into())
+    }
+}
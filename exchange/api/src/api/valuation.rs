@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    prelude::Insertable, BoolExpressionMethods, ExpressionMethods, QueryResult, Queryable,
+    Selectable,
+};
+use itertools::Itertools;
+use rocket::{get, http::Status, serde::json::Json};
+use rocket_db_pools::{
+    deadpool_redis::redis,
+    diesel::{
+        prelude::{QueryDsl, RunQueryDsl},
+        AsyncPgConnection,
+    },
+    Connection,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{
+    accounts::{self, UserIdCheck},
+    keys,
+    schema::fills::dsl,
+    types::{Notional, Price},
+};
+use crate::{Accounting, Meta, Orders};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Selectable, Insertable, JsonSchema)]
+#[diesel(table_name = super::schema::fills)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Fill {
+    /// A unique identifier for this fill.
+    pub id: uuid::Uuid,
+    pub asset_id: i32,
+    pub buy_order_id: uuid::Uuid,
+    pub sell_order_id: uuid::Uuid,
+    pub buyer_id: uuid::Uuid,
+    pub seller_id: uuid::Uuid,
+    pub price: Price,
+    pub size: i32,
+    /// Date & time of the fill in RFC 3339 format.
+    pub created: DateTime<Utc>,
+}
+
+/// Add fills to the fill history, skipping any that were already recorded.
+pub async fn record_fills(meta: &mut AsyncPgConnection, fills: &[Fill]) -> QueryResult<usize> {
+    diesel::insert_into(dsl::fills)
+        .values(fills)
+        .on_conflict_do_nothing()
+        .execute(meta)
+        .await
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Lot {
+    /// Units in the lot, negative for a short lot.
+    size: i64,
+    price: Price,
+}
+
+/// A position in one asset, as lots matched first in, first out.
+#[derive(Debug, Default)]
+pub struct Lots {
+    open: VecDeque<Lot>,
+    realised: Notional,
+}
+
+impl Lots {
+    /// Apply a fill of `size` units at `price`, where sells have a negative size. `None` if the
+    /// realised profit or loss overflows.
+    pub fn apply(&mut self, mut size: i64, price: Price) -> Option<()> {
+        while size != 0 {
+            match self.open.front_mut() {
+                Some(lot) if lot.size.signum() != size.signum() => {
+                    let closed = size.abs().min(lot.size.abs()) * lot.size.signum();
+                    self.realised = (price * closed)
+                        .checked_sub(lot.price * closed)
+                        .and_then(|gain| self.realised.checked_add(gain))?;
+                    lot.size -= closed;
+                    size += closed;
+                    if lot.size == 0 {
+                        self.open.pop_front();
+                    }
+                }
+                _ => {
+                    self.open.push_back(Lot { size, price });
+                    size = 0;
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Total cost of the open lots, or `None` if it overflows.
+    pub fn cost_basis(&self) -> Option<Notional> {
+        Notional::checked_sum(self.open.iter().map(|lot| lot.price * lot.size))
+    }
+
+    /// Profit or loss from closed lots.
+    pub fn realised(&self) -> Notional {
+        self.realised
+    }
+}
+
+/// Price to value a position in an asset at: the last trade price, or the mid price if the asset
+/// hasn't traded.
+pub async fn mark<C: redis::aio::ConnectionLike>(
+    orders: &mut C,
+    asset_id: i32,
+) -> redis::RedisResult<Option<Price>> {
+    let script = redis::Script::new(
+        r"
+        local last = redis.call('GET', KEYS[1])
+        local best_bid = redis.call('ZRANGE', KEYS[2], 0, 0, 'WITHSCORES')[2]
+        local best_offer = redis.call('ZRANGE', KEYS[3], 0, 0, 'WITHSCORES')[2]
+        return {last, best_bid or false, best_offer or false}
+    ",
+    );
+
+    let (last, best_bid, best_offer): (Option<Price>, Option<Price>, Option<Price>) = script
+        .prepare_invoke()
+        .key(keys::asset(asset_id, "last"))
+        .key(keys::asset(asset_id, "bids"))
+        .key(keys::asset(asset_id, "offers"))
+        .invoke_async(orders)
+        .await?;
+
+    // bids are scored by their negated price
+    Ok(last.or_else(|| {
+        best_bid.zip(best_offer).and_then(|(best_bid, best_offer)| {
+            Price::ZERO
+                .checked_sub(best_bid)
+                .map(|best_bid| best_bid.midpoint(best_offer))
+        })
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AssetPnl {
+    pub asset_id: i32,
+    /// Units of the asset held by the account.
+    pub position: i128,
+    /// Total cost of the lots making up the position.
+    pub cost_basis: Notional,
+    /// Price the position is valued at, if the asset has traded or has bids and offers.
+    pub mark: Option<Price>,
+    /// Profit or loss locked in by selling lots.
+    pub realised: Notional,
+    /// Profit or loss of the position if it were sold at the mark price.
+    pub unrealised: Option<Notional>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Pnl {
+    pub realised: Notional,
+    /// Unrealised profit or loss, summed over assets with a mark price.
+    pub unrealised: Notional,
+    pub assets: Vec<AssetPnl>,
+}
+
+/// # Get PnL
+///
+/// Show realised and unrealised profit or loss for each asset traded by an account.
+#[openapi(tag = "Accounts")]
+#[get("/accounts/<account_id>/pnl")]
+pub async fn get_pnl_for_account(
+    _check: UserIdCheck,
+    account_id: uuid::Uuid,
+    mut meta: Connection<Meta>,
+    mut orders: Connection<Orders>,
+    accounting: Connection<Accounting>,
+) -> Result<Json<Pnl>, Status> {
+    let positions: BTreeMap<i32, i128> =
+        accounts::equity_holdings(&mut meta, &accounting, account_id)
+            .await?
+            .into_iter()
+            .map(|holdings| (holdings.asset_id, holdings.amount))
+            .collect();
+
+    let fills: Vec<Fill> = dsl::fills
+        .filter(
+            dsl::buyer_id
+                .eq(account_id)
+                .or(dsl::seller_id.eq(account_id)),
+        )
+        .order((dsl::created.asc(), dsl::id.asc()))
+        .load(&mut meta)
+        .await
+        .map_err(|e| {
+            error!("error fetching fills: {e}");
+            Status::InternalServerError
+        })?;
+
+    let overflowed = || {
+        error!("profit or loss of account {account_id} overflowed");
+        Status::InternalServerError
+    };
+
+    let mut lots: BTreeMap<i32, Lots> = BTreeMap::new();
+    for fill in fills {
+        let lots = lots.entry(fill.asset_id).or_default();
+        if fill.buyer_id == account_id {
+            lots.apply(fill.size as i64, fill.price)
+                .ok_or_else(overflowed)?;
+        }
+        if fill.seller_id == account_id {
+            lots.apply(-fill.size as i64, fill.price)
+                .ok_or_else(overflowed)?;
+        }
+    }
+
+    let asset_ids = positions
+        .keys()
+        .chain(lots.keys())
+        .copied()
+        .unique()
+        .sorted()
+        .collect_vec();
+
+    let mut assets = Vec::with_capacity(asset_ids.len());
+    for asset_id in asset_ids {
+        let mark = mark(orders.as_mut(), asset_id).await.map_err(|e| {
+            error!("error fetching mark price: {e}");
+            Status::InternalServerError
+        })?;
+        let position = positions.get(&asset_id).copied().unwrap_or_default();
+        let (cost_basis, realised) = match lots.get(&asset_id) {
+            Some(lots) => (lots.cost_basis().ok_or_else(overflowed)?, lots.realised()),
+            None => Default::default(),
+        };
+
+        assets.push(AssetPnl {
+            asset_id,
+            position,
+            cost_basis,
+            mark,
+            realised,
+            unrealised: mark
+                .and_then(|mark| mark.checked_mul(position))
+                .and_then(|value| value.checked_sub(cost_basis)),
+        });
+    }
+
+    Ok(Json(Pnl {
+        realised: Notional::checked_sum(assets.iter().map(|asset| asset.realised))
+            .ok_or_else(overflowed)?,
+        unrealised: Notional::checked_sum(assets.iter().filter_map(|asset| asset.unrealised))
+            .ok_or_else(overflowed)?,
+        assets,
+    }))
+}
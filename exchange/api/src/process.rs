@@ -1,10 +1,582 @@
-use rocket::{Build, Rocket};
-use rocket_db_pools::Database;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
 
-use crate::{Accounting, Orders};
+use rocket::{
+    fairing::AdHoc, futures::FutureExt, http::Status, serde::json, Build, Orbit, Rocket, Shutdown,
+};
+use rocket_db_pools::{
+    deadpool_redis::{
+        self,
+        redis::{self, FromRedisValue},
+    },
+    diesel::AsyncPgConnection,
+    Database,
+};
+use tigerbeetle_unofficial as tb;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::{
+    api::{
+        accounts, bands, competitions,
+        events::{self, OrderEvent, OrderEventKind, EVENTS_KEY},
+        expiry, history, keys,
+        ledger::{self, DEAD_SETTLEMENTS_KEY, SETTLEMENTS_KEY},
+        reconciliation,
+    },
+    Accounting, Meta, Orders,
+};
+
+/// The most stream entries read at a time.
+const CONSUMER_BATCH_SIZE: usize = 100;
+
+/// How long a consumer waits, in milliseconds, after finding no new entries in any of its streams.
+const CONSUMER_IDLE_MILLIS: u64 = 1000;
 
 pub fn rocket() -> Rocket<Build> {
     rocket::build()
+        .attach(Meta::init())
         .attach(Accounting::init())
         .attach(Orders::init())
+        .manage(Workers::default())
+        .attach(AdHoc::on_liftoff("workers", |rocket| {
+            Box::pin(spawn_workers(rocket))
+        }))
+        .attach(AdHoc::on_shutdown("workers", |rocket| {
+            Box::pin(join_workers(rocket))
+        }))
+}
+
+/// Background work run on a fixed interval. Every job can safely be run again after failing
+/// part of the way through.
+#[derive(Debug, Clone, Copy)]
+enum Job {
+    /// Record the standings of running competitions.
+    LeaderboardSnapshots,
+    /// Remove expired good 'til date and day orders from the book.
+    OrderExpiry,
+    /// Resume continuous trading in assets whose volatility auction is over.
+    Reopening,
+    /// Create funds accounts missing from the ledger for registered users.
+    AccountReconciliation,
+    /// Report orders on the book and reservations in the ledger that don't match, repairing
+    /// them if asked to.
+    BookReconciliation { repair: bool },
+}
+
+/// Shortest time a worker holds a job's lock, so that a worker that dies while running a job
+/// only holds it up for a while. Runs that outlast their lock can overlap with the next one.
+const MIN_JOB_LEASE: Duration = Duration::from_secs(30);
+
+/// Release a job's lock if this worker still holds it.
+const RELEASE_JOB_LOCK: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+impl Job {
+    fn name(self) -> &'static str {
+        match self {
+            Self::LeaderboardSnapshots => "leaderboard_snapshots",
+            Self::OrderExpiry => "order_expiry",
+            Self::Reopening => "reopening",
+            Self::AccountReconciliation => "account_reconciliation",
+            Self::BookReconciliation { .. } => "book_reconciliation",
+        }
+    }
+
+    /// Config key for the job's interval in seconds, and the interval used if it isn't set.
+    fn interval(self) -> (&'static str, u64) {
+        match self {
+            Self::LeaderboardSnapshots => ("leaderboard.snapshot_interval", 60),
+            Self::OrderExpiry => ("orders.expiry_interval", 1),
+            Self::Reopening => ("orders.reopening_interval", 1),
+            Self::AccountReconciliation => ("accounts.reconciliation_interval", 300),
+            Self::BookReconciliation { .. } => ("orders.reconciliation_interval", 600),
+        }
+    }
+
+    async fn run(
+        self,
+        meta: &mut AsyncPgConnection,
+        orders: &mut deadpool_redis::Connection,
+        accounting: &tb::Client,
+    ) -> Result<(), Status> {
+        match self {
+            Self::LeaderboardSnapshots => {
+                competitions::take_snapshots(meta, orders, accounting).await
+            }
+            Self::OrderExpiry => expiry::expire_orders(meta, orders, accounting)
+                .await
+                .map(|_| ()),
+            Self::Reopening => bands::reopen_assets(meta, orders, accounting)
+                .await
+                .map(|_| ()),
+            Self::AccountReconciliation => accounts::reconcile_accounts(meta, accounting)
+                .await
+                .map(|_| ()),
+            Self::BookReconciliation { repair } => {
+                reconciliation::reconcile(meta, orders, accounting, repair)
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+}
+
+/// Redis streams read through a consumer group. Entries of each stream are handled in order and
+/// acknowledged once handled, so every entry is handled at least once, even across restarts.
+#[derive(Debug, Clone, Copy)]
+enum Consumer {
+    /// Retry transfers that failed when first created, setting aside any the ledger rejects.
+    Settlement,
+    /// Keep the order table up to date with the order event streams.
+    History,
+    /// Keep each account's index of open orders up to date with the order event streams.
+    AccountIndex,
+    /// Copy every asset's order events to the stream of all of them.
+    GlobalFeed,
+}
+
+impl Consumer {
+    /// Name of the consumer group reading the streams.
+    fn group(self) -> &'static str {
+        match self {
+            Self::Settlement => "settlement",
+            Self::History => "history",
+            Self::AccountIndex => "account_index",
+            Self::GlobalFeed => "global_feed",
+        }
+    }
+
+    /// Keys of the streams to read: the settlement stream, or the event stream of every asset.
+    async fn streams(self, meta: &mut AsyncPgConnection) -> Result<Vec<String>, Status> {
+        match self {
+            Self::Settlement => Ok(vec![SETTLEMENTS_KEY.to_owned()]),
+            Self::History | Self::AccountIndex | Self::GlobalFeed => {
+                Ok(reconciliation::asset_ids(meta)
+                    .await?
+                    .into_iter()
+                    .map(|asset_id| keys::asset(asset_id, "events"))
+                    .collect())
+            }
+        }
+    }
+
+    /// Whether the group is the only one reading its streams, so that entries can be deleted
+    /// once handled. Streams read by several groups are trimmed by length instead.
+    fn owns_stream(self) -> bool {
+        match self {
+            Self::Settlement => true,
+            Self::History | Self::AccountIndex | Self::GlobalFeed => false,
+        }
+    }
+
+    /// Handle a stream entry. Entries are delivered again until handled successfully, so this
+    /// must be idempotent.
+    async fn handle(
+        self,
+        meta: &mut AsyncPgConnection,
+        orders: &mut deadpool_redis::Connection,
+        accounting: &tb::Client,
+        id: &str,
+        fields: &redis::Value,
+    ) -> Result<(), Status> {
+        match self {
+            Self::Settlement => {
+                let fields = HashMap::<String, String>::from_redis_value(fields).ok();
+                let Some((raw, transfers)) = fields.as_ref().and_then(|fields| {
+                    let raw = fields.get("transfers")?;
+                    Some((raw, json::from_str(raw).ok()?))
+                }) else {
+                    // retrying won't help, so the entry is dropped
+                    error!("malformed settlement {id}");
+                    return Ok(());
+                };
+
+                match ledger::retry_transfers(meta, accounting, transfers).await {
+                    Ok(()) => Ok(()),
+                    // Retrying won't help either, so the settlement is set aside for
+                    // investigation instead of holding up the ones behind it.
+                    Err(e) if e.is_rejection() => {
+                        error!(
+                            "settlement {id} rejected, moving it to {DEAD_SETTLEMENTS_KEY}: {e}"
+                        );
+                        ledger::dead_letter(orders, id, raw, &e.to_string())
+                            .await
+                            .map_err(|e| {
+                                error!("error setting aside settlement {id}: {e}");
+                                Status::InternalServerError
+                            })
+                    }
+                    Err(e) => {
+                        error!("error retrying settlement {id}: {e}");
+                        Err(Status::InternalServerError)
+                    }
+                }
+            }
+            Self::History => {
+                let Some(event) = order_event(id, fields) else {
+                    return Ok(());
+                };
+
+                history::record(meta, &event).await.map_err(|e| {
+                    error!("error recording order event {id}: {e}");
+                    Status::InternalServerError
+                })
+            }
+            Self::AccountIndex => {
+                let Some(event) = order_event(id, fields) else {
+                    return Ok(());
+                };
+
+                // orders are indexed when accepted and unindexed once nothing is left of them
+                let key = keys::account_orders(event.account_id.0);
+                let res = match (event.event, event.remaining) {
+                    (_, 0) => {
+                        redis::cmd("ZREM")
+                            .arg(key)
+                            .arg(event.order_id)
+                            .query_async::<_, ()>(orders)
+                            .await
+                    }
+                    (OrderEventKind::Accepted, _) => {
+                        redis::cmd("ZADD")
+                            .arg(key)
+                            .arg(event.asset_id)
+                            .arg(event.order_id)
+                            .query_async::<_, ()>(orders)
+                            .await
+                    }
+                    _ => return Ok(()),
+                };
+
+                res.map_err(|e| {
+                    error!("error indexing order event {id}: {e}");
+                    Status::InternalServerError
+                })
+            }
+            // copies of events handled again are told apart by their source ID
+            Self::GlobalFeed => events::publish(orders, id, fields).await.map_err(|e| {
+                error!("error copying order event {id} to {EVENTS_KEY}: {e}");
+                Status::InternalServerError
+            }),
+        }
+    }
+}
+
+/// Parse an order event, logging it if it is malformed, since retrying won't help.
+fn order_event(id: &str, fields: &redis::Value) -> Option<OrderEvent> {
+    OrderEvent::from_redis_value(fields)
+        .map_err(|e| error!("malformed order event {id}: {e}"))
+        .ok()
+}
+
+/// Running jobs and consumers, waited on at shutdown so that they can finish what they are
+/// doing.
+#[derive(Debug, Default)]
+struct Workers(Mutex<Vec<JoinHandle<()>>>);
+
+async fn spawn_workers(rocket: &Rocket<Orbit>) {
+    let (Some(meta), Some(orders), Some(accounting), Some(workers)) = (
+        Meta::fetch(rocket),
+        Orders::fetch(rocket),
+        Accounting::fetch(rocket),
+        rocket.state::<Workers>(),
+    ) else {
+        error!("databases not initialized, not running workers");
+        return;
+    };
+    let mut handles = workers.0.lock().unwrap();
+
+    let repair = rocket
+        .figment()
+        .extract_inner("orders.reconciliation_repair")
+        .unwrap_or(false);
+
+    // intervals tick immediately, so every job also runs once at startup. Every worker process
+    // ticks, but only the one that takes a job's lock runs it.
+    for job in [
+        Job::LeaderboardSnapshots,
+        Job::OrderExpiry,
+        Job::Reopening,
+        Job::AccountReconciliation,
+        Job::BookReconciliation { repair },
+    ] {
+        let (key, default) = job.interval();
+        let interval = rocket
+            .figment()
+            .extract_inner(key)
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(default));
+        let (meta, orders, accounting) =
+            (meta.0 .0.clone(), orders.0.clone(), accounting.0.clone());
+        let mut shutdown = rocket.shutdown();
+        let lock = keys::job_lock(job.name());
+        let lease = interval.max(MIN_JOB_LEASE);
+        let token = uuid::Uuid::now_v7().to_string();
+
+        handles.push(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = ticker.tick() => {}
+                }
+
+                let (mut meta, mut orders) = match (meta.get().await, orders.get().await) {
+                    (Ok(meta), Ok(orders)) => (meta, orders),
+                    (Err(e), _) => {
+                        error!("error connecting to postgres: {e}");
+                        continue;
+                    }
+                    (_, Err(e)) => {
+                        error!("error connecting to redis: {e}");
+                        continue;
+                    }
+                };
+
+                let locked: Option<String> = match redis::cmd("SET")
+                    .arg(&lock)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(lease.as_millis() as u64)
+                    .query_async(&mut orders)
+                    .await
+                {
+                    Ok(locked) => locked,
+                    Err(e) => {
+                        error!("error locking {job:?} job: {e}");
+                        continue;
+                    }
+                };
+                // another worker is running the job
+                if locked.is_none() {
+                    continue;
+                }
+
+                if let Err(status) = job.run(&mut meta, &mut orders, &accounting).await {
+                    error!("error running {job:?} job: {status}");
+                }
+
+                if let Err(e) = redis::Script::new(RELEASE_JOB_LOCK)
+                    .key(&lock)
+                    .arg(&token)
+                    .invoke_async::<_, i32>(&mut orders)
+                    .await
+                {
+                    error!("error unlocking {job:?} job: {e}");
+                }
+            }
+        }));
+    }
+
+    // consumers with the same name share their pending entries, so each worker process needs
+    // its own name
+    let name: String = rocket
+        .figment()
+        .extract_inner("worker.name")
+        .unwrap_or_else(|_| "worker".to_owned());
+    let retry_interval = rocket
+        .figment()
+        .extract_inner("worker.retry_interval")
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(5));
+    for consumer in [
+        Consumer::Settlement,
+        Consumer::History,
+        Consumer::AccountIndex,
+        Consumer::GlobalFeed,
+    ] {
+        let (meta, orders, accounting) =
+            (meta.0 .0.clone(), orders.0.clone(), accounting.0.clone());
+        let (name, shutdown) = (name.clone(), rocket.shutdown());
+
+        handles.push(tokio::spawn(async move {
+            let group = consumer.group();
+            // Streams the group has been created on, and whether entries read from them before a
+            // restart but never acknowledged are still to be handled first.
+            let mut backlogs: HashMap<String, bool> = HashMap::new();
+
+            while !stopping(&shutdown) {
+                let (mut meta, mut orders) = match (meta.get().await, orders.get().await) {
+                    (Ok(meta), Ok(orders)) => (meta, orders),
+                    (Err(e), _) => {
+                        error!("error connecting to postgres: {e}");
+                        tokio::time::sleep(retry_interval).await;
+                        continue;
+                    }
+                    (_, Err(e)) => {
+                        error!("error connecting to redis: {e}");
+                        tokio::time::sleep(retry_interval).await;
+                        continue;
+                    }
+                };
+
+                let streams = match consumer.streams(&mut meta).await {
+                    Ok(streams) => streams,
+                    Err(status) => {
+                        error!("error listing streams for {group}: {status}");
+                        tokio::time::sleep(retry_interval).await;
+                        continue;
+                    }
+                };
+
+                let (mut idle, mut failed) = (true, false);
+                for stream in streams {
+                    if !backlogs.contains_key(&stream) {
+                        if let Err(e) = create_group(&mut orders, &stream, group).await {
+                            error!("error creating consumer group {group} on {stream}: {e}");
+                            failed = true;
+                            continue;
+                        }
+                        backlogs.insert(stream.clone(), true);
+                    }
+
+                    let backlog = backlogs[&stream];
+                    let entries =
+                        match read_group(&mut orders, &stream, group, &name, backlog).await {
+                            Ok(entries) => entries,
+                            Err(e) => {
+                                error!("error reading {stream}: {e}");
+                                failed = true;
+                                continue;
+                            }
+                        };
+                    if entries.is_empty() {
+                        backlogs.insert(stream, false);
+                        continue;
+                    }
+                    idle = false;
+
+                    for (id, fields) in entries {
+                        // entries deleted while pending come back without fields
+                        if !matches!(fields, redis::Value::Nil)
+                            && consumer
+                                .handle(&mut meta, &mut orders, &accounting, &id, &fields)
+                                .await
+                                .is_err()
+                        {
+                            // later entries wait, so that entries are handled in order
+                            backlogs.insert(stream.clone(), true);
+                            failed = true;
+                            break;
+                        }
+
+                        let delete = consumer.owns_stream();
+                        if let Err(e) = acknowledge(&mut orders, &stream, group, &id, delete).await
+                        {
+                            // handled again before anything after it
+                            error!("error acknowledging {id} on {stream}: {e}");
+                            backlogs.insert(stream.clone(), true);
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+
+                if failed {
+                    // unacknowledged entries are read again from the backlog after a pause
+                    tokio::time::sleep(retry_interval).await;
+                } else if idle {
+                    tokio::time::sleep(Duration::from_millis(CONSUMER_IDLE_MILLIS)).await;
+                }
+            }
+        }));
+    }
+}
+
+/// Whether the worker has been asked to shut down.
+fn stopping(shutdown: &Shutdown) -> bool {
+    shutdown.clone().now_or_never().is_some()
+}
+
+async fn join_workers(rocket: &Rocket<Orbit>) {
+    let Some(workers) = rocket.state::<Workers>() else {
+        return;
+    };
+
+    let handles = std::mem::take(&mut *workers.0.lock().unwrap());
+    info!("waiting for {} workers to finish", handles.len());
+    for handle in handles {
+        if let Err(e) = handle.await {
+            error!("worker panicked: {e}");
+        }
+    }
+}
+
+/// Create a consumer group reading a stream from its start, and the stream if it doesn't exist, so
+/// that entries added before the worker first saw the stream are read too.
+async fn create_group(
+    orders: &mut deadpool_redis::Connection,
+    stream: &str,
+    group: &str,
+) -> redis::RedisResult<()> {
+    let res = redis::cmd("XGROUP")
+        .arg("CREATE")
+        .arg(stream)
+        .arg(group)
+        .arg("0")
+        .arg("MKSTREAM")
+        .query_async(orders)
+        .await;
+
+    match res {
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+        res => res,
+    }
+}
+
+/// A stream's key and the IDs and fields of entries read from it.
+type StreamEntries = (String, Vec<(String, redis::Value)>);
+
+/// Entries of a stream for this consumer: its own unacknowledged entries from the backlog, or
+/// new ones otherwise.
+async fn read_group(
+    orders: &mut deadpool_redis::Connection,
+    stream: &str,
+    group: &str,
+    name: &str,
+    backlog: bool,
+) -> redis::RedisResult<Vec<(String, redis::Value)>> {
+    let reply: Option<Vec<StreamEntries>> = redis::cmd("XREADGROUP")
+        .arg("GROUP")
+        .arg(group)
+        .arg(name)
+        .arg("COUNT")
+        .arg(CONSUMER_BATCH_SIZE)
+        .arg("STREAMS")
+        .arg(stream)
+        .arg(if backlog { "0" } else { ">" })
+        .query_async(orders)
+        .await?;
+
+    Ok(reply
+        .into_iter()
+        .flatten()
+        .flat_map(|(_, entries)| entries)
+        .collect())
+}
+
+/// Mark a stream entry as handled, deleting it if no other group reads the stream.
+async fn acknowledge(
+    orders: &mut deadpool_redis::Connection,
+    stream: &str,
+    group: &str,
+    id: &str,
+    delete: bool,
+) -> redis::RedisResult<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("XACK")
+        .arg(stream)
+        .arg(group)
+        .arg(id)
+        .ignore();
+    if delete {
+        pipe.cmd("XDEL").arg(stream).arg(id).ignore();
+    }
+    pipe.query_async(orders).await
 }
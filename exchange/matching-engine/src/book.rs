@@ -0,0 +1,240 @@
+//! In-memory order book for a single asset, matching by price then time priority.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use protocol::{client::Side, price::Price};
+
+/// An order resting on the book.
+#[derive(Debug, Clone, Copy)]
+pub struct RestingOrder {
+    pub id: u128,
+    pub user: u128,
+    pub price: Price,
+    /// Units left to fill, shown or hidden.
+    pub remaining: u32,
+    /// Units shown at a time, or 0 to show the whole order.
+    pub display_size: u32,
+    /// Units currently shown on the book.
+    pub visible: u32,
+}
+
+impl RestingOrder {
+    pub fn new(id: u128, user: u128, price: Price, size: u32, display_size: u32) -> Self {
+        Self {
+            id,
+            user,
+            price,
+            remaining: size,
+            display_size,
+            visible: slice(size, display_size),
+        }
+    }
+}
+
+fn slice(remaining: u32, display_size: u32) -> u32 {
+    if display_size == 0 {
+        remaining
+    } else {
+        remaining.min(display_size)
+    }
+}
+
+/// A trade between an incoming order and a resting one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fill {
+    pub maker: u128,
+    pub maker_user: u128,
+    pub price: Price,
+    pub size: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Book {
+    bids: BTreeMap<Price, VecDeque<RestingOrder>>,
+    offers: BTreeMap<Price, VecDeque<RestingOrder>>,
+}
+
+impl Book {
+    /// Match an incoming order against the other side of the book, at no worse than `limit` if
+    /// given, returning its fills and the size left unfilled.
+    ///
+    /// Only the visible slice of an iceberg order can be taken at once. When it runs out, the
+    /// next slice is shown behind the other orders at its price.
+    pub fn execute(&mut self, side: Side, limit: Option<Price>, mut size: u32) -> (Vec<Fill>, u32) {
+        let mut fills = Vec::new();
+        while size > 0 {
+            let mut level = match side {
+                Side::Buy => match self.offers.first_entry() {
+                    Some(level) if limit.map_or(true, |limit| *level.key() <= limit) => level,
+                    _ => break,
+                },
+                Side::Sell => match self.bids.last_entry() {
+                    Some(level) if limit.map_or(true, |limit| *level.key() >= limit) => level,
+                    _ => break,
+                },
+            };
+
+            let queue = level.get_mut();
+            while size > 0 {
+                let Some(mut order) = queue.pop_front() else {
+                    break;
+                };
+                let fill_size = size.min(order.visible);
+                fills.push(Fill {
+                    maker: order.id,
+                    maker_user: order.user,
+                    price: order.price,
+                    size: fill_size,
+                });
+                size -= fill_size;
+                order.remaining -= fill_size;
+                order.visible -= fill_size;
+
+                if order.remaining == 0 {
+                    continue;
+                }
+                if order.visible == 0 {
+                    order.visible = slice(order.remaining, order.display_size);
+                    queue.push_back(order);
+                } else {
+                    queue.push_front(order);
+                }
+            }
+
+            if queue.is_empty() {
+                level.remove();
+            }
+        }
+
+        (fills, size)
+    }
+
+    /// Rest (the unfilled part of) a limit order at the back of its price level.
+    pub fn insert(&mut self, side: Side, order: RestingOrder) {
+        self.side_mut(side)
+            .entry(order.price)
+            .or_default()
+            .push_back(order);
+    }
+
+    /// Remove a resting order from the book.
+    pub fn cancel(&mut self, side: Side, price: Price, id: u128) -> Option<RestingOrder> {
+        let levels = self.side_mut(side);
+        let queue = levels.get_mut(&price)?;
+        let order = queue.remove(queue.iter().position(|order| order.id == id)?);
+        if queue.is_empty() {
+            levels.remove(&price);
+        }
+        order
+    }
+
+    /// Units shown at each price level of one side of the book, best price first.
+    pub fn depth(&self, side: Side) -> Vec<(Price, u32)> {
+        let visible = |(&price, queue): (&Price, &VecDeque<RestingOrder>)| {
+            (price, queue.iter().map(|order| order.visible).sum())
+        };
+        match side {
+            Side::Buy => self.bids.iter().rev().map(visible).collect(),
+            Side::Sell => self.offers.iter().map(visible).collect(),
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Price, VecDeque<RestingOrder>> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.offers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(units: i64) -> Price {
+        Price::from_mills(units * 1000).unwrap()
+    }
+
+    fn fill(maker: u128, price_units: i64, size: u32) -> Fill {
+        Fill {
+            maker,
+            maker_user: maker,
+            price: price(price_units),
+            size,
+        }
+    }
+
+    fn rest(book: &mut Book, side: Side, id: u128, price_units: i64, size: u32, display: u32) {
+        book.insert(
+            side,
+            RestingOrder::new(id, id, price(price_units), size, display),
+        );
+    }
+
+    #[test]
+    fn matches_best_price_then_time() {
+        let mut book = Book::default();
+        rest(&mut book, Side::Sell, 1, 11, 5, 0);
+        rest(&mut book, Side::Sell, 2, 10, 5, 0);
+        rest(&mut book, Side::Sell, 3, 10, 5, 0);
+
+        let (fills, left) = book.execute(Side::Buy, None, 12);
+        assert_eq!(fills, [fill(2, 10, 5), fill(3, 10, 5), fill(1, 11, 2)]);
+        assert_eq!(left, 0);
+        assert_eq!(book.depth(Side::Sell), [(price(11), 3)]);
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let mut book = Book::default();
+        rest(&mut book, Side::Buy, 1, 10, 5, 0);
+        rest(&mut book, Side::Buy, 2, 9, 5, 0);
+
+        let (fills, left) = book.execute(Side::Sell, Some(price(10)), 8);
+        assert_eq!(fills, [fill(1, 10, 5)]);
+        assert_eq!(left, 3);
+        assert_eq!(book.depth(Side::Buy), [(price(9), 5)]);
+    }
+
+    #[test]
+    fn iceberg_shows_one_slice_at_a_time() {
+        let mut book = Book::default();
+        rest(&mut book, Side::Sell, 1, 10, 10, 4);
+        assert_eq!(book.depth(Side::Sell), [(price(10), 4)]);
+
+        let (fills, left) = book.execute(Side::Buy, None, 3);
+        assert_eq!(fills, [fill(1, 10, 3)]);
+        assert_eq!(left, 0);
+        assert_eq!(book.depth(Side::Sell), [(price(10), 1)]);
+    }
+
+    #[test]
+    fn iceberg_refill_goes_behind_the_level() {
+        let mut book = Book::default();
+        rest(&mut book, Side::Sell, 1, 10, 10, 4);
+        rest(&mut book, Side::Sell, 2, 10, 3, 0);
+
+        let (fills, left) = book.execute(Side::Buy, None, 9);
+        assert_eq!(fills, [fill(1, 10, 4), fill(2, 10, 3), fill(1, 10, 2)]);
+        assert_eq!(left, 0);
+        assert_eq!(book.depth(Side::Sell), [(price(10), 2)]);
+
+        let (fills, left) = book.execute(Side::Buy, None, 10);
+        assert_eq!(fills, [fill(1, 10, 2), fill(1, 10, 2)]);
+        assert_eq!(left, 6);
+        assert!(book.depth(Side::Sell).is_empty());
+    }
+
+    #[test]
+    fn cancel_removes_empty_levels() {
+        let mut book = Book::default();
+        rest(&mut book, Side::Buy, 1, 10, 5, 0);
+        rest(&mut book, Side::Buy, 2, 10, 5, 0);
+
+        assert_eq!(book.cancel(Side::Buy, price(10), 1).map(|o| o.id), Some(1));
+        assert!(book.cancel(Side::Buy, price(10), 1).is_none());
+        assert_eq!(book.depth(Side::Buy), [(price(10), 5)]);
+        assert_eq!(book.cancel(Side::Buy, price(10), 2).map(|o| o.id), Some(2));
+        assert!(book.depth(Side::Buy).is_empty());
+    }
+}
@@ -1,17 +1,16 @@
-use std::num::NonZeroU64;
-
 use zerocopy::{
-    big_endian::{U128, U64},
+    big_endian::{U128, U32, U64},
     Immutable, IntoBytes, KnownLayout, TryFromBytes, Unaligned,
 };
 
-use crate::{ApplicationLayer, Tag, WireFormat};
+use crate::{price::Price, ApplicationLayer, Tag, WireFormat};
 
 #[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(u8)]
 pub enum RequestKind {
     NewOrder,
     CancelOrder,
+    ReplaceOrder,
 }
 
 impl WireFormat for RequestKind {}
@@ -23,6 +22,7 @@ pub enum ResponseKind {
     NewOrderAck,
     CancelOrderAck,
     Error,
+    ReplaceOrderAck,
 }
 
 impl WireFormat for ResponseKind {}
@@ -33,10 +33,19 @@ impl Tag for ResponseKind {}
 pub struct Order {
     pub user: U128,
     pub side: Side,
-    pub price: Option<NonZeroU64>,
+    pub order_type: OrderType,
+    /// Limit price. Ignored for market orders.
+    pub price: Price,
+    pub size: U32,
+    /// Units of a limit order shown on the book at a time, or 0 to show all of it.
+    pub display_size: U32,
 }
 
-impl WireFormat for Order {}
+impl WireFormat for Order {
+    fn in_range(&self) -> bool {
+        self.price.in_range()
+    }
+}
 
 #[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(u8)]
@@ -45,6 +54,13 @@ pub enum Side {
     Sell,
 }
 
+#[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+#[repr(u8)]
+pub enum OrderType {
+    Market,
+    Limit,
+}
+
 #[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct CancelOrder {
@@ -53,6 +69,22 @@ pub struct CancelOrder {
 
 impl WireFormat for CancelOrder {}
 
+/// Change the limit price and/or remaining size of a resting order. Reducing only the size keeps
+/// the order's time priority; any other change loses it.
+#[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+#[repr(C, packed)]
+pub struct ReplaceOrder {
+    pub id: U128,
+    pub price: Price,
+    pub size: U32,
+}
+
+impl WireFormat for ReplaceOrder {
+    fn in_range(&self) -> bool {
+        self.price.in_range()
+    }
+}
+
 #[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
 pub struct NewOrderAck {
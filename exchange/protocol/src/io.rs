@@ -25,7 +25,7 @@ impl<T: Tag, R: Read> MessageStream<T, R> {
     }
 
     pub fn message(&self) -> CastResult<Message<T, [u8]>> {
-        Message::try_ref_from(&self.buf)
+        Ok(Message::try_ref_from(&self.buf)?)
     }
 
     pub fn read_next(&mut self) -> io::Result<()> {
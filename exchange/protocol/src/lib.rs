@@ -1,5 +1,6 @@
 pub mod client;
 pub mod io;
+pub mod price;
 
 pub use zerocopy;
 
@@ -9,7 +10,30 @@ use zerocopy::{
     big_endian::U32, Immutable, IntoBytes, KnownLayout, TryCastError, TryFromBytes, Unaligned,
 };
 
-pub type CastResult<'a, T> = Result<&'a T, TryCastError<&'a [u8], T>>;
+pub type CastResult<'a, T> = Result<&'a T, CastError<'a, T>>;
+
+/// Why bytes couldn't be read as a message.
+pub enum CastError<'a, T: ?Sized + TryFromBytes> {
+    /// The bytes don't have the message's layout, or a field holds an invalid value.
+    Invalid(TryCastError<&'a [u8], T>),
+    /// A field is beyond the range of its type, such as a price over [`price::Price::MAX`].
+    OutOfRange,
+}
+
+impl<'a, T: ?Sized + TryFromBytes> From<TryCastError<&'a [u8], T>> for CastError<'a, T> {
+    fn from(e: TryCastError<&'a [u8], T>) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+impl<T: ?Sized + TryFromBytes> Debug for CastError<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(e) => f.debug_tuple("Invalid").field(e).finish(),
+            Self::OutOfRange => f.write_str("OutOfRange"),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 #[repr(C, packed)]
@@ -19,7 +43,11 @@ pub struct Message<T: Tag, P: ?Sized + WireFormat = [u8]> {
     payload: ManuallyDrop<P>,
 }
 
-impl<T: Tag, P: ?Sized + WireFormat> WireFormat for Message<T, P> {}
+impl<T: Tag, P: ?Sized + WireFormat> WireFormat for Message<T, P> {
+    fn in_range(&self) -> bool {
+        self.payload().in_range()
+    }
+}
 
 impl<T: Tag, P: ?Sized + WireFormat> Message<T, P> {
     pub fn payload(&self) -> &P {
@@ -30,7 +58,12 @@ impl<T: Tag, P: ?Sized + WireFormat> Message<T, P> {
     }
 
     pub fn cast<Q: ?Sized + ApplicationLayer>(&self) -> CastResult<Message<T, Q>> {
-        Message::<T, Q>::try_ref_from(self.as_bytes())
+        let message = Message::<T, Q>::try_ref_from(self.as_bytes())?;
+        if message.in_range() {
+            Ok(message)
+        } else {
+            Err(CastError::OutOfRange)
+        }
     }
 }
 
@@ -44,7 +77,13 @@ impl<T: Tag, P: ApplicationLayer<Tag = T>> Message<T, P> {
     }
 }
 
-pub trait WireFormat: TryFromBytes + IntoBytes + Immutable + KnownLayout + Unaligned {}
+pub trait WireFormat: TryFromBytes + IntoBytes + Immutable + KnownLayout + Unaligned {
+    /// Whether every field is within the range of its type, which its layout alone doesn't
+    /// guarantee for types like [`price::Price`].
+    fn in_range(&self) -> bool {
+        true
+    }
+}
 
 impl WireFormat for [u8] {}
 
@@ -0,0 +1,564 @@
+//! Fixed-point prices and amounts of money.
+//!
+//! Both types count whole units of [`SCALE`] decimal places (mills), so that arithmetic on them is
+//! exact. They are written as decimal strings in JSON, as integer counts of mills in Postgres and
+//! Redis, and as big-endian integers on the wire.
+
+use std::{
+    cmp::Ordering,
+    fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
+    ops::Mul,
+    str::FromStr,
+};
+
+use zerocopy::{
+    big_endian::{I128, I64},
+    Immutable, IntoBytes, KnownLayout, TryFromBytes, Unaligned,
+};
+
+use crate::WireFormat;
+
+/// Number of decimal places in a [`Price`] or [`Notional`].
+pub const SCALE: u32 = 3;
+
+const ONE: i128 = 10i128.pow(SCALE);
+
+/// Largest number of mills a price can hold: 2^53 - 1, the largest integer that Redis sorted set
+/// scores and Lua numbers represent exactly.
+const MAX_PRICE: i64 = (1 << 53) - 1;
+
+/// The price of one unit of an asset.
+#[derive(Clone, Copy, Default, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+#[cfg_attr(
+    feature = "diesel",
+    derive(diesel::AsExpression, diesel::FromSqlRow),
+    diesel(sql_type = diesel::sql_types::BigInt)
+)]
+#[repr(transparent)]
+pub struct Price(I64);
+
+/// Prices are decoded from any 64-bit integer, so those beyond [`Price::MAX`] are caught here.
+impl WireFormat for Price {
+    fn in_range(&self) -> bool {
+        Self::from_mills(self.mills()).is_some()
+    }
+}
+
+impl Price {
+    pub const ZERO: Self = Self(I64::ZERO);
+    pub const MAX: Self = Self(I64::new(MAX_PRICE));
+    pub const MIN: Self = Self(I64::new(-MAX_PRICE));
+
+    /// A price of `mills` thousandths, or `None` if it is beyond [`Price::MAX`].
+    pub const fn from_mills(mills: i64) -> Option<Self> {
+        if mills >= -MAX_PRICE && mills <= MAX_PRICE {
+            Some(Self(I64::new(mills)))
+        } else {
+            None
+        }
+    }
+
+    pub fn mills(self) -> i64 {
+        self.0.get()
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.mills() > 0
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Self::from_mills(self.mills().checked_add(rhs.mills())?)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Self::from_mills(self.mills().checked_sub(rhs.mills())?)
+    }
+
+    /// Whether this price is a whole number of `tick`s. Every price is on a zero tick.
+    pub fn is_multiple_of(self, tick: Self) -> bool {
+        tick.mills() == 0 || self.mills() % tick.mills() == 0
+    }
+
+    /// The price halfway between two prices, rounded towards negative infinity.
+    pub fn midpoint(self, other: Self) -> Self {
+        // both prices are within 53 bits, so neither the sum nor the result can overflow
+        Self(I64::new((self.mills() + other.mills()).div_euclid(2)))
+    }
+
+    /// The value of `units` units at this price, or `None` if it doesn't fit in a [`Notional`].
+    pub fn checked_mul(self, units: i128) -> Option<Notional> {
+        units
+            .checked_mul(self.mills() as i128)
+            .map(Notional::from_mills)
+    }
+}
+
+/// The value of a number of units at a price. A 53-bit price times a 64-bit size can't overflow.
+impl Mul<i64> for Price {
+    type Output = Notional;
+
+    fn mul(self, units: i64) -> Notional {
+        Notional::from_mills(self.mills() as i128 * units as i128)
+    }
+}
+
+impl Mul<u32> for Price {
+    type Output = Notional;
+
+    fn mul(self, units: u32) -> Notional {
+        self * units as i64
+    }
+}
+
+/// An amount of money, such as a cash balance or the value of a fill.
+#[derive(Clone, Copy, Default, TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+#[cfg_attr(
+    feature = "diesel",
+    derive(diesel::AsExpression, diesel::FromSqlRow),
+    diesel(sql_type = diesel::sql_types::BigInt)
+)]
+#[repr(transparent)]
+pub struct Notional(I128);
+
+impl WireFormat for Notional {}
+
+impl Notional {
+    pub const ZERO: Self = Self(I128::ZERO);
+
+    pub const fn from_mills(mills: i128) -> Self {
+        Self(I128::new(mills))
+    }
+
+    pub fn mills(self) -> i128 {
+        self.0.get()
+    }
+
+    /// This amount as a TigerBeetle transfer amount, or `None` if it is negative.
+    pub fn to_ledger(self) -> Option<u128> {
+        u128::try_from(self.mills()).ok()
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.mills() > 0
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.mills().checked_add(rhs.mills()).map(Self::from_mills)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.mills().checked_sub(rhs.mills()).map(Self::from_mills)
+    }
+
+    /// The sum of two amounts, clamped at the largest and smallest amounts there can be. Only
+    /// for sums that can't get that far, like the value traded by a single order.
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self::from_mills(self.mills().saturating_add(rhs.mills()))
+    }
+
+    /// The total of `amounts`, or `None` if it overflows.
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Self>) -> Option<Self> {
+        amounts.into_iter().try_fold(Self::ZERO, Self::checked_add)
+    }
+
+    /// This amount relative to `base`, e.g. the return on an initial balance. `None` if `base` is
+    /// zero.
+    pub fn ratio(self, base: Self) -> Option<f64> {
+        (base.mills() != 0).then(|| self.mills() as f64 / base.mills() as f64)
+    }
+}
+
+macro_rules! impl_common {
+    ($ty:ident) => {
+        impl PartialEq for $ty {
+            fn eq(&self, other: &Self) -> bool {
+                self.mills() == other.mills()
+            }
+        }
+
+        impl Eq for $ty {}
+
+        impl PartialOrd for $ty {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for $ty {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.mills().cmp(&other.mills())
+            }
+        }
+
+        impl Hash for $ty {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.mills().hash(state)
+            }
+        }
+
+        impl Debug for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({self})", stringify!($ty))
+            }
+        }
+
+        impl Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mills = i128::from(self.mills());
+                let sign = if mills < 0 { "-" } else { "" };
+                let (whole, fraction) = (
+                    mills.unsigned_abs() / ONE as u128,
+                    mills.unsigned_abs() % ONE as u128,
+                );
+                write!(
+                    f,
+                    "{sign}{whole}.{fraction:0width$}",
+                    width = SCALE as usize
+                )
+            }
+        }
+    };
+}
+
+impl_common!(Price);
+impl_common!(Notional);
+
+impl FromStr for Price {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        i64::try_from(parse_mills(s)?)
+            .ok()
+            .and_then(Self::from_mills)
+            .ok_or(ParseError::OutOfRange)
+    }
+}
+
+impl FromStr for Notional {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        parse_mills(s).map(Self::from_mills)
+    }
+}
+
+/// Parse a decimal number with at most [`SCALE`] decimal places into a count of mills.
+fn parse_mills(s: &str) -> Result<i128, ParseError> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+    let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || digits.ends_with('.') {
+        return Err(ParseError::Invalid);
+    }
+    if fraction.len() > SCALE as usize {
+        return Err(ParseError::TooPrecise);
+    }
+
+    let whole: i128 = whole.parse().map_err(|_| ParseError::OutOfRange)?;
+    let fraction: i128 = format!("{fraction:0<width$}", width = SCALE as usize)
+        .parse()
+        .map_err(|_| ParseError::Invalid)?;
+    let mills = whole
+        .checked_mul(ONE)
+        .and_then(|mills| mills.checked_add(fraction))
+        .ok_or(ParseError::OutOfRange)?;
+
+    Ok(if negative { -mills } else { mills })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Not a decimal number.
+    Invalid,
+    /// More decimal places than [`SCALE`], which would have to be rounded away.
+    TooPrecise,
+    /// Too large in magnitude.
+    OutOfRange,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "not a decimal number"),
+            Self::TooPrecise => write!(f, "more than {SCALE} decimal places"),
+            Self::OutOfRange => write!(f, "out of range"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use std::{fmt, marker::PhantomData, str::FromStr};
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{Notional, Price};
+
+    struct DecimalVisitor<T>(PhantomData<T>);
+
+    impl<T: FromStr<Err = super::ParseError>> de::Visitor<'_> for DecimalVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a decimal string with at most {} places", super::SCALE)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+            v.parse().map_err(E::custom)
+        }
+    }
+
+    macro_rules! impl_serde {
+        ($ty:ident) => {
+            impl Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    deserializer.deserialize_str(DecimalVisitor(PhantomData))
+                }
+            }
+        };
+    }
+
+    impl_serde!(Price);
+    impl_serde!(Notional);
+}
+
+#[cfg(feature = "schemars")]
+mod schemars_impls {
+    use schemars::{
+        gen::SchemaGenerator,
+        schema::{InstanceType, Metadata, Schema, SchemaObject, StringValidation},
+        JsonSchema,
+    };
+
+    use super::{Notional, Price};
+
+    fn decimal_schema(description: &str) -> Schema {
+        Schema::Object(SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(description.to_string()),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(r"^-?[0-9]+(\.[0-9]{1,3})?$".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        })
+    }
+
+    impl JsonSchema for Price {
+        fn schema_name() -> String {
+            "Price".to_string()
+        }
+
+        fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+            decimal_schema("A price per unit, as a decimal with at most 3 places.")
+        }
+    }
+
+    impl JsonSchema for Notional {
+        fn schema_name() -> String {
+            "Notional".to_string()
+        }
+
+        fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+            decimal_schema("An amount of money, as a decimal with at most 3 places.")
+        }
+    }
+}
+
+#[cfg(feature = "diesel")]
+mod diesel_impls {
+    use diesel::{
+        deserialize::{self, FromSql},
+        pg::{Pg, PgValue},
+        serialize::{self, Output, ToSql},
+        sql_types::BigInt,
+    };
+
+    use super::{Notional, Price};
+
+    // Both types are stored as a BIGINT count of mills.
+
+    impl FromSql<BigInt, Pg> for Price {
+        fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+            let mills = <i64 as FromSql<BigInt, Pg>>::from_sql(bytes)?;
+            Price::from_mills(mills).ok_or_else(|| format!("price {mills} out of range").into())
+        }
+    }
+
+    impl ToSql<BigInt, Pg> for Price {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+            <i64 as ToSql<BigInt, Pg>>::to_sql(&self.mills(), &mut out.reborrow())
+        }
+    }
+
+    impl FromSql<BigInt, Pg> for Notional {
+        fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+            <i64 as FromSql<BigInt, Pg>>::from_sql(bytes)
+                .map(|mills| Notional::from_mills(mills as i128))
+        }
+    }
+
+    impl ToSql<BigInt, Pg> for Notional {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+            let mills = i64::try_from(self.mills())
+                .map_err(|_| format!("notional {self} too large for BIGINT"))?;
+            <i64 as ToSql<BigInt, Pg>>::to_sql(&mills, &mut out.reborrow())
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_impls {
+    use redis::{ErrorKind, FromRedisValue, RedisResult, RedisWrite, ToRedisArgs, Value};
+
+    use super::{Notional, Price};
+
+    // Both types are stored as an integer count of mills, so that Lua scripts can compare them
+    // and use prices as sorted set scores.
+
+    impl ToRedisArgs for Price {
+        fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+            self.mills().write_redis_args(out)
+        }
+    }
+
+    impl FromRedisValue for Price {
+        fn from_redis_value(v: &Value) -> RedisResult<Self> {
+            let mills = i64::from_redis_value(v)?;
+            Price::from_mills(mills)
+                .ok_or_else(|| (ErrorKind::TypeError, "price out of range").into())
+        }
+    }
+
+    impl ToRedisArgs for Notional {
+        fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+            out.write_arg(self.mills().to_string().as_bytes())
+        }
+    }
+
+    impl FromRedisValue for Notional {
+        fn from_redis_value(v: &Value) -> RedisResult<Self> {
+            match v {
+                Value::Int(mills) => Ok(Notional::from_mills(*mills as i128)),
+                _ => String::from_redis_value(v)?
+                    .parse()
+                    .map(Notional::from_mills)
+                    .map_err(|_| (ErrorKind::TypeError, "notional is not an integer").into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zerocopy::{IntoBytes, TryFromBytes};
+
+    use super::{Notional, ParseError, Price};
+    use crate::WireFormat;
+
+    fn price(s: &str) -> Price {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_decimals() {
+        assert_eq!(price("12").mills(), 12_000);
+        assert_eq!(price("12.5").mills(), 12_500);
+        assert_eq!(price("0.001").mills(), 1);
+        assert_eq!(price("-1.25").mills(), -1_250);
+        assert_eq!("-0.5".parse::<Notional>().unwrap().mills(), -500);
+    }
+
+    #[test]
+    fn rejects_malformed_and_out_of_range() {
+        for s in ["", "-", ".5", "1.", "1.2.3", "1e3", "+1", " 1", "1,5"] {
+            assert_eq!(s.parse::<Price>(), Err(ParseError::Invalid), "{s:?}");
+        }
+        assert_eq!("9007199254740.991".parse::<Price>(), Ok(Price::MAX));
+        assert_eq!(
+            "9007199254740.992".parse::<Price>(),
+            Err(ParseError::OutOfRange)
+        );
+        assert_eq!(
+            "1".repeat(40).parse::<Notional>(),
+            Err(ParseError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn never_rounds_away_precision() {
+        assert_eq!("1.0001".parse::<Price>(), Err(ParseError::TooPrecise));
+        assert_eq!("1.0000".parse::<Price>(), Err(ParseError::TooPrecise));
+        assert_eq!(price("1.000"), price("1"));
+    }
+
+    #[test]
+    fn displays_with_three_places() {
+        assert_eq!(price("12").to_string(), "12.000");
+        assert_eq!(price("0.05").to_string(), "0.050");
+        assert_eq!(price("-0.001").to_string(), "-0.001");
+        assert_eq!(Price::MIN.to_string(), "-9007199254740.991");
+        assert_eq!(Notional::from_mills(-1_234_567).to_string(), "-1234.567");
+        assert_eq!(price(&Price::MAX.to_string()), Price::MAX);
+    }
+
+    #[test]
+    fn midpoint_rounds_down() {
+        assert_eq!(price("1").midpoint(price("1.003")), price("1.001"));
+        assert_eq!(price("-1").midpoint(price("-1.003")), price("-1.002"));
+        assert_eq!(Price::MAX.midpoint(Price::MAX), Price::MAX);
+    }
+
+    #[test]
+    fn multiplies_exactly() {
+        assert_eq!(price("1.5") * 3u32, Notional::from_mills(4_500));
+        assert_eq!(price("-2") * 2i64, Notional::from_mills(-4_000));
+        assert_eq!(Price::MAX.checked_mul(i128::MAX), None);
+    }
+
+    #[test]
+    fn sums_check_for_overflow() {
+        let max = Notional::from_mills(i128::MAX);
+        assert_eq!(max.checked_add(Notional::from_mills(1)), None);
+        assert_eq!(
+            Notional::checked_sum([price("1") * 1u32, price("2") * 1u32]),
+            Some(Notional::from_mills(3_000))
+        );
+        assert_eq!(Notional::checked_sum([max, max]), None);
+        assert_eq!(max.saturating_add(max), max);
+    }
+
+    #[test]
+    fn converts_to_ledger_amounts() {
+        assert_eq!(Notional::from_mills(1_500).to_ledger(), Some(1_500));
+        assert_eq!(Notional::ZERO.to_ledger(), Some(0));
+        assert_eq!(Notional::from_mills(-1).to_ledger(), None);
+    }
+
+    #[test]
+    fn catches_wire_prices_out_of_range() {
+        let decode = |mills: i64| *Price::try_ref_from(mills.to_be_bytes().as_slice()).unwrap();
+
+        assert!(decode(1_000).in_range());
+        assert_eq!(decode(1_000).as_bytes(), price("1").as_bytes());
+        assert!(decode(Price::MAX.mills()).in_range());
+        assert!(!decode(Price::MAX.mills() + 1).in_range());
+        assert!(!decode(i64::MIN).in_range());
+    }
+}